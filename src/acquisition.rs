@@ -0,0 +1,1319 @@
+use std::{path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use chrono::Utc;
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc::{self, error::TrySendError}};
+
+use crate::{disk, led::{LedColor, LedPattern, SharedLed}, metrics::Metrics, serial::{nmea, Frame, Framing, GpsRecord, ParseOptions, ReadError, SecTickModule, SerialMessage}, services::{ControlMessage, ServiceMessage}, writer::{event::{EventConfig, EventRecorder, EventTrigger}, OutputWriter, OutputWriterConfig, Rejected, Writer}};
+
+#[derive(Clone)]
+pub struct AcquisitionConfig {
+    pub node_id: String,
+    pub serial_port: String,
+    pub reconnect_attempts: u32,
+    pub open_timeout: Duration,
+    pub read_timeout: Duration,
+    // Whether the device sends text lines or binary frames
+    pub framing: Framing,
+    pub resync_on_open: bool,
+    pub skip_lines_on_open: usize,
+    // How long without a good frame before the LED shows the stalled color
+    pub stall_timeout: Duration,
+    pub file_duration: Duration,
+    // Continue the file a previous run left unclosed, if it is younger than
+    // file_duration, instead of starting a new one
+    pub resume_interrupted: bool,
+    // Also start a new file once the current one reaches this size
+    pub max_file_bytes: Option<u64>,
+    pub parse_options: ParseOptions,
+    // Raw ADC values treated as clipped, and the fraction of clipped samples
+    // in a frame that triggers a warning
+    pub adc_min: i32,
+    pub adc_max: i32,
+    pub clip_threshold: f32,
+    pub writer_config: OutputWriterConfig,
+    // Below this much free space on the output filesystem frames are no
+    // longer written, until space is freed up again
+    pub min_free_bytes: u64,
+    // How many frames may wait for the writer before new ones are dropped
+    pub writer_queue_capacity: usize,
+    // How much later than expected a frame's GPS timestamp may be before
+    // the frames in between count as dropped at the source
+    pub gap_tolerance_secs: f64,
+    // Consecutive frames that must agree before the LED shows a change in
+    // the GPS fix
+    pub fix_debounce_frames: usize,
+    // Saves frames around the configured events to files of their own
+    pub events: Option<EventConfig>,
+}
+
+impl AcquisitionConfig {
+    // Whether `trigger` should start an event file
+    fn fires(&self, trigger: EventTrigger) -> bool {
+        self.events.as_ref().is_some_and(|events| events.triggers.contains(&trigger))
+    }
+}
+
+// Messages read ahead of the acquisition loop before the reader waits
+const SERIAL_QUEUE_CAPACITY: usize = 64;
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const ROTATION_MIN_RETRY: Duration = Duration::from_secs(5);
+const ROTATION_MAX_RETRY: Duration = Duration::from_secs(300);
+// Weight of the newest frame in the sample_rate gauge
+const SAMPLE_RATE_SMOOTHING: f64 = 0.1;
+
+// What can change without a restart, applied from the next file on
+#[derive(Debug, Clone)]
+pub struct ReloadableSettings {
+    pub file_duration: Duration,
+    pub gzip_level: i8,
+}
+
+enum WriterCommand {
+    // Also when the frame was read, for the latency histogram
+    Frame(chrono::DateTime<Utc>, Instant, Frame),
+    Comment(String),
+    Gps(chrono::DateTime<Utc>, GpsRecord),
+    Reload(ReloadableSettings),
+    // Starts or extends an event file from the next frame
+    Event(String),
+}
+
+// Frames missing between two consecutive timestamped frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameGap {
+    previous: i64,
+    timestamp: i64,
+    missing_frames: u64,
+}
+
+// What is wrong with a frame's GPS timestamp
+#[derive(Debug, PartialEq)]
+enum TimestampProblem {
+    // None, or zero as sent before the GPS knows the time
+    Missing,
+    // Not after the previous frame's, as when the GPS resets
+    NotIncreasing { previous: i64, timestamp: i64 },
+}
+
+// Follows the GPS timestamps from frame to frame
+struct TimestampChecker {
+    last_timestamp: Option<i64>,
+}
+
+impl TimestampChecker {
+    fn new() -> TimestampChecker {
+        TimestampChecker { last_timestamp: None }
+    }
+
+    fn check(&mut self, frame: &Frame) -> Option<TimestampProblem> {
+        let timestamp = match frame.timestamp() {
+            None | Some(0) => return Some(TimestampProblem::Missing),
+            Some(timestamp) => timestamp,
+        };
+
+        let previous = self.last_timestamp.replace(timestamp);
+        previous.filter(|previous| timestamp <= *previous)
+            .map(|previous| TimestampProblem::NotIncreasing { previous, timestamp })
+    }
+}
+
+// Spots frames dropped at the source from jumps in the GPS timestamps. A
+// frame covers samples / sample_rate seconds, so the next one is expected
+// that much after it.
+struct GapDetector {
+    tolerance_secs: f64,
+    last_timestamp: Option<i64>,
+}
+
+impl GapDetector {
+    fn new(tolerance_secs: f64) -> GapDetector {
+        GapDetector { tolerance_secs, last_timestamp: None }
+    }
+
+    fn check(&mut self, frame: &Frame) -> Option<FrameGap> {
+        let timestamp = match frame.timestamp() {
+            Some(timestamp) if timestamp != 0 => timestamp,
+            // Without a timestamp there's nothing to measure the next one from
+            _ => {
+                self.last_timestamp = None;
+                return None;
+            }
+        };
+
+        let previous = self.last_timestamp.replace(timestamp)?;
+        let cadence = frame.sample_count() as f64 / frame.sample_rate() as f64;
+        if !cadence.is_finite() || cadence <= 0.0 {
+            return None;
+        }
+
+        // Timestamps going backwards are reported as non-monotonic instead
+        let late = (timestamp - previous) as f64 - cadence;
+        if late <= self.tolerance_secs {
+            return None;
+        }
+
+        Some(FrameGap {
+            previous,
+            timestamp,
+            missing_frames: (late / cadence).round().max(1.0) as u64,
+        })
+    }
+}
+
+// Keeps the LED on the GPS fix it shows until enough consecutive frames
+// disagree with it, so a marginal fix doesn't make it flicker. Only the LED
+// waits, frames are still written or not by their own fix.
+struct FixDebouncer {
+    frames: usize,
+    shown: Option<bool>,
+    // Consecutive frames disagreeing with `shown`
+    disagreeing: usize,
+}
+
+impl FixDebouncer {
+    fn new(frames: usize) -> FixDebouncer {
+        FixDebouncer { frames, shown: None, disagreeing: 0 }
+    }
+
+    // Returns whether the LED should show a fix
+    fn update(&mut self, has_fix: bool) -> bool {
+        match self.shown {
+            // The first frame is shown straight away
+            None => self.shown = Some(has_fix),
+            Some(shown) if shown == has_fix => self.disagreeing = 0,
+            Some(_) => {
+                self.disagreeing += 1;
+                if self.disagreeing >= self.frames {
+                    self.shown = Some(has_fix);
+                    self.disagreeing = 0;
+                }
+            }
+        }
+        self.shown == Some(true)
+    }
+}
+
+// Exponential moving average, taking the first value as is
+struct MovingAverage {
+    weight: f64,
+    value: Option<f64>,
+}
+
+impl MovingAverage {
+    fn new(weight: f64) -> MovingAverage {
+        MovingAverage { weight, value: None }
+    }
+
+    fn add(&mut self, value: f64) -> f64 {
+        let average = match self.value {
+            Some(average) => average + self.weight * (value - average),
+            None => value,
+        };
+        self.value = Some(average);
+        average
+    }
+}
+
+// A line or binary frame from the serial reader, or why it couldn't read one,
+// with when it came off the port
+type SerialRead = (chrono::DateTime<Utc>, Instant, anyhow::Result<SerialMessage>);
+
+// Reads frames from one serial port and writes them to that port's own files
+pub struct Acquisition {
+    config: AcquisitionConfig,
+    serial: SecTickModule,
+    writer_task: WriterTask,
+    led: Arc<SharedLed>,
+    led_source: usize,
+    metrics: Arc<Metrics>,
+    tx: broadcast::Sender<ServiceMessage>,
+    control_rx: broadcast::Receiver<ControlMessage>,
+}
+
+// Owns the output file so slow writes never hold up serial reads
+struct WriterTask {
+    node_id: String,
+    // None when a rotation closed the last file but couldn't start the next
+    writer: Option<OutputWriter>,
+    writer_config: OutputWriterConfig,
+    file_duration: Duration,
+    // When the current file was started, earlier than the task if it was
+    // continued from a previous run
+    file_started: Instant,
+    max_file_bytes: Option<u64>,
+    led: Arc<SharedLed>,
+    led_source: usize,
+    metrics: Arc<Metrics>,
+    // Size of the current file when it was last checked
+    file_size: u64,
+    // Set while rotation is failing: when to try again, and the delay after that
+    rotation_retry: Option<(Instant, Duration)>,
+    events: Option<EventRecorder>,
+}
+
+impl Acquisition {
+    pub fn new(config: AcquisitionConfig,
+        led: Arc<SharedLed>,
+        led_source: usize,
+        metrics: Arc<Metrics>,
+        tx: broadcast::Sender<ServiceMessage>,
+        control_rx: broadcast::Receiver<ControlMessage>) -> anyhow::Result<Acquisition> {
+
+        log::info!("Starting acquisition for node_id=\"{}\" on {}", config.node_id, config.serial_port);
+
+        let mut serial = SecTickModule::new(config.serial_port.clone(), 1_000_000, config.open_timeout, config.read_timeout, config.reconnect_attempts);
+        serial.set_resync(config.resync_on_open, config.skip_lines_on_open);
+        serial.set_framing(config.framing);
+        serial.open()?;
+
+        Acquisition::with_serial(config, serial, led, led_source, metrics, tx, control_rx)
+    }
+
+    // As new, reading from a module that is already set up
+    pub fn with_serial(config: AcquisitionConfig,
+        serial: SecTickModule,
+        led: Arc<SharedLed>,
+        led_source: usize,
+        metrics: Arc<Metrics>,
+        tx: broadcast::Sender<ServiceMessage>,
+        control_rx: broadcast::Receiver<ControlMessage>) -> anyhow::Result<Acquisition> {
+
+        // Event files go in a directory of their own next to the regular ones
+        let events = config.events.as_ref().map(|events| {
+            let mut writer_config = config.writer_config.clone();
+            writer_config.set_output_path(config.writer_config.output_path().join("events"));
+            EventRecorder::new(events, writer_config)
+        });
+
+        let (writer, file_age) = match config.resume_interrupted {
+            true => OutputWriter::resume_or_new(config.writer_config.clone(), config.file_duration)?,
+            false => (OutputWriter::new(config.writer_config.clone())?, Duration::ZERO),
+        };
+
+        let writer_task = WriterTask {
+            node_id: config.node_id.clone(),
+            writer: Some(writer),
+            writer_config: config.writer_config.clone(),
+            file_duration: config.file_duration,
+            file_started: Instant::now().checked_sub(file_age).unwrap_or_else(Instant::now),
+            max_file_bytes: config.max_file_bytes,
+            led: led.clone(),
+            led_source,
+            metrics: metrics.clone(),
+            rotation_retry: None,
+            file_size: 0,
+            events,
+        };
+
+        Ok(Acquisition {
+            config,
+            serial,
+            writer_task,
+            led,
+            led_source,
+            metrics,
+            tx,
+            control_rx,
+        })
+    }
+
+    // Writes CSV files to `dir` from a port that is never opened, so it only
+    // sees read errors until it is shut down
+    #[cfg(test)]
+    pub fn without_port(dir: &std::path::Path, tx: broadcast::Sender<ServiceMessage>) -> Acquisition {
+        let serial = SecTickModule::new("/dev/heartbeat-test-missing".to_string(), 1_000_000, Duration::from_millis(50), Duration::from_millis(50), 1);
+        Acquisition::for_test(dir, serial, tx)
+    }
+
+    // Writes CSV files to `dir` from what the scripted port plays
+    #[cfg(test)]
+    pub fn with_mock_port(dir: &std::path::Path, port: crate::serial::mock::MockSerialPort, tx: broadcast::Sender<ServiceMessage>) -> Acquisition {
+        Acquisition::for_test(dir, SecTickModule::with_port(Box::new(port)), tx)
+    }
+
+    #[cfg(test)]
+    fn for_test(dir: &std::path::Path, serial: SecTickModule, tx: broadcast::Sender<ServiceMessage>) -> Acquisition {
+        use crate::{led::{MockPin, LED}, serial::Framing, writer::{csv::CsvWriterConfig, Decimation, FilenameTimezone, FlushPolicy}};
+
+        let writer_config = OutputWriterConfig::Csv(CsvWriterConfig {
+            node_id: "node".to_string(),
+            output_path: dir.to_path_buf(),
+            samples_per_frame: 3,
+            decimation: Decimation::default(),
+            flush: FlushPolicy::default(),
+            filename_timezone: FilenameTimezone::default(),
+        });
+        let led = LED::with_pins(Box::new(MockPin::default()), Box::new(MockPin::default()), Box::new(MockPin::default()), false);
+        let led = Arc::new(SharedLed::new(led, 1));
+        let metrics = Arc::new(Metrics::new().expect("Unable to register metrics"));
+        let (_, control_rx) = broadcast::channel(1);
+
+        let config = AcquisitionConfig {
+            node_id: "node".to_string(),
+            serial_port: "/dev/heartbeat-test-missing".to_string(),
+            // Waits a second before trying to open it, instead of spinning
+            reconnect_attempts: 1,
+            open_timeout: Duration::from_millis(50),
+            read_timeout: Duration::from_millis(50),
+            framing: Framing::Text,
+            resync_on_open: false,
+            skip_lines_on_open: 0,
+            stall_timeout: Duration::from_secs(60),
+            file_duration: Duration::from_secs(3600),
+            resume_interrupted: false,
+            max_file_bytes: None,
+            parse_options: ParseOptions::default(),
+            adc_min: 0,
+            adc_max: 1023,
+            clip_threshold: 0.01,
+            writer_config: writer_config.clone(),
+            min_free_bytes: 0,
+            writer_queue_capacity: 8,
+            gap_tolerance_secs: 0.5,
+            fix_debounce_frames: 1,
+            events: None,
+        };
+
+        let writer_task = WriterTask {
+            node_id: config.node_id.clone(),
+            writer: Some(OutputWriter::new(writer_config.clone()).expect("Unable to create test writer")),
+            writer_config,
+            file_duration: config.file_duration,
+            file_started: Instant::now(),
+            max_file_bytes: None,
+            led: led.clone(),
+            led_source: 0,
+            metrics: metrics.clone(),
+            file_size: 0,
+            rotation_retry: None,
+            events: None,
+        };
+
+        Acquisition {
+            serial,
+            config,
+            writer_task,
+            led,
+            led_source: 0,
+            metrics,
+            tx,
+            control_rx,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.config.node_id
+    }
+
+    pub async fn run(mut self, mut shutdown_rx: tokio::sync::broadcast::Receiver<()>) -> anyhow::Result<()> {
+        let (writer_tx, writer_rx) = mpsc::channel(self.config.writer_queue_capacity);
+        let writer_handle = tokio::spawn(self.writer_task.run(writer_rx));
+
+        let (serial_tx, mut serial_rx) = mpsc::channel(SERIAL_QUEUE_CAPACITY);
+        let reader_handle = tokio::spawn(Self::read_serial(self.serial, serial_tx));
+
+        let last_frame_at = Arc::new(Mutex::new(Instant::now()));
+        let watchdog_handle = tokio::spawn(Self::watchdog(
+            self.config.node_id.clone(),
+            self.config.stall_timeout,
+            last_frame_at.clone(),
+            self.led.clone(),
+            self.led_source,
+            self.metrics.clone()));
+
+        let mut control_open = true;
+        // Unknown until the first frame arrives
+        let mut has_gps_fix: Option<bool> = None;
+        let mut timestamps = TimestampChecker::new();
+        let mut gaps = GapDetector::new(self.config.gap_tolerance_secs);
+        let mut led_fix = FixDebouncer::new(self.config.fix_debounce_frames);
+        let mut sample_rate = MovingAverage::new(SAMPLE_RATE_SMOOTHING);
+
+        let disk_full = Arc::new(AtomicBool::new(false));
+        let disk_monitor_handle = tokio::spawn(Self::disk_monitor(
+            self.config.node_id.clone(),
+            self.config.writer_config.output_path().to_path_buf(),
+            self.config.min_free_bytes,
+            disk_full.clone(),
+            self.metrics.clone()));
+
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    break;
+                },
+                message = self.control_rx.recv(), if control_open => {
+                    match message {
+                        Ok(ControlMessage::Comment(comment)) => {
+                            Self::queue(&self.config.node_id, &writer_tx, WriterCommand::Comment(comment))?;
+                        },
+                        Ok(ControlMessage::Reload(settings)) => {
+                            // Unlike frames this mustn't be dropped, wait for room
+                            writer_tx.send(WriterCommand::Reload(settings)).await
+                                .map_err(|_| anyhow::anyhow!("[{}] Writer task has stopped", self.config.node_id))?;
+                        },
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("[{}] Missed {} service messages, comments or a reload may have been lost", self.config.node_id, skipped);
+                        },
+                        Err(RecvError::Closed) => control_open = false,
+                    }
+                },
+                read = serial_rx.recv() => {
+                    let (when, read_at, message) = match read {
+                        Some(read) => read,
+                        None => {
+                            log::error!("[{}] Serial reader has stopped", self.config.node_id);
+                            break;
+                        }
+                    };
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(e) => {
+                            Self::count_read_error(&self.config.node_id, &e, &self.metrics);
+                            if matches!(e.downcast_ref::<ReadError>(), Some(ReadError::Timeout)) {
+                                // The port is still open, so there's nothing to reconnect. The
+                                // watchdog takes care of the LED if this goes on for too long.
+                                log::warn!("[{}] No data from {} for {:?}", self.config.node_id, self.config.serial_port, self.config.read_timeout);
+                            } else {
+                                log::error!("[{}] Error reading from serial port: {:?}", self.config.node_id, e);
+                                self.led.set_color(self.led_source, LedColor::Red)?;
+                            }
+                            continue;
+                        }
+                    };
+
+                    let parsed = match &message {
+                        SerialMessage::Line(line) if line.starts_with("#") => {
+                            self.led.set_color(self.led_source, LedColor::Blue)?;
+                            let command = match nmea::parse(line) {
+                                Some(record) => WriterCommand::Gps(Utc::now(), record),
+                                None => WriterCommand::Comment(line.clone()),
+                            };
+                            Self::queue(&self.config.node_id, &writer_tx, command)?;
+                            continue;
+                        },
+                        SerialMessage::Line(line) => Frame::parse_with_options(line, &self.config.parse_options),
+                        SerialMessage::Binary(bytes) => Frame::parse_binary_with_options(bytes, &self.config.parse_options),
+                    };
+
+                    let frame = match parsed {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            self.metrics.parse_errors.with_label_values(&[self.config.node_id.as_str()]).inc();
+                            self.led.set_color(self.led_source, LedColor::Red)?;
+                            log::error!("[{}] Failed to parse frame: {}\n{}", self.config.node_id, e, message.excerpt());
+                            continue;
+                        }
+                    };
+
+                    if let Ok(mut last_frame_at) = last_frame_at.lock() {
+                        *last_frame_at = Instant::now();
+                    }
+
+                    match timestamps.check(&frame) {
+                        Some(TimestampProblem::Missing) => log::warn!("[{}] Frame has no GPS timestamp", self.config.node_id),
+                        Some(TimestampProblem::NotIncreasing { previous, timestamp }) => {
+                            log::warn!("[{}] GPS timestamp went from {} to {}", self.config.node_id, previous, timestamp);
+                            self.metrics.non_monotonic_timestamps.with_label_values(&[self.config.node_id.as_str()]).inc();
+                        },
+                        None => {},
+                    }
+
+                    if let Some(gap) = gaps.check(&frame) {
+                        let note = format!("Frame gap: GPS time jumped from {} to {}, about {} frames missing", gap.previous, gap.timestamp, gap.missing_frames);
+                        log::warn!("[{}] {}", self.config.node_id, note);
+                        self.metrics.frame_gaps.with_label_values(&[self.config.node_id.as_str()]).inc();
+                        if self.config.fires(EventTrigger::FrameGap) {
+                            Self::queue(&self.config.node_id, &writer_tx, WriterCommand::Event(note.clone()))?;
+                        }
+                        Self::queue(&self.config.node_id, &writer_tx, WriterCommand::Comment(note))?;
+                    }
+
+                    let clip_ratio = frame.clip_ratio(self.config.adc_min, self.config.adc_max);
+                    if clip_ratio > self.config.clip_threshold {
+                        log::warn!("[{}] {:.1}% of samples are at the ADC rails (device clipping flag: {})", self.config.node_id, clip_ratio * 100.0, frame.metadata().is_clipping());
+                        self.metrics.clipping_frames.with_label_values(&[self.config.node_id.as_str()]).inc();
+                        if self.config.fires(EventTrigger::Clipping) {
+                            let reason = format!("{:.1}% of samples at the ADC rails", clip_ratio * 100.0);
+                            Self::queue(&self.config.node_id, &writer_tx, WriterCommand::Event(reason))?;
+                        }
+                    }
+
+                    self.metrics.sample_rate.with_label_values(&[self.config.node_id.as_str()]).set(sample_rate.add(frame.sample_rate() as f64));
+                    self.metrics.satellites.with_label_values(&[self.config.node_id.as_str()]).set(frame.satellite_count() as i64);
+
+                    let stats = frame.signal_stats();
+                    self.metrics.signal_rms.with_label_values(&[self.config.node_id.as_str()]).set(stats.rms as f64);
+                    self.metrics.signal_peak_to_peak.with_label_values(&[self.config.node_id.as_str()]).set(stats.peak_to_peak);
+
+                    let has_fix = frame.metadata().has_gps_fix();
+                    if has_gps_fix != Some(has_fix) {
+                        log::info!("[{}] GPS fix {} ({} satellites)", self.config.node_id, if has_fix { "acquired" } else { "lost" }, frame.satellite_count());
+                        self.tx.send(ServiceMessage::GpsFixChanged {
+                            node_id: self.config.node_id.clone(),
+                            has_fix,
+                            satellites: frame.satellite_count(),
+                        })?;
+                        // Not for the first frame, which only tells us what the fix was all along
+                        if has_gps_fix.is_some() && self.config.fires(EventTrigger::GpsFix) {
+                            let reason = format!("GPS fix {}", if has_fix { "acquired" } else { "lost" });
+                            Self::queue(&self.config.node_id, &writer_tx, WriterCommand::Event(reason))?;
+                        }
+                        has_gps_fix = Some(has_fix);
+                    }
+
+                    // Every good frame sets the LED again, so a comment, error or stall
+                    // only shows until the next one
+                    let disk_full = disk_full.load(Ordering::Relaxed);
+                    let show_fix = led_fix.update(has_fix);
+                    if show_fix && disk_full {
+                        self.led.set_pattern(self.led_source, LedPattern::Pulse { color: LedColor::Yellow, period_ms: 2000 })?;
+                    } else if show_fix {
+                        self.led.set_color(self.led_source, LedColor::Green)?;
+                    } else {
+                        self.led.set_pattern(self.led_source, LedPattern::Blink { color: LedColor::Magenta, period_ms: 1000 })?;
+                    }
+
+                    // Without a GPS fix nothing gets written, nor while there is no room for it
+                    if has_fix && disk_full {
+                        self.metrics.frames_skipped_disk_full.with_label_values(&[self.config.node_id.as_str()]).inc();
+                    } else if has_fix {
+                        Self::queue_frame(&self.config.node_id, &writer_tx, WriterCommand::Frame(when, read_at, frame.clone()), &self.metrics)?;
+                    }
+
+                    self.tx.send(ServiceMessage::NewFrame {
+                        node_id: self.config.node_id.clone(),
+                        frame,
+                    })?;
+                }
+            }
+        }
+
+        reader_handle.abort();
+        watchdog_handle.abort();
+        disk_monitor_handle.abort();
+
+        // Closing the channel lets the writer drain what's queued and close the file
+        drop(writer_tx);
+        writer_handle.await?
+    }
+
+    // Reads in a task of its own so that a read is never cancelled halfway
+    // through a line, and a reconnect backoff never restarts, because
+    // something else the acquisition loop waits on came first
+    async fn read_serial(mut serial: SecTickModule, serial_tx: mpsc::Sender<SerialRead>) {
+        loop {
+            let message = serial.read_message_with_reconnect().await;
+            if serial_tx.send((Utc::now(), Instant::now(), message)).await.is_err() {
+                // The acquisition loop has finished
+                break;
+            }
+        }
+    }
+
+    // Flags the node as stalled if no frame has parsed within stall_timeout,
+    // even when the serial port itself reports no errors
+    async fn watchdog(node_id: String,
+        stall_timeout: Duration,
+        last_frame_at: Arc<Mutex<Instant>>,
+        led: Arc<SharedLed>,
+        led_source: usize,
+        metrics: Arc<Metrics>) {
+
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut stalled = false;
+
+        loop {
+            interval.tick().await;
+
+            let elapsed = match last_frame_at.lock() {
+                Ok(last_frame_at) => last_frame_at.elapsed(),
+                Err(_) => continue,
+            };
+
+            metrics.seconds_since_last_frame.with_label_values(&[node_id.as_str()]).set(elapsed.as_secs_f64());
+
+            if elapsed > stall_timeout {
+                if !stalled {
+                    log::error!("[{}] No frames for {:.0}s, acquisition looks stalled", node_id, elapsed.as_secs_f64());
+                    stalled = true;
+                }
+                if let Err(e) = led.set_pattern(led_source, LedPattern::Blink { color: LedColor::Red, period_ms: 500 }) {
+                    log::error!("Unable to set LED: {:?}", e);
+                }
+            } else if stalled {
+                log::info!("[{}] Frames are arriving again", node_id);
+                stalled = false;
+            }
+        }
+    }
+
+    // Pauses writing while free space on the output filesystem is below
+    // min_free_bytes, and resumes once it has recovered
+    async fn disk_monitor(node_id: String,
+        output_dir: PathBuf,
+        min_free_bytes: u64,
+        disk_full: Arc<AtomicBool>,
+        metrics: Arc<Metrics>) {
+
+        let mut interval = tokio::time::interval(DISK_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let free_bytes = match disk::free_bytes(&output_dir) {
+                Ok(free_bytes) => free_bytes,
+                Err(e) => {
+                    log::warn!("[{}] Unable to check free space in {}: {:?}", node_id, output_dir.display(), e);
+                    continue;
+                }
+            };
+            metrics.disk_free_bytes.with_label_values(&[node_id.as_str()]).set(free_bytes as i64);
+
+            let full = free_bytes < min_free_bytes;
+            if full != disk_full.swap(full, Ordering::Relaxed) {
+                if full {
+                    log::error!("[{}] Only {} MB free in {}, pausing writes", node_id, free_bytes / 1_000_000, output_dir.display());
+                } else {
+                    log::info!("[{}] {} MB free in {}, resuming writes", node_id, free_bytes / 1_000_000, output_dir.display());
+                }
+            }
+        }
+    }
+
+    // Counts a failed read in serial_read_errors by kind. Errors that aren't
+    // a ReadError come from failing to reopen the port, so count as io.
+    fn count_read_error(node_id: &str, e: &anyhow::Error, metrics: &Metrics) {
+        let kind = e.downcast_ref::<ReadError>().map_or("io", ReadError::kind);
+        metrics.serial_read_errors.with_label_values(&[node_id, kind]).inc();
+    }
+
+    // Queues a command for the writer without blocking. Returns false if the
+    // queue was full and the command had to be dropped.
+    fn queue(node_id: &str, writer_tx: &mpsc::Sender<WriterCommand>, command: WriterCommand) -> anyhow::Result<bool> {
+        match writer_tx.try_send(command) {
+            Ok(_) => Ok(true),
+            Err(TrySendError::Full(_)) => {
+                log::warn!("[{}] Writer queue is full, dropping data", node_id);
+                Ok(false)
+            },
+            Err(TrySendError::Closed(_)) => Err(anyhow::anyhow!("[{}] Writer task has stopped", node_id)),
+        }
+    }
+
+    // Like queue, counting the frame in frames_dropped when the queue is full
+    fn queue_frame(node_id: &str, writer_tx: &mpsc::Sender<WriterCommand>, command: WriterCommand, metrics: &Metrics) -> anyhow::Result<()> {
+        if !Self::queue(node_id, writer_tx, command)? {
+            metrics.frames_dropped.with_label_values(&[node_id]).inc();
+        }
+        Ok(())
+    }
+}
+
+impl WriterTask {
+    async fn run(mut self, mut rx: mpsc::Receiver<WriterCommand>) -> anyhow::Result<()> {
+        let mut last_start = self.file_started;
+
+        while let Some(command) = rx.recv().await {
+            let retry_due = !matches!(self.rotation_retry, Some((at, _)) if Instant::now() < at);
+            // Whichever limit is reached first
+            let too_big = self.max_file_bytes.is_some_and(|max| self.file_size >= max);
+            if (last_start.elapsed() > self.file_duration || too_big) && retry_due {
+                if too_big {
+                    log::info!("[{}] Current file has reached {} bytes, starting a new one", self.node_id, self.file_size);
+                }
+
+                if self.try_rotate()? {
+                    last_start = Instant::now();
+                }
+            }
+
+            match command {
+                WriterCommand::Comment(comment) => {
+                    match async { self.open_writer()?.write_comment(&comment).await }.await {
+                        Ok(_) => {},
+                        Err(e) if e.is::<Rejected>() => log::warn!("[{}] Dropped a comment the file can't store: {:?}", self.node_id, e),
+                        Err(e) => {
+                            if self.recover(e)? {
+                                last_start = Instant::now();
+                            }
+                        }
+                    }
+                },
+                WriterCommand::Gps(when, record) => {
+                    match async { self.open_writer()?.write_gps(when, &record).await }.await {
+                        Ok(_) => {},
+                        Err(e) if e.is::<Rejected>() => log::warn!("[{}] Dropped a GPS sentence the file can't store: {:?}", self.node_id, e),
+                        Err(e) => {
+                            if self.recover(e)? {
+                                last_start = Instant::now();
+                            }
+                        }
+                    }
+                },
+                WriterCommand::Reload(settings) => {
+                    log::info!("[{}] Next file will last {:?} with gzip level {}", self.node_id, settings.file_duration, settings.gzip_level);
+                    self.file_duration = settings.file_duration;
+                    self.writer_config.set_gzip_level(settings.gzip_level);
+                    if let Some(events) = &mut self.events {
+                        events.set_gzip_level(settings.gzip_level);
+                    }
+                },
+                WriterCommand::Event(reason) => {
+                    if let Some(events) = &mut self.events {
+                        events.trigger(reason);
+                    }
+                },
+                WriterCommand::Frame(when, read_at, frame) => {
+                    let written = async { self.open_writer()?.write_frame(when, &frame).await }.await;
+                    self.metrics.frame_latency.with_label_values(&[self.node_id.as_str()]).observe(read_at.elapsed().as_secs_f64());
+                    match written {
+                        Ok(_) => {
+                            self.metrics.frames_written.with_label_values(&[self.node_id.as_str()]).inc();
+                            self.record_file_size();
+                            // The acquisition loop shows the fix state, a file that
+                            // can't be rotated stays red over it
+                            if self.rotation_retry.is_some() {
+                                self.led.set_color(self.led_source, LedColor::Red)?;
+                            }
+                        },
+                        Err(e) if e.is::<Rejected>() => {
+                            log::error!("[{}] Failed to write frame: {:?}", self.node_id, e);
+                            self.led.set_color(self.led_source, LedColor::Red)?;
+                        },
+                        Err(e) => {
+                            if self.recover(e)? {
+                                last_start = Instant::now();
+                            }
+                        }
+                    }
+
+                    if let Some(events) = &mut self.events {
+                        match events.push(when, &frame).await {
+                            Ok(Some(path)) => log::info!("[{}] Finished event file {}", self.node_id, path.display()),
+                            Ok(None) => {},
+                            Err(e) => log::error!("[{}] Failed to write event file: {:?}", self.node_id, e),
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(writer) = self.writer {
+            match writer.close() {
+                Ok(path) => log::info!("Finished writing {}", path.display()),
+                Err(e) => log::error!("Failed to close output file: {:?}", e),
+            }
+        }
+
+        if let Some(events) = self.events {
+            match events.close() {
+                Ok(Some(path)) => log::info!("Finished event file {}", path.display()),
+                Ok(None) => {},
+                Err(e) => log::error!("Failed to close event file: {:?}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn open_writer(&mut self) -> anyhow::Result<&mut OutputWriter> {
+        self.writer.as_mut().ok_or_else(|| anyhow::anyhow!("No output file is open"))
+    }
+
+    // The size on disk only grows when the writer flushes, so between
+    // flushes this lags behind what was written
+    fn record_file_size(&mut self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let size = match std::fs::metadata(writer.file_path()) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                log::warn!("[{}] Unable to check the output file size: {:?}", self.node_id, e);
+                return;
+            }
+        };
+
+        self.metrics.bytes_written.with_label_values(&[self.node_id.as_str()]).inc_by(size.saturating_sub(self.file_size));
+        self.metrics.current_file_bytes.with_label_values(&[self.node_id.as_str()]).set(size as i64);
+        self.file_size = size;
+    }
+
+    // Starts a new file, returning false if that failed and the current file
+    // has to do until the retry is due
+    fn try_rotate(&mut self) -> anyhow::Result<bool> {
+        match self.rotate() {
+            Ok(_) => {
+                self.rotation_retry = None;
+                Ok(true)
+            },
+            Err(e) => {
+                // Keep writing to the current file, if it is still open,
+                // rather than taking the node down
+                let delay = self.rotation_retry.map_or(ROTATION_MIN_RETRY, |(_, delay)| delay);
+                log::error!("[{}] Unable to start a new file, retrying in {:?}: {:?}", self.node_id, delay, e);
+                self.led.set_color(self.led_source, LedColor::Red)?;
+                self.rotation_retry = Some((Instant::now() + delay, (delay * 2).min(ROTATION_MAX_RETRY)));
+                Ok(false)
+            }
+        }
+    }
+
+    // A failed write may have left the current file unusable, so move on to
+    // a fresh one and keep going. Returns whether a new file was started.
+    // Only losing the output directory altogether stops the writer.
+    fn recover(&mut self, e: anyhow::Error) -> anyhow::Result<bool> {
+        self.metrics.write_errors.with_label_values(&[self.node_id.as_str()]).inc();
+        self.led.set_color(self.led_source, LedColor::Red)?;
+
+        let output_dir = self.writer_config.output_path();
+        if !output_dir.is_dir() {
+            log::error!("[{}] Write failed and {} is gone, stopping: {:?}", self.node_id, output_dir.display(), e);
+            return Err(anyhow::anyhow!("[{}] Output directory {} is gone", self.node_id, output_dir.display()));
+        }
+
+        if matches!(self.rotation_retry, Some((at, _)) if Instant::now() < at) {
+            log::error!("[{}] Write failed while unable to start a new file: {:?}", self.node_id, e);
+            return Ok(false);
+        }
+
+        log::error!("[{}] Write failed, starting a new file: {:?}", self.node_id, e);
+        self.try_rotate()
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let output_dir = self.writer_config.output_path();
+        disk::check_writable(output_dir)?;
+        let free_bytes = disk::free_bytes(output_dir)?;
+        self.metrics.disk_free_bytes.with_label_values(&[self.node_id.as_str()]).set(free_bytes as i64);
+        if free_bytes == 0 {
+            return Err(anyhow::anyhow!("No space left in {}", output_dir.display()));
+        }
+
+        // Finish the current file first, so it is complete and in the
+        // manifest by the time the next one appears
+        if let Some(writer) = self.writer.take() {
+            match writer.close() {
+                Ok(path) => log::info!("Finished writing {}", path.display()),
+                Err(e) => log::error!("Failed to close output file: {:?}", e),
+            }
+        }
+        self.writer = Some(OutputWriter::new(self.writer_config.clone())?);
+
+        self.metrics.files_rotated.with_label_values(&[self.node_id.as_str()]).inc();
+        self.file_size = 0;
+        self.record_file_size();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+    use chrono::Utc;
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{Acquisition, FixDebouncer, FrameGap, GapDetector, MovingAverage, TimestampChecker, TimestampProblem, WriterCommand, WriterTask};
+    use crate::{led::{LedColor, LedPattern, MockPin, SharedLed, LED}, metrics::Metrics, serial::{mock::MockSerialPort, Frame, ReadError}, services::ServiceMessage, testutil, writer::{csv::CsvWriterConfig, manifest, Decimation, FilenameTimezone, FlushPolicy, OutputWriter, OutputWriterConfig, Writer}};
+
+    // Polls until `done` holds, failing the test after a few seconds
+    async fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn frame_command(timestamp: i64) -> WriterCommand {
+        WriterCommand::Frame(Utc::now(), Instant::now(), testutil::frame(timestamp, true, &[1, 2, 3]))
+    }
+
+    #[test]
+    fn queue_drops_commands_while_the_writer_is_behind() {
+        let (writer_tx, writer_rx) = mpsc::channel(1);
+        let comment = || WriterCommand::Comment("note".to_string());
+
+        assert!(Acquisition::queue("node", &writer_tx, comment()).unwrap());
+        assert!(!Acquisition::queue("node", &writer_tx, comment()).unwrap());
+
+        drop(writer_rx);
+        assert!(Acquisition::queue("node", &writer_tx, comment()).is_err());
+    }
+
+    #[test]
+    fn counts_frames_dropped_for_a_full_queue() {
+        let metrics = Metrics::new().unwrap();
+        let (writer_tx, _writer_rx) = mpsc::channel(1);
+
+        Acquisition::queue_frame("node", &writer_tx, frame_command(1), &metrics).unwrap();
+        assert_eq!(metrics.frames_dropped.with_label_values(&["node"]).get(), 0);
+
+        Acquisition::queue_frame("node", &writer_tx, frame_command(2), &metrics).unwrap();
+        assert_eq!(metrics.frames_dropped.with_label_values(&["node"]).get(), 1);
+    }
+
+    #[test]
+    fn counts_read_errors_by_kind() {
+        let metrics = Metrics::new().unwrap();
+        let count = |kind: &str| metrics.serial_read_errors.with_label_values(&["node", kind]).get();
+
+        Acquisition::count_read_error("node", &ReadError::Timeout.into(), &metrics);
+        Acquisition::count_read_error("node", &ReadError::Timeout.into(), &metrics);
+        Acquisition::count_read_error("node", &ReadError::Io(std::io::Error::from_raw_os_error(5)).into(), &metrics);
+        Acquisition::count_read_error("node", &anyhow::anyhow!("Unable to reconnect"), &metrics);
+        Acquisition::count_read_error("node", &ReadError::Parse("invalid UTF-8".to_string()).into(), &metrics);
+
+        assert_eq!(count("timeout"), 2);
+        assert_eq!(count("io"), 2);
+        assert_eq!(count("parse"), 1);
+        assert_eq!(count("lock"), 0);
+    }
+
+    fn untimed_frame() -> Frame {
+        Frame::parse(&testutil::frame_line(0, true, &[1, 2, 3]).replacen("$0,", "$,", 1)).unwrap()
+    }
+
+    #[test]
+    fn a_missing_timestamp_is_reported() {
+        let mut timestamps = TimestampChecker::new();
+        assert_eq!(timestamps.check(&untimed_frame()), Some(TimestampProblem::Missing));
+        assert_eq!(timestamps.check(&testutil::frame(1, true, &[1, 2, 3])), None);
+    }
+
+    #[test]
+    fn a_zero_timestamp_counts_as_missing() {
+        let mut timestamps = TimestampChecker::new();
+        assert_eq!(timestamps.check(&testutil::frame(5, true, &[1, 2, 3])), None);
+        assert_eq!(timestamps.check(&testutil::frame(0, true, &[1, 2, 3])), Some(TimestampProblem::Missing));
+        // Compared with the last real timestamp
+        assert_eq!(timestamps.check(&testutil::frame(6, true, &[1, 2, 3])), None);
+    }
+
+    #[test]
+    fn a_decreasing_timestamp_is_reported() {
+        let mut timestamps = TimestampChecker::new();
+        assert_eq!(timestamps.check(&testutil::frame(10, true, &[1, 2, 3])), None);
+        assert_eq!(timestamps.check(&testutil::frame(7, true, &[1, 2, 3])), Some(TimestampProblem::NotIncreasing { previous: 10, timestamp: 7 }));
+        assert_eq!(timestamps.check(&testutil::frame(8, true, &[1, 2, 3])), None);
+    }
+
+    #[test]
+    fn detects_a_jump_in_the_timestamps() {
+        // 1000 samples at 1000 Hz, so one frame per second
+        let frame = |timestamp| testutil::frame(timestamp, true, &[0; 1000]);
+        let mut gaps = GapDetector::new(0.5);
+
+        assert_eq!(gaps.check(&frame(100)), None);
+        assert_eq!(gaps.check(&frame(101)), None);
+        assert_eq!(gaps.check(&frame(104)), Some(FrameGap { previous: 101, timestamp: 104, missing_frames: 2 }));
+        assert_eq!(gaps.check(&frame(105)), None);
+        // Going backwards isn't a gap
+        assert_eq!(gaps.check(&frame(90)), None);
+        assert_eq!(gaps.check(&frame(91)), None);
+    }
+
+    #[test]
+    fn untimed_frames_restart_gap_detection() {
+        let mut gaps = GapDetector::new(0.5);
+        assert_eq!(gaps.check(&testutil::frame(100, true, &[0; 1000])), None);
+        assert_eq!(gaps.check(&testutil::frame(0, false, &[0; 1000])), None);
+        assert_eq!(gaps.check(&testutil::frame(110, true, &[0; 1000])), None);
+    }
+
+    #[test]
+    fn led_fix_waits_for_consecutive_frames() {
+        let mut fix = FixDebouncer::new(3);
+        assert!(fix.update(true));
+        // A marginal fix coming and going doesn't change the LED
+        for _ in 0..5 {
+            assert!(fix.update(false));
+            assert!(fix.update(false));
+            assert!(fix.update(true));
+        }
+        assert!(fix.update(false));
+        assert!(fix.update(false));
+        assert!(!fix.update(false));
+        assert!(!fix.update(true));
+    }
+
+    #[test]
+    fn sample_rate_is_smoothed() {
+        let mut rate = MovingAverage::new(0.5);
+        assert_eq!(rate.add(1000.0), 1000.0);
+        assert_eq!(rate.add(1100.0), 1050.0);
+        assert_eq!(rate.add(1100.0), 1075.0);
+    }
+
+    #[tokio::test]
+    async fn announces_each_gps_fix_change_once() {
+        let dir = testutil::temp_dir("acquisition-fix-changes");
+        let fixes = [false, false, true, true, true, false, false];
+        let port = fixes.iter().enumerate().fold(MockSerialPort::new(), |port, (i, has_fix)| {
+            port.line(&testutil::frame_line(i as i64 + 1, *has_fix, &[1, 2, 3]))
+        });
+        let (tx, mut service_rx) = broadcast::channel(64);
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let run = tokio::spawn(Acquisition::with_mock_port(&dir, port, tx).run(shutdown_tx.subscribe()));
+
+        let mut changes = Vec::new();
+        let mut frames = 0;
+        while frames < fixes.len() {
+            match tokio::time::timeout(Duration::from_secs(5), service_rx.recv()).await.expect("Timed out waiting for frames").unwrap() {
+                ServiceMessage::GpsFixChanged { has_fix, .. } => changes.push(has_fix),
+                ServiceMessage::NewFrame { .. } => frames += 1,
+                _ => {},
+            }
+        }
+        shutdown_tx.send(()).unwrap();
+        run.await.unwrap().unwrap();
+
+        // The first frame tells what the fix is, then only the changes follow
+        assert_eq!(changes, vec![false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn watchdog_flags_a_stalled_node() {
+        let led = LED::with_pins(Box::new(MockPin::default()), Box::new(MockPin::default()), Box::new(MockPin::default()), false);
+        let led = Arc::new(SharedLed::new(led, 1));
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let last_frame_at = Arc::new(Mutex::new(Instant::now() - Duration::from_secs(5)));
+        let handle = tokio::spawn(Acquisition::watchdog("node".to_string(), Duration::from_secs(1), last_frame_at, led.clone(), 0, metrics.clone()));
+
+        let stalled = LedPattern::Blink { color: LedColor::Red, period_ms: 500 };
+        wait_for("stalled LED", || led.get_pattern() == stalled).await;
+        handle.abort();
+
+        assert!(metrics.seconds_since_last_frame.with_label_values(&["node"]).get() >= 5.0);
+    }
+
+    #[tokio::test]
+    async fn disk_monitor_pauses_writes_when_space_runs_low() {
+        let dir = testutil::temp_dir("acquisition-disk-monitor");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let disk_full = Arc::new(AtomicBool::new(false));
+        let handle = tokio::spawn(Acquisition::disk_monitor("node".to_string(), dir, u64::MAX, disk_full.clone(), metrics.clone()));
+
+        wait_for("disk full", || disk_full.load(Ordering::Relaxed)).await;
+        handle.abort();
+
+        assert!(metrics.disk_free_bytes.with_label_values(&["node"]).get() > 0);
+    }
+
+    // Writes CSV files of three-sample frames to `dir`
+    fn writer_task(dir: &Path, metrics: Arc<Metrics>) -> WriterTask {
+        writer_task_with(OutputWriterConfig::Csv(CsvWriterConfig {
+            node_id: "node".to_string(),
+            output_path: dir.to_path_buf(),
+            samples_per_frame: 3,
+            decimation: Decimation::default(),
+            flush: FlushPolicy::default(),
+            filename_timezone: FilenameTimezone::default(),
+        }), metrics)
+    }
+
+    fn writer_task_with(writer_config: OutputWriterConfig, metrics: Arc<Metrics>) -> WriterTask {
+        let led = LED::with_pins(Box::new(MockPin::default()), Box::new(MockPin::default()), Box::new(MockPin::default()), false);
+        WriterTask {
+            node_id: "node".to_string(),
+            writer: Some(OutputWriter::new(writer_config.clone()).unwrap()),
+            writer_config,
+            file_duration: Duration::from_secs(3600),
+            file_started: Instant::now(),
+            max_file_bytes: None,
+            led: Arc::new(SharedLed::new(led, 1)),
+            led_source: 0,
+            metrics,
+            file_size: 0,
+            rotation_retry: None,
+            events: None,
+        }
+    }
+
+    fn failing_writer_task(dir: &Path, metrics: Arc<Metrics>) -> WriterTask {
+        let mut task = writer_task(dir, metrics);
+        match task.writer.as_mut().unwrap() {
+            OutputWriter::Csv(writer) => writer.redirect(Path::new("/dev/full")),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+        task
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_written_and_files_rotated() {
+        let dir = testutil::temp_dir("acquisition-writer-metrics");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let mut task = writer_task(&dir, metrics.clone());
+        task.file_duration = Duration::from_millis(200);
+        let bytes_written = || metrics.bytes_written.with_label_values(&["node"]).get();
+        let files_rotated = || metrics.files_rotated.with_label_values(&["node"]).get();
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        let run = tokio::spawn(task.run(writer_rx));
+        writer_tx.send(frame_command(1)).await.unwrap();
+        wait_for("bytes_written to go up", || bytes_written() > 0).await;
+        assert_eq!(files_rotated(), 0);
+
+        let before = bytes_written();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        writer_tx.send(frame_command(2)).await.unwrap();
+        wait_for("files_rotated to go up", || files_rotated() == 1).await;
+        wait_for("bytes_written to count the new file", || bytes_written() > before).await;
+
+        drop(writer_tx);
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_a_new_file_once_max_file_bytes_is_reached() {
+        let dir = testutil::temp_dir("acquisition-max-file-bytes");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let mut task = writer_task(&dir, metrics.clone());
+        task.max_file_bytes = Some(1);
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        let run = tokio::spawn(task.run(writer_rx));
+        writer_tx.send(frame_command(1)).await.unwrap();
+        wait_for("the first frame to be written", || metrics.frames_written.with_label_values(&["node"]).get() == 1).await;
+        assert_eq!(testutil::files_with_extension(&dir, "csv").len(), 1);
+
+        writer_tx.send(frame_command(2)).await.unwrap();
+        drop(writer_tx);
+        run.await.unwrap().unwrap();
+
+        assert_eq!(metrics.files_rotated.with_label_values(&["node"]).get(), 1);
+        assert_eq!(testutil::files_with_extension(&dir, "csv").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rotating_twice_in_one_second_keeps_every_frame() {
+        let dir = testutil::temp_dir("acquisition-max-file-bytes-fast");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let mut task = writer_task(&dir, metrics.clone());
+        task.max_file_bytes = Some(1);
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        let run = tokio::spawn(task.run(writer_rx));
+        for timestamp in 1..=3 {
+            writer_tx.send(frame_command(timestamp)).await.unwrap();
+            wait_for("the frame to be written", || metrics.frames_written.with_label_values(&["node"]).get() == timestamp as u64).await;
+        }
+        drop(writer_tx);
+        run.await.unwrap().unwrap();
+
+        assert_eq!(metrics.files_rotated.with_label_values(&["node"]).get(), 2);
+        let files = testutil::files_with_extension(&dir, "csv");
+        // At least two of the three were started in the same second
+        assert!(files.iter().any(|path| path.to_string_lossy().ends_with("_1.csv")), "{:?}", files);
+        let rows: Vec<Vec<String>> = files.iter().map(|path| testutil::csv_rows(path)).collect();
+        assert_eq!(rows.len(), 3);
+        for (row, timestamp) in rows.iter().zip(1..) {
+            assert_eq!(row.len(), 1);
+            assert!(row[0].starts_with(&format!("{},", timestamp)));
+        }
+        assert_eq!(manifest::entries(&dir).unwrap().len(), 3);
+        assert!(testutil::stale_manifest_entries(&dir).is_empty());
+    }
+
+    #[test]
+    fn rotation_finishes_the_old_file_before_starting_the_next() {
+        let dir = testutil::temp_dir("acquisition-rotation-order");
+        let mut task = writer_task(&dir, Arc::new(Metrics::new().unwrap()));
+        let first = task.writer.as_ref().unwrap().file_path().to_path_buf();
+        let valid_config = task.writer_config.clone();
+
+        // A config the next file can't be opened with, to stop rotate()
+        // between closing the old file and opening the new one
+        let mut broken_config = valid_config.clone();
+        match &mut broken_config {
+            OutputWriterConfig::Csv(config) => config.decimation.factor = 0,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+        task.writer_config = broken_config;
+        assert!(task.rotate().is_err());
+        assert!(task.writer.is_none());
+        let entries = manifest::entries(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, first.file_name().unwrap().to_string_lossy());
+
+        task.writer_config = valid_config;
+        task.rotate().unwrap();
+        let second = task.writer.as_ref().unwrap().file_path().to_path_buf();
+        assert_ne!(second, first);
+        assert!(second.exists());
+        assert_eq!(manifest::entries(&dir).unwrap().len(), 1);
+        assert!(testutil::stale_manifest_entries(&dir).is_empty());
+    }
+
+    #[tokio::test]
+    async fn writer_moves_to_a_new_file_after_a_failed_write() {
+        let dir = testutil::temp_dir("acquisition-write-failure");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let task = failing_writer_task(&dir, metrics.clone());
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        writer_tx.send(frame_command(1)).await.unwrap();
+        writer_tx.send(frame_command(2)).await.unwrap();
+        drop(writer_tx);
+        task.run(writer_rx).await.unwrap();
+
+        assert_eq!(metrics.write_errors.with_label_values(&["node"]).get(), 1);
+        assert_eq!(metrics.files_rotated.with_label_values(&["node"]).get(), 1);
+        assert_eq!(metrics.frames_written.with_label_values(&["node"]).get(), 1);
+        // Failed writes take time too
+        assert_eq!(metrics.frame_latency.with_label_values(&["node"]).get_sample_count(), 2);
+        // Both files are from the same second, each with a name of its own
+        let files = testutil::files_with_extension(&dir, "csv");
+        assert_eq!(files.len(), 2);
+        assert!(testutil::csv_rows(&files[0]).is_empty());
+        let rows = testutil::csv_rows(&files[1]);
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].starts_with("2,"));
+        // The failing file can't be flushed on close, so only the new one is recorded
+        let entries = manifest::entries(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, files[1].file_name().unwrap().to_string_lossy());
+        assert!(testutil::stale_manifest_entries(&dir).is_empty());
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[tokio::test]
+    async fn a_comment_the_file_cant_store_is_dropped() {
+        let dir = testutil::temp_dir("acquisition-rejected-comment");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let task = writer_task_with(OutputWriterConfig::Hdf5(testutil::hdf5_config("node", &dir, 3)), metrics.clone());
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        // As from a garbled `#` line
+        writer_tx.send(WriterCommand::Comment("battery\0low".to_string())).await.unwrap();
+        writer_tx.send(frame_command(1)).await.unwrap();
+        drop(writer_tx);
+        task.run(writer_rx).await.unwrap();
+
+        assert_eq!(metrics.write_errors.with_label_values(&["node"]).get(), 0);
+        assert_eq!(metrics.files_rotated.with_label_values(&["node"]).get(), 0);
+        assert_eq!(metrics.frames_written.with_label_values(&["node"]).get(), 1);
+        assert_eq!(testutil::files_with_extension(&dir, "h5").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn writer_stops_when_the_output_directory_is_gone() {
+        let dir = testutil::temp_dir("acquisition-output-gone");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let task = failing_writer_task(&dir, metrics.clone());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (writer_tx, writer_rx) = mpsc::channel(4);
+        writer_tx.send(frame_command(1)).await.unwrap();
+        drop(writer_tx);
+        assert!(task.run(writer_rx).await.is_err());
+
+        assert_eq!(metrics.write_errors.with_label_values(&["node"]).get(), 1);
+        assert_eq!(metrics.files_rotated.with_label_values(&["node"]).get(), 0);
+    }
+}
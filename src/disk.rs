@@ -0,0 +1,59 @@
+// Checks on the filesystem the output files are written to
+use std::{fs, path::Path};
+
+// Writes and removes a small file, which fails once the filesystem has
+// gone read-only or is completely full
+pub fn check_writable(dir: &Path) -> anyhow::Result<()> {
+    let test_file = dir.join(".heartbeat_write_test");
+    fs::write(&test_file, "test").map_err(|e| anyhow::anyhow!("Unable to write to {}: {}", dir.display(), e))?;
+    fs::remove_file(&test_file)?;
+    Ok(())
+}
+
+// Bytes available to this process on the filesystem holding `dir`
+pub fn free_bytes(dir: &Path) -> anyhow::Result<u64> {
+    Ok(fs2::available_space(dir)?)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub total: u64,
+    pub used: u64,
+    // As free_bytes, so space reserved for root doesn't count
+    pub free: u64,
+}
+
+pub fn usage(dir: &Path) -> anyhow::Result<Usage> {
+    let total = fs2::total_space(dir)?;
+    Ok(Usage {
+        total,
+        used: total.saturating_sub(fs2::free_space(dir)?),
+        free: fs2::available_space(dir)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_writable, free_bytes, usage};
+    use crate::testutil;
+
+    #[test]
+    fn checks_a_writable_directory_and_cleans_up() {
+        let dir = testutil::temp_dir("disk-writable");
+        check_writable(&dir).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        assert!(check_writable(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn reports_space_on_the_filesystem() {
+        let dir = testutil::temp_dir("disk-space");
+        assert!(free_bytes(&dir).is_ok());
+
+        assert!(free_bytes(&dir.join("missing")).is_err());
+
+        let usage = usage(&dir).unwrap();
+        assert!(usage.used + usage.free <= usage.total);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+// Converts an HDF5 capture file to the CSV layout CsvWriter uses, run with
+// `heartbeat-acquisition export --format csv <in.h5> <out.csv>`
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use crate::writer::{hdf5::CPU_TIME_UNITS, Decimation};
+
+// Written to gps_time for frames without a GPS timestamp
+const MISSING_GPS_TIME: i64 = -1;
+// Frames read from the samples dataset at a time
+const BLOCK_ROWS: usize = 1024;
+
+// Returns the number of frames written
+pub fn to_csv(input: &Path, output: &Path, decimation: Decimation) -> anyhow::Result<usize> {
+    decimation.check()?;
+
+    let file = hdf5::File::open(input)?;
+    let samples = file.dataset("samples")?;
+    let shape = samples.shape();
+    let (frames, samples_per_frame) = match shape.as_slice() {
+        [frames, samples_per_frame] => (*frames, *samples_per_frame),
+        _ => return Err(anyhow::anyhow!("samples has shape {:?}, expected 2 dimensions", shape)),
+    };
+
+    let gps_time = file.dataset("gps_time")?.read_raw::<i64>()?;
+    // CsvWriter writes whole seconds, older files have them too
+    let cpu_time_dataset = file.dataset("cpu_time")?;
+    let in_millis = cpu_time_dataset.attr("UNITS").ok()
+        .map(|attr| attr.read_scalar::<hdf5::types::VarLenUnicode>())
+        .transpose()?
+        .is_some_and(|units| units.as_str() == CPU_TIME_UNITS);
+    let cpu_time: Vec<i64> = cpu_time_dataset.read_raw::<i64>()?.into_iter()
+        .map(|time| if in_millis { time.div_euclid(1000) } else { time })
+        .collect();
+    let latitude = file.dataset("latitude")?.read_raw::<f32>()?;
+    let longitude = file.dataset("longitude")?.read_raw::<f32>()?;
+    let elevation = file.dataset("elevation")?.read_raw::<f32>()?;
+    let satellites = file.dataset("satellites")?.read_raw::<i8>()?;
+    for (name, len) in [("gps_time", gps_time.len()), ("cpu_time", cpu_time.len()), ("latitude", latitude.len()), ("longitude", longitude.len()), ("elevation", elevation.len()), ("satellites", satellites.len())] {
+        if len != frames {
+            return Err(anyhow::anyhow!("{} has {} entries but samples has {} frames", name, len, frames));
+        }
+    }
+
+    // The file may already be decimated, and columns are named after the
+    // sample's position in the original frame
+    let file_factor = file.attr("DECIMATION_FACTOR")?.read_scalar::<u32>()? as usize;
+    let step = file_factor * decimation.factor;
+
+    let mut csv = BufWriter::new(File::create(output)?);
+    let mut header = String::from("gps_time,cpu_time,latitude,longitude,elevation,satellites");
+    for i in 0..decimation.width(samples_per_frame) {
+        header.push_str(&format!(",sample_{}", i * step));
+    }
+    writeln!(csv, "{}", header)?;
+
+    // HDF5 doesn't record which frame a comment came after, so they all go
+    // at the top
+    for comment in file.dataset("comments")?.read_raw::<hdf5::types::VarLenUnicode>()? {
+        writeln!(csv, "# {}", comment.as_str().trim_end())?;
+    }
+
+    for start in (0..frames).step_by(BLOCK_ROWS) {
+        let end = (start + BLOCK_ROWS).min(frames);
+        let block = samples.read_slice_2d::<i32, _>((start..end, ..))?;
+        for (offset, row) in block.outer_iter().enumerate() {
+            let index = start + offset;
+            let gps_time = match gps_time[index] {
+                MISSING_GPS_TIME => String::new(),
+                timestamp => timestamp.to_string(),
+            };
+
+            let mut line = format!("{},{},{},{},{},{}",
+                gps_time,
+                cpu_time[index],
+                latitude[index],
+                longitude[index],
+                elevation[index],
+                satellites[index]);
+
+            for sample in decimation.apply(&row.to_vec()) {
+                line.push_str(&format!(",{}", sample));
+            }
+            writeln!(csv, "{}", line)?;
+        }
+    }
+
+    csv.flush()?;
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::to_csv;
+    use crate::{testutil, writer::{hdf5::HDF5Writer, Decimation, DecimationMode, Writer}};
+
+    #[tokio::test]
+    async fn writes_the_csv_writer_layout() {
+        let dir = testutil::temp_dir("export");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap() + chrono::Duration::milliseconds(500);
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 4)).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294400, true, &[1, 2, 3, 4])).await.unwrap();
+        writer.write_comment("battery low").await.unwrap();
+        let untimed = crate::serial::Frame::parse(&testutil::frame_line(0, false, &[5, 6, 7, 8]).replacen("$0,", "$,", 1)).unwrap();
+        writer.write_frame(when, &untimed).await.unwrap();
+        let input = writer.close().unwrap();
+
+        let output = dir.join("out.csv");
+        assert_eq!(to_csv(&input, &output, Decimation::default()).unwrap(), 2);
+        let contents = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(contents.lines().collect::<Vec<_>>(), vec![
+            "gps_time,cpu_time,latitude,longitude,elevation,satellites,sample_0,sample_1,sample_2,sample_3",
+            "# battery low",
+            "1709294400,1709294400,52.1,4.3,10,7,1,2,3,4",
+            ",1709294400,52.1,4.3,10,7,5,6,7,8",
+        ]);
+
+        let decimated = dir.join("decimated.csv");
+        to_csv(&input, &decimated, Decimation { factor: 2, mode: DecimationMode::Average }).unwrap();
+        assert_eq!(std::fs::read_to_string(&decimated).unwrap().lines().next(), Some("gps_time,cpu_time,latitude,longitude,elevation,satellites,sample_0,sample_2"));
+        assert_eq!(testutil::csv_rows(&decimated), vec![
+            "1709294400,1709294400,52.1,4.3,10,7,1,3",
+            ",1709294400,52.1,4.3,10,7,5,7",
+        ]);
+    }
+}
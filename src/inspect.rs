@@ -0,0 +1,194 @@
+// Summarizes an HDF5 capture file written by HDF5Writer, run with
+// `heartbeat-acquisition inspect <file>`
+use std::{fmt, path::Path};
+
+use hdf5::types::VarLenUnicode;
+
+// Written to gps_time for frames without a GPS timestamp
+const MISSING_GPS_TIME: i64 = -1;
+// How much later than expected a frame may be before it counts as a gap
+const GAP_TOLERANCE_SECS: f64 = 0.5;
+// Datasets with one entry per frame
+const PER_FRAME_DATASETS: &[&str] = &["gps_time", "cpu_time", "latitude", "longitude", "elevation", "satellites", "gps_fix", "clipping", "valid"];
+
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    node_id: String,
+    frames: usize,
+    samples_per_frame: usize,
+    sample_rate: Option<f32>,
+    // First and last GPS time, frames without one left out
+    time_range: Option<(i64, i64)>,
+    // Odd but readable, one line each
+    anomalies: Vec<String>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "node_id:      {}", self.node_id)?;
+        writeln!(f, "frames:       {} of {} samples", self.frames, self.samples_per_frame)?;
+        match self.sample_rate {
+            Some(sample_rate) => writeln!(f, "sample rate:  {} Hz", sample_rate)?,
+            None => writeln!(f, "sample rate:  unknown")?,
+        }
+        match self.time_range {
+            Some((first, last)) => writeln!(f, "time range:   {} to {} ({} s)", format_time(first), format_time(last), last - first)?,
+            None => writeln!(f, "time range:   no GPS timestamps")?,
+        }
+        if self.anomalies.is_empty() {
+            write!(f, "anomalies:    none")
+        } else {
+            write!(f, "anomalies:")?;
+            for anomaly in &self.anomalies {
+                write!(f, "\n  - {}", anomaly)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+// Prints the summary and returns false if the file is unreadable or corrupt
+pub fn run(path: &Path) -> bool {
+    match summarize(path) {
+        Ok(summary) => {
+            println!("{}", summary);
+            true
+        },
+        Err(e) => {
+            println!("{} is corrupt or not a capture file: {:#}", path.display(), e);
+            false
+        }
+    }
+}
+
+// Errors for anything that makes the file unusable: it won't open, a dataset
+// or attribute the writer always creates is missing, or the per-frame
+// datasets disagree on the number of frames
+pub fn summarize(path: &Path) -> anyhow::Result<Summary> {
+    let file = hdf5::File::open(path)?;
+
+    let node_id = file.attr("NODE_ID")?.read_scalar::<VarLenUnicode>()?.to_string();
+    let samples = file.dataset("samples")?;
+    let shape = samples.shape();
+    let (frames, samples_per_frame) = match shape.as_slice() {
+        [frames, samples_per_frame] => (*frames, *samples_per_frame),
+        _ => return Err(anyhow::anyhow!("samples has shape {:?}, expected 2 dimensions", shape)),
+    };
+
+    for name in PER_FRAME_DATASETS {
+        let len = file.dataset(name)?.size();
+        if len != frames {
+            return Err(anyhow::anyhow!("{} has {} entries but samples has {} frames", name, len, frames));
+        }
+    }
+
+    // Written with the first frame, so absent from empty files
+    let sample_rate = file.attr("EFFECTIVE_SAMPLE_RATE").ok()
+        .map(|attr| attr.read_scalar::<f32>())
+        .transpose()?;
+
+    let gps_time = file.dataset("gps_time")?.read_raw::<i64>()?;
+    let timed: Vec<i64> = gps_time.iter().copied().filter(|time| *time != MISSING_GPS_TIME).collect();
+    let time_range = timed.first().zip(timed.last()).map(|(first, last)| (*first, *last));
+
+    let mut anomalies = Vec::new();
+
+    let untimed = frames - timed.len();
+    if untimed > 0 {
+        anomalies.push(format!("{} frames without a GPS timestamp", untimed));
+    }
+
+    let backwards: Vec<(usize, i64, i64)> = timed_pairs(&gps_time).filter(|(_, previous, time)| time <= previous).collect();
+    if let Some((index, previous, time)) = backwards.first() {
+        anomalies.push(format!("GPS time doesn't increase {} times, first at frame {} ({} to {})", backwards.len(), index, previous, time));
+    }
+
+    if let Some(sample_rate) = sample_rate.filter(|sample_rate| *sample_rate > 0.0) {
+        let cadence = samples_per_frame as f64 / sample_rate as f64;
+        let gaps: Vec<(usize, i64, i64)> = timed_pairs(&gps_time)
+            .filter(|(_, previous, time)| (time - previous) as f64 - cadence > GAP_TOLERANCE_SECS)
+            .collect();
+        if let Some((index, previous, time)) = gaps.first() {
+            anomalies.push(format!("{} gaps in GPS time, first at frame {} ({} to {})", gaps.len(), index, previous, time));
+        }
+    }
+
+    if frames > 0 && samples_per_frame > 0 {
+        let all_zero: Vec<usize> = samples.read_2d::<i32>()?.outer_iter()
+            .enumerate()
+            .filter(|(_, row)| row.iter().all(|sample| *sample == 0))
+            .map(|(index, _)| index)
+            .collect();
+        if let Some(index) = all_zero.first() {
+            anomalies.push(format!("{} frames with only zero samples, first at frame {}", all_zero.len(), index));
+        }
+    }
+
+    let invalid = file.dataset("valid")?.read_raw::<u8>()?.iter().filter(|valid| **valid == 0).count();
+    if invalid > 0 {
+        anomalies.push(format!("{} frames marked invalid", invalid));
+    }
+
+    Ok(Summary {
+        node_id,
+        frames,
+        samples_per_frame,
+        sample_rate,
+        time_range,
+        anomalies,
+    })
+}
+
+// Consecutive frames that both have a GPS time, as (index of the second,
+// previous time, time)
+fn timed_pairs(gps_time: &[i64]) -> impl Iterator<Item = (usize, i64, i64)> + '_ {
+    gps_time.windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0] != MISSING_GPS_TIME && pair[1] != MISSING_GPS_TIME)
+        .map(|(index, pair)| (index + 1, pair[0], pair[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::summarize;
+    use crate::{testutil, writer::{hdf5::HDF5Writer, Writer}};
+
+    #[tokio::test]
+    async fn summarizes_a_written_file() {
+        let dir = testutil::temp_dir("inspect");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        // A second of samples per frame, so consecutive timestamps are 1 s apart
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 1000)).unwrap();
+        for (timestamp, sample) in [(1709294400, 5), (1709294401, 0), (1709294405, 7)] {
+            writer.write_frame(when, &testutil::frame(timestamp, true, &[sample; 1000])).await.unwrap();
+        }
+        let path = writer.close().unwrap();
+
+        let summary = summarize(&path).unwrap();
+        assert_eq!(summary.to_string(), [
+            "node_id:      node",
+            "frames:       3 of 1000 samples",
+            "sample rate:  1000 Hz",
+            "time range:   2024-03-01 12:00:00 UTC to 2024-03-01 12:00:05 UTC (5 s)",
+            "anomalies:",
+            "  - 1 gaps in GPS time, first at frame 2 (1709294401 to 1709294405)",
+            "  - 1 frames with only zero samples, first at frame 1",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let dir = testutil::temp_dir("inspect-missing");
+        assert!(summarize(&dir.join("missing.h5")).is_err());
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(PartialEq, Copy, Clone)]
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread::JoinHandle, time::Duration};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum LedColor {
     Red,
     Green,
@@ -10,108 +12,431 @@ pub enum LedColor {
     Off
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LedPattern {
+    Solid(LedColor),
+    // On for half the period, off for the other half
+    Blink { color: LedColor, period_ms: u64 },
+    // A short flash at the start of each period
+    Pulse { color: LedColor, period_ms: u64 },
+}
+
+impl LedPattern {
+    pub fn color(&self) -> LedColor {
+        match self {
+            LedPattern::Solid(color) => *color,
+            LedPattern::Blink { color, .. } => *color,
+            LedPattern::Pulse { color, .. } => *color,
+        }
+    }
+}
+
+pub trait GpioPin: Send {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
 #[cfg(target_os = "linux")]
-pub mod led {
-    use rppal::gpio::{Gpio, OutputPin};
-    use std::error::Error;
-    use super::LedColor;
-
-    pub struct LED {
-        pin_red: OutputPin,
-        pin_green: OutputPin,
-        pin_blue: OutputPin,
-        color: LedColor
-    }
-
-    impl LED {
-        pub fn new(pin_red: u8, pin_green: u8, pin_blue: u8) -> anyhow::Result<LED> {
-            Ok(LED {
-                pin_red: Gpio::new()?.get(pin_red)?.into_output(),
-                pin_green: Gpio::new()?.get(pin_green)?.into_output(),
-                pin_blue: Gpio::new()?.get(pin_blue)?.into_output(),
-                color: LedColor::Off
-            })
+impl GpioPin for rppal::gpio::OutputPin {
+    fn set_high(&mut self) {
+        rppal::gpio::OutputPin::set_high(self);
+    }
+
+    fn set_low(&mut self) {
+        rppal::gpio::OutputPin::set_low(self);
+    }
+}
+
+// A pin that only remembers its level. Clones share the same level, so a
+// clone kept outside the LED can be used to check what was driven.
+#[derive(Clone, Default)]
+pub struct MockPin {
+    high: Arc<AtomicBool>,
+}
+
+impl MockPin {
+    #[cfg(test)]
+    pub fn is_high(&self) -> bool {
+        self.high.load(Ordering::Relaxed)
+    }
+}
+
+impl GpioPin for MockPin {
+    fn set_high(&mut self) {
+        self.high.store(true, Ordering::Relaxed);
+    }
+
+    fn set_low(&mut self) {
+        self.high.store(false, Ordering::Relaxed);
+    }
+}
+
+struct Pins {
+    pin_red: Box<dyn GpioPin>,
+    pin_green: Box<dyn GpioPin>,
+    pin_blue: Box<dyn GpioPin>,
+    active_low: bool,
+}
+
+pub struct LED {
+    pins: Arc<Mutex<Pins>>,
+    pattern: LedPattern,
+    pattern_thread: Option<(Arc<AtomicBool>, JoinHandle<()>)>
+}
+
+impl Pins {
+    fn apply(&mut self, color: LedColor) {
+        let (red, green, blue) = match color {
+            LedColor::Red => (true, false, false),
+            LedColor::Green => (false, true, false),
+            LedColor::Blue => (false, false, true),
+            LedColor::Cyan => (false, true, true),
+            LedColor::Magenta => (true, false, true),
+            LedColor::Yellow => (true, true, false),
+            LedColor::White => (true, true, true),
+            LedColor::Off => (false, false, false),
+        };
+
+        Self::drive(self.pin_red.as_mut(), red, self.active_low);
+        Self::drive(self.pin_green.as_mut(), green, self.active_low);
+        Self::drive(self.pin_blue.as_mut(), blue, self.active_low);
+    }
+
+    // Common-anode LEDs light up when the pin is pulled low
+    fn drive(pin: &mut dyn GpioPin, on: bool, active_low: bool) {
+        if on != active_low {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+    }
+}
+
+impl LED {
+    #[cfg(target_os = "linux")]
+    pub fn new(pin_red: u8, pin_green: u8, pin_blue: u8, active_low: bool) -> anyhow::Result<LED> {
+        let gpio = rppal::gpio::Gpio::new()?;
+        Ok(LED::with_pins(
+            Box::new(gpio.get(pin_red)?.into_output()),
+            Box::new(gpio.get(pin_green)?.into_output()),
+            Box::new(gpio.get(pin_blue)?.into_output()),
+            active_low))
+    }
+
+    // There is no GPIO off the Raspberry Pi, the LED just records its state
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_pin_red: u8, _pin_green: u8, _pin_blue: u8, active_low: bool) -> anyhow::Result<LED> {
+        Ok(LED::disabled(active_low))
+    }
+
+    // Losing the status light shouldn't stop acquisition. If the GPIO can't
+    // be opened, e.g. because another process holds the pins, this warns and
+    // carries on with an LED that drives nothing.
+    pub fn new_or_disabled(pin_red: u8, pin_green: u8, pin_blue: u8, active_low: bool) -> LED {
+        LED::or_disabled(LED::new(pin_red, pin_green, pin_blue, active_low), active_low)
+    }
+
+    fn or_disabled(led: anyhow::Result<LED>, active_low: bool) -> LED {
+        led.unwrap_or_else(|e| {
+            log::warn!("Unable to set up the status LED, running without it: {:#}", e);
+            LED::disabled(active_low)
+        })
+    }
+
+    // Only records its state, on pins that aren't connected to anything
+    fn disabled(active_low: bool) -> LED {
+        LED::with_pins(
+            Box::new(MockPin::default()),
+            Box::new(MockPin::default()),
+            Box::new(MockPin::default()),
+            active_low)
+    }
+
+    pub fn with_pins(pin_red: Box<dyn GpioPin>, pin_green: Box<dyn GpioPin>, pin_blue: Box<dyn GpioPin>, active_low: bool) -> LED {
+        let mut pins = Pins {
+            pin_red,
+            pin_green,
+            pin_blue,
+            active_low,
+        };
+        // Output pins start out low, which is "on" for a common-anode LED
+        pins.apply(LedColor::Off);
+
+        LED {
+            pins: Arc::new(Mutex::new(pins)),
+            pattern: LedPattern::Solid(LedColor::Off),
+            pattern_thread: None
+        }
+    }
+
+    pub fn set_color(&mut self, color: LedColor) -> anyhow::Result<()> {
+        self.set_pattern(LedPattern::Solid(color))
+    }
+
+    pub fn set_pattern(&mut self, pattern: LedPattern) -> anyhow::Result<()> {
+        // Callers set the status on every frame, don't restart a running pattern
+        if pattern == self.pattern && (self.pattern_thread.is_some() || matches!(pattern, LedPattern::Solid(_))) {
+            return Ok(());
         }
 
-        pub fn set_color(&mut self, color: LedColor) -> anyhow::Result<()> {
-            match color {
-                LedColor::Red => {
-                    self.pin_red.set_high();
-                    self.pin_green.set_low();
-                    self.pin_blue.set_low();
-                },
-                LedColor::Green => {
-                    self.pin_red.set_low();
-                    self.pin_green.set_high();
-                    self.pin_blue.set_low();
-                },
-                LedColor::Blue => {
-                    self.pin_red.set_low();
-                    self.pin_green.set_low();
-                    self.pin_blue.set_high();
-                },
-                LedColor::Cyan => {
-                    self.pin_red.set_low();
-                    self.pin_green.set_high();
-                    self.pin_blue.set_high();
-                },
-                LedColor::Magenta => {
-                    self.pin_red.set_high();
-                    self.pin_green.set_low();
-                    self.pin_blue.set_high();
-                },
-                LedColor::Yellow => {
-                    self.pin_red.set_high();
-                    self.pin_green.set_high();
-                    self.pin_blue.set_low();
-                },
-                LedColor::White => {
-                    self.pin_red.set_high();
-                    self.pin_green.set_high();
-                    self.pin_blue.set_high();
-                },
-                LedColor::Off => {
-                    self.pin_red.set_low();
-                    self.pin_green.set_low();
-                    self.pin_blue.set_low();
-                },
+        self.stop_pattern_thread();
+        self.pattern = pattern; // Save the current pattern state
+
+        let (color, on, off) = match pattern {
+            LedPattern::Solid(color) => {
+                self.pins.lock().map_err(|_| anyhow::anyhow!("Error locking mutex"))?.apply(color);
+                return Ok(());
+            },
+            LedPattern::Blink { color, period_ms } => (color, period_ms / 2, period_ms - period_ms / 2),
+            LedPattern::Pulse { color, period_ms } => (color, period_ms / 10, period_ms - period_ms / 10),
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_inner = stop.clone();
+        let pins = self.pins.clone();
+        let handle = std::thread::spawn(move || {
+            while !stop_inner.load(Ordering::Relaxed) {
+                if let Ok(mut pins) = pins.lock() {
+                    pins.apply(color);
+                }
+                sleep_unless_stopped(&stop_inner, Duration::from_millis(on));
+
+                if let Ok(mut pins) = pins.lock() {
+                    pins.apply(LedColor::Off);
+                }
+                sleep_unless_stopped(&stop_inner, Duration::from_millis(off));
             }
-            self.color = color; // Save the current color state
-            Ok(())
+        });
+
+        self.pattern_thread = Some((stop, handle));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn get_color(&self) -> LedColor {
+        self.pattern.color()
+    }
+
+    #[cfg(test)]
+    pub fn get_pattern(&self) -> LedPattern {
+        self.pattern
+    }
+
+    fn stop_pattern_thread(&mut self) {
+        if let Some((stop, handle)) = self.pattern_thread.take() {
+            stop.store(true, Ordering::Relaxed);
+            let _ = handle.join();
         }
+    }
+}
+
+impl Drop for LED {
+    fn drop(&mut self) {
+        self.stop_pattern_thread();
+    }
+}
+
+// Sleeps in small steps so a pattern change doesn't wait out a long period
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let step = Duration::from_millis(10);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(step.min(duration - slept));
+        slept += step;
+    }
+}
+
+// Lets several acquisition loops share one LED. Each loop reports its own
+// pattern, and red wins if any of them is in an error state.
+pub struct SharedLed {
+    led: std::sync::Mutex<LED>,
+    patterns: std::sync::Mutex<Vec<LedPattern>>,
+}
 
-        pub fn get_color(&self) -> LedColor {
-            self.color
+impl SharedLed {
+    pub fn new(led: LED, sources: usize) -> SharedLed {
+        SharedLed {
+            led: std::sync::Mutex::new(led),
+            patterns: std::sync::Mutex::new(vec![LedPattern::Solid(LedColor::Off); sources]),
         }
     }
 
+    pub fn set_color(&self, source: usize, color: LedColor) -> anyhow::Result<()> {
+        self.set_pattern(source, LedPattern::Solid(color))
+    }
+
+    pub fn set_pattern(&self, source: usize, pattern: LedPattern) -> anyhow::Result<()> {
+        let mut patterns = self.patterns.lock().map_err(|_| anyhow::anyhow!("Error locking mutex"))?;
+        patterns[source] = pattern;
+
+        let pattern = match patterns.iter().find(|p| p.color() == LedColor::Red) {
+            Some(error) => *error,
+            None => pattern,
+        };
+
+        self.set_all(pattern)
+    }
+
+    // Sets the LED directly, regardless of what the sources last reported
+    pub fn set_all(&self, pattern: LedPattern) -> anyhow::Result<()> {
+        let mut led = self.led.lock().map_err(|_| anyhow::anyhow!("Error locking mutex"))?;
+        led.set_pattern(pattern)
+    }
+
+    #[cfg(test)]
+    pub fn get_pattern(&self) -> LedPattern {
+        self.led.lock().unwrap().get_pattern()
+    }
 }
 
-#[cfg(not(target_os = "linux"))]
-pub mod led {
-    use std::error::Error;
-    use super::LedColor;
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
 
-    pub struct LED {
-        color: LedColor
+    use super::{LedColor, LedPattern, MockPin, SharedLed, LED};
+
+    // The LED and clones of its red, green and blue pins
+    fn led(active_low: bool) -> (LED, [MockPin; 3]) {
+        let pins = [MockPin::default(), MockPin::default(), MockPin::default()];
+        let led = LED::with_pins(Box::new(pins[0].clone()), Box::new(pins[1].clone()), Box::new(pins[2].clone()), active_low);
+        (led, pins)
     }
 
-    impl LED {
-        pub fn new(_pin_red: u8, _pin_green: u8, _pin_blue: u8) -> anyhow::Result<LED> {
-            Ok(LED {
-                color: LedColor::Off
-            })
+    fn levels(pins: &[MockPin; 3]) -> [bool; 3] {
+        [pins[0].is_high(), pins[1].is_high(), pins[2].is_high()]
+    }
+
+    // Watches the pins for a while, returning every set of levels seen
+    fn watch(pins: &[MockPin; 3], duration: Duration) -> Vec<[bool; 3]> {
+        let start = Instant::now();
+        let mut seen = Vec::new();
+        while start.elapsed() < duration {
+            let levels = levels(pins);
+            if !seen.contains(&levels) {
+                seen.push(levels);
+            }
+            std::thread::sleep(Duration::from_millis(2));
         }
+        seen
+    }
 
-        pub fn set_color(&mut self, _color: LedColor) -> anyhow::Result<()> {
-            Ok(())
+    const COLORS: [(LedColor, [bool; 3]); 8] = [
+        (LedColor::Red, [true, false, false]),
+        (LedColor::Green, [false, true, false]),
+        (LedColor::Blue, [false, false, true]),
+        (LedColor::Cyan, [false, true, true]),
+        (LedColor::Magenta, [true, false, true]),
+        (LedColor::Yellow, [true, true, false]),
+        (LedColor::White, [true, true, true]),
+        (LedColor::Off, [false, false, false]),
+    ];
+
+    #[test]
+    fn colors_drive_the_right_pins() {
+        let (mut led, pins) = led(false);
+        assert_eq!(levels(&pins), [false, false, false]);
+
+        for (color, expected) in COLORS {
+            led.set_color(color).unwrap();
+            assert_eq!(levels(&pins), expected, "{:?}", color);
+            assert_eq!(led.get_color(), color);
         }
+    }
+
+    #[test]
+    fn active_low_inverts_every_pin() {
+        let (mut led, pins) = led(true);
+        // Off straight away, even though output pins start out low
+        assert_eq!(levels(&pins), [true, true, true]);
 
-        pub fn get_color(&self) -> LedColor {
-            self.color
+        for (color, expected) in COLORS {
+            led.set_color(color).unwrap();
+            assert_eq!(levels(&pins), expected.map(|on| !on), "{:?}", color);
         }
     }
 
-}
+    #[test]
+    fn stores_the_current_pattern() {
+        let (mut led, _pins) = led(false);
+        assert_eq!(led.get_pattern(), LedPattern::Solid(LedColor::Off));
+
+        let blink = LedPattern::Blink { color: LedColor::Magenta, period_ms: 1000 };
+        led.set_pattern(blink).unwrap();
+        assert_eq!(led.get_pattern(), blink);
+        assert_eq!(led.get_color(), LedColor::Magenta);
+
+        let pulse = LedPattern::Pulse { color: LedColor::Yellow, period_ms: 2000 };
+        led.set_pattern(pulse).unwrap();
+        assert_eq!(led.get_pattern(), pulse);
+    }
+
+    #[test]
+    fn blink_toggles_the_color_on_and_off() {
+        let (mut led, pins) = led(false);
+        led.set_pattern(LedPattern::Blink { color: LedColor::Cyan, period_ms: 40 }).unwrap();
+
+        let seen = watch(&pins, Duration::from_millis(200));
+        assert!(seen.contains(&[false, true, true]), "{:?}", seen);
+        assert!(seen.contains(&[false, false, false]), "{:?}", seen);
+    }
+
+    #[test]
+    fn solid_color_stops_a_running_pattern() {
+        let (mut led, pins) = led(true);
+        led.set_pattern(LedPattern::Pulse { color: LedColor::Red, period_ms: 20 }).unwrap();
+        led.set_color(LedColor::Green).unwrap();
+
+        assert_eq!(watch(&pins, Duration::from_millis(100)), vec![[true, false, true]]);
+    }
 
-pub use led::LED;
\ No newline at end of file
+    #[test]
+    fn dropping_the_led_stops_its_pattern() {
+        let (mut led, pins) = led(false);
+        led.set_pattern(LedPattern::Blink { color: LedColor::White, period_ms: 20 }).unwrap();
+        drop(led);
+
+        assert_eq!(watch(&pins, Duration::from_millis(100)).len(), 1);
+    }
+
+    #[test]
+    fn unavailable_gpio_leaves_a_working_led() {
+        let led = LED::or_disabled(Err(anyhow::anyhow!("GPIO pins are in use")), false);
+        let shared = SharedLed::new(led, 2);
+
+        // Everything the acquisitions do with it still succeeds
+        shared.set_color(0, LedColor::Green).unwrap();
+        shared.set_pattern(1, LedPattern::Blink { color: LedColor::Magenta, period_ms: 1000 }).unwrap();
+        shared.set_color(1, LedColor::Red).unwrap();
+    }
+
+    #[test]
+    fn shared_led_shows_red_while_any_source_is_red() {
+        let (led, pins) = led(false);
+        let shared = SharedLed::new(led, 2);
+
+        shared.set_color(0, LedColor::Red).unwrap();
+        shared.set_color(1, LedColor::Green).unwrap();
+        assert_eq!(levels(&pins), [true, false, false]);
+
+        shared.set_color(0, LedColor::Green).unwrap();
+        assert_eq!(levels(&pins), [false, true, false]);
+    }
+
+    #[test]
+    fn shared_led_keeps_a_red_pattern_over_other_sources() {
+        let (led, _pins) = led(false);
+        let shared = SharedLed::new(led, 2);
+
+        let stalled = LedPattern::Blink { color: LedColor::Red, period_ms: 500 };
+        shared.set_pattern(0, stalled).unwrap();
+        shared.set_pattern(1, LedPattern::Pulse { color: LedColor::Green, period_ms: 2000 }).unwrap();
+        assert_eq!(shared.get_pattern(), stalled);
+
+        shared.set_color(0, LedColor::Green).unwrap();
+        assert_eq!(shared.get_pattern(), LedPattern::Solid(LedColor::Green));
+
+        // Sets the LED whatever the sources say
+        shared.set_all(LedPattern::Solid(LedColor::White)).unwrap();
+        assert_eq!(shared.get_pattern(), LedPattern::Solid(LedColor::White));
+    }
+}
\ No newline at end of file
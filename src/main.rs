@@ -1,21 +1,102 @@
-use std::{fs, thread, time::{Duration, Instant, SystemTime}};
+// The code base spells out returns and field names, and LED reads better as is
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::upper_case_acronyms)]
 
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, thread, time::{Duration, SystemTime}};
+
+use clap::{Parser, Subcommand};
 use colored::*;
-use log::Level;
-use serde::Deserialize;
-use serial::{Frame, SecTickModule};
-use services::local::{LocalService, LocalServiceConfig};
-use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
-use writer::Writer;
+use log::{Level, LevelFilter};
+use serde::{Deserialize, Serialize};
+use acquisition::{Acquisition, AcquisitionConfig, ReloadableSettings};
+use serial::{ChecksumAlgorithm, ChecksumMode, Framing, ParseOptions, SampleType};
+use services::{display::DisplayService, local::{LocalService, LocalServiceConfig}, mdns::MdnsAdvertisement, systemd::{Notifier, SdNotifier, SystemdWatchdog}, LagCountingReceiver};
+use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM}, iterator::Signals};
+use writer::{event::{EventConfig, EventTrigger}, Decimation, DecimationMode, FilenameTimezone, FlushPolicy, OutputFormat, OutputWriterConfig};
+#[cfg(feature = "hdf5")]
+use writer::AdcScale;
 
 mod serial;
 mod writer;
 mod services;
 mod led;
+mod metrics;
+mod acquisition;
+mod disk;
+mod selftest;
+#[cfg(feature = "hdf5")]
+mod inspect;
+#[cfg(feature = "hdf5")]
+mod export;
+#[cfg(test)]
+mod testutil;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    // Colored lines for a terminal
+    #[default]
+    Human,
+    // One JSON object per line, for log shippers
+    Json,
+}
+
+// The default level can change at runtime through set_log_level. Module
+// overrides are fixed once the logger is set up.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Debug as usize);
+
+// Comments and reloads only, so a handful is plenty
+const CONTROL_CHANNEL_CAPACITY: usize = 16;
+
+const LOCAL_SERVICE_PORT: u16 = 8767;
+
+fn set_log_level(level: LevelFilter) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+fn parse_level(level: &str, warnings: &mut Vec<String>) -> LevelFilter {
+    match level.trim().parse::<LevelFilter>() {
+        Ok(level) => level,
+        Err(_) => {
+            warnings.push(format!("Invalid log level \"{}\", using Info", level));
+            LevelFilter::Info
+        }
+    }
+}
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
+// RUST_LOG, when set, wins over the config. Like env_logger it takes a comma
+// separated list of "level" and "module=level" entries.
+fn setup_logger(format: LogFormat, level: &str, module_levels: &HashMap<String, String>) -> Result<(), fern::InitError> {
+    let mut warnings = Vec::new();
+    logger(format, level, module_levels, &mut warnings)
+        .chain(std::io::stdout())
+        .apply()?;
+
+    for warning in warnings {
+        log::warn!("{}", warning);
+    }
+    Ok(())
+}
+
+// The logger setup_logger installs, before it is given an output. Sets the
+// default level right away. Problems with the levels are added to
+// `warnings`, to log once a logger is installed.
+fn logger(format: LogFormat, level: &str, module_levels: &HashMap<String, String>, warnings: &mut Vec<String>) -> fern::Dispatch {
+    let mut level = parse_level(level, warnings);
+    let mut modules: Vec<(String, LevelFilter)> = module_levels.iter()
+        .map(|(module, level)| (module.clone(), parse_level(level, warnings)))
+        .collect();
+
+    if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        for entry in rust_log.split(',').filter(|entry| !entry.trim().is_empty()) {
+            match entry.split_once('=') {
+                Some((module, module_level)) => modules.push((module.trim().to_string(), parse_level(module_level, warnings))),
+                None => level = parse_level(entry, warnings),
+            }
+        }
+    }
+
+    let dispatch = match format {
+        LogFormat::Human => fern::Dispatch::new().format(|out, message, record| {
             let color = match record.level() {
                 Level::Error => "red",
                 Level::Warn => "yellow",
@@ -32,43 +113,832 @@ fn setup_logger() -> Result<(), fern::InitError> {
                 record.target(),
                 message
             ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .apply()?;
-    Ok(())
+        }),
+        LogFormat::Json => fern::Dispatch::new().format(|out, message, record| {
+            let line = serde_json::json!({
+                "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": message.to_string(),
+            });
+            out.finish(format_args!("{}", line))
+        }),
+    };
+
+    set_log_level(level);
+    let overridden: Vec<String> = modules.iter().map(|(module, _)| module.clone()).collect();
+    let mut dispatch = dispatch
+        .level(LevelFilter::Trace)
+        .filter(move |metadata| {
+            let target = metadata.target();
+            let is_overridden = overridden.iter()
+                .any(|module| target == module || target.starts_with(&format!("{}::", module)));
+            is_overridden || metadata.level() as usize <= LOG_LEVEL.load(Ordering::Relaxed)
+        });
+    for (module, level) in modules {
+        dispatch = dispatch.level_for(module, level);
+    }
+    dispatch
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "Captures GPS-timestamped frames from SecTick modules")]
+struct Args {
+    /// Config file to load, instead of looking in $HEARTBEAT_CONFIG, ./config.toml and /etc/heartbeat/config.toml
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+    /// Overrides node_id from the config
+    #[arg(long)]
+    node_id: Option<String>,
+    /// Overrides serial_port from the config, repeat for several ports
+    #[arg(long)]
+    serial_port: Vec<String>,
+    /// Overrides output_dir from the config
+    #[arg(long)]
+    output_dir: Option<String>,
+    /// Check the serial port, LED and output directory, then exit
+    #[arg(long)]
+    selftest: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// Offline tools that run instead of an acquisition
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Summarize an HDF5 capture file and report anomalies, exits 1 if it is corrupt
+    Inspect {
+        /// Capture file to check
+        path: std::path::PathBuf,
+    },
+    /// Convert an HDF5 capture file for tools that can't read HDF5
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// Keep every Nth sample, or the average of each N with --decimation-mode average
+        #[arg(long, default_value_t = 1)]
+        decimation: usize,
+        #[arg(long, value_enum, default_value = "pick")]
+        decimation_mode: DecimationMode,
+        /// Capture file to convert
+        input: std::path::PathBuf,
+        /// File to write
+        output: std::path::PathBuf,
+    },
+}
+
+// Kept outside the export module so the CLI parses the same without HDF5
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ExportFormat {
+    Csv,
 }
 
-#[derive(Deserialize)]
+// A single serial port, or a list of ports each run as its own node
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SerialPortConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl SerialPortConfig {
+    fn ports(&self) -> Vec<String> {
+        match self {
+            SerialPortConfig::One(port) => vec![port.clone()],
+            SerialPortConfig::Many(ports) => ports.clone(),
+        }
+    }
+}
+
+// HDF5-only settings go unused in builds without the hdf5 feature
+#[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+#[derive(Clone, Serialize, Deserialize)]
 struct HeartbeatConfig {
-    serial_port: String,
+    serial_port: SerialPortConfig,
     node_id: String,
     file_duration_mins: i64,
+    // Start a new file once the current one reaches this size, even if
+    // file_duration_mins hasn't passed yet
+    max_file_mb: Option<u64>,
+    // On startup, continue the last file if the previous run stopped without
+    // closing it and it is younger than file_duration_mins. A file that
+    // can't be continued is left as it is and a new one started.
+    #[serde(default)]
+    resume_interrupted_files: bool,
     gzip_level: i8,
+    #[serde(default = "default_samples_chunk_rows")]
+    samples_chunk_rows: usize,
+    #[serde(default = "default_comments_gzip_level")]
+    comments_gzip_level: u8,
+    // Flush output files every N frames, and at least every M seconds when
+    // set. Up to that much data is lost on a crash or power cut, in exchange
+    // for less SD card wear.
+    #[serde(default = "default_flush_every_n_frames")]
+    flush_every_n_frames: usize,
+    flush_interval_secs: Option<u64>,
     output_dir: String,
+    // "human" or "json"
+    #[serde(default)]
+    log_format: LogFormat,
+    // "error", "warn", "info", "debug" or "trace", with optional per-module
+    // overrides such as "heartbeat_acquisition::writer" = "info"
+    #[serde(default = "default_log_level")]
+    log_level: String,
+    #[serde(default)]
+    log_levels: HashMap<String, String>,
+    // "hdf5" or "csv", defaults to "hdf5" when absent. Builds without the
+    // hdf5 feature always write CSV.
+    #[serde(default)]
+    output_format: OutputFormat,
+    #[serde(default = "default_serial_reconnect_attempts")]
+    serial_reconnect_attempts: u32,
+    // How long opening the serial port may take
+    #[serde(default = "default_serial_open_timeout_ms")]
+    serial_open_timeout_ms: u64,
+    #[serde(default = "default_serial_read_timeout_ms")]
+    serial_read_timeout_ms: u64,
+    // "text" for CSV lines, "binary" for length-prefixed binary frames
+    #[serde(default)]
+    serial_framing: Framing,
+    // Drop the first, probably partial, line after opening the port
+    #[serde(default = "default_resync_on_open")]
+    resync_on_open: bool,
+    // Full lines to drop on top of that
+    #[serde(default)]
+    skip_lines_on_open: usize,
+    #[serde(default = "default_stall_timeout_secs")]
+    stall_timeout_secs: u64,
+    // A frame arriving this much later than the previous frame's GPS time
+    // plus its duration is logged as a gap, with the frames in between
+    // presumed dropped by the device
+    #[serde(default = "default_gap_tolerance_secs")]
+    gap_tolerance_secs: f64,
+    // Frames in a row that must agree before the LED shows the GPS fix
+    // was acquired or lost
+    #[serde(default = "default_led_fix_debounce_frames")]
+    led_fix_debounce_frames: usize,
+    #[serde(default = "default_samples_per_frame")]
+    samples_per_frame: usize,
+    #[serde(default)]
+    pad_short_frames: bool,
+    // Write only every Nth sample ("pick") or the mean of each N ("average")
+    #[serde(default = "default_decimation_factor")]
+    decimation_factor: usize,
+    #[serde(default)]
+    decimation_mode: DecimationMode,
+    #[serde(default = "default_health_stale_secs")]
+    health_stale_secs: u64,
+    // PEM files; the local service serves HTTPS when both are set
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    // Bearer token required by every local service route except /health
+    api_token: Option<String>,
+    // Advertise the local service over mDNS as _heartbeat._tcp
+    #[serde(default)]
+    advertise_mdns: bool,
+    // Frames kept in memory for /frames/recent
+    #[serde(default = "default_recent_frames_capacity")]
+    recent_frames_capacity: usize,
+    // "strict", "warn_and_keep" or "repair"
+    #[serde(default)]
+    checksum_mode: ChecksumMode,
+    // "sum" or "crc16", whichever the firmware sends
+    #[serde(default)]
+    checksum_algorithm: ChecksumAlgorithm,
+    // How long to wait for acquisitions to finish their files on shutdown
+    // before exiting anyway
+    #[serde(default = "default_shutdown_timeout_secs")]
+    shutdown_timeout_secs: u64,
+    // Shut down as on SIGTERM after running this long, for timed
+    // experiments. Unset or 0 runs until stopped.
+    max_runtime_mins: Option<u64>,
+    #[serde(default = "default_writer_queue_capacity")]
+    writer_queue_capacity: usize,
+    // Frames buffered for each web service subscriber. One that falls
+    // further behind misses frames, which are still written to disk.
+    #[serde(default = "default_broadcast_capacity")]
+    broadcast_capacity: usize,
+    // Set for common-anode RGB LEDs, which light up when a pin is low
+    #[serde(default)]
+    led_active_low: bool,
+    // Also show the status on an SSD1306 OLED, in builds with the oled
+    // feature
+    #[serde(default)]
+    oled_display: bool,
+    #[serde(default = "default_oled_i2c_bus")]
+    oled_i2c_bus: u8,
+    // 0x3C on most modules, 0x3D on some
+    #[serde(default = "default_oled_i2c_address")]
+    oled_i2c_address: u8,
+    // Writing pauses while the output filesystem has less than this free
+    #[serde(default = "default_min_free_mb")]
+    min_free_mb: u64,
+    // ADC resolution and reference voltage, recorded in HDF5 files so raw
+    // counts can be converted to volts
+    #[serde(default = "default_adc_bits")]
+    adc_bits: u8,
+    #[serde(default = "default_adc_vref")]
+    adc_vref: f32,
+    // "i16" or "i32", the type of the samples from the device and of the
+    // HDF5 samples dataset. Frames with samples outside it are rejected.
+    #[serde(default)]
+    sample_type: SampleType,
+    // Raw ADC range, samples at either end count as clipped
+    #[serde(default = "default_adc_min")]
+    adc_min: i32,
+    #[serde(default = "default_adc_max")]
+    adc_max: i32,
+    #[serde(default = "default_clip_threshold")]
+    clip_threshold: f32,
+    // Any of "clipping", "gps_fix" and "frame_gap". Each time one happens the
+    // frames around it are also saved to a file of their own under
+    // output_dir/events. Empty turns event files off.
+    #[serde(default)]
+    event_triggers: Vec<EventTrigger>,
+    // Frames saved from before and after each event
+    #[serde(default = "default_event_frames")]
+    event_pre_frames: usize,
+    #[serde(default = "default_event_frames")]
+    event_post_frames: usize,
+    // Time zone of the timestamp in file names, e.g. "+02:00" or
+    // "Europe/Amsterdam". Recorded times stay UTC whatever this is.
+    #[serde(default)]
+    filename_timezone: FilenameTimezone,
+}
+
+fn default_samples_chunk_rows() -> usize {
+    64
+}
+
+fn default_comments_gzip_level() -> u8 {
+    8
+}
+
+fn default_flush_every_n_frames() -> usize {
+    1
+}
+
+fn default_log_level() -> String {
+    "debug".to_string()
+}
+
+fn default_serial_reconnect_attempts() -> u32 {
+    10
+}
+
+fn default_serial_open_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_serial_read_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_resync_on_open() -> bool {
+    true
+}
+
+fn default_stall_timeout_secs() -> u64 {
+    10
+}
+
+fn default_gap_tolerance_secs() -> f64 {
+    0.5
+}
+
+fn default_led_fix_debounce_frames() -> usize {
+    3
+}
+
+fn default_samples_per_frame() -> usize {
+    7200
+}
+
+fn default_decimation_factor() -> usize {
+    1
+}
+
+fn default_health_stale_secs() -> u64 {
+    5
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_recent_frames_capacity() -> usize {
+    60
+}
+
+fn default_oled_i2c_bus() -> u8 {
+    1
+}
+
+fn default_oled_i2c_address() -> u8 {
+    0x3C
+}
+
+fn default_writer_queue_capacity() -> usize {
+    64
+}
+
+fn default_broadcast_capacity() -> usize {
+    16
+}
+
+fn default_min_free_mb() -> u64 {
+    100
+}
+
+fn default_adc_bits() -> u8 {
+    10
+}
+
+fn default_adc_vref() -> f32 {
+    3.3
+}
+
+fn default_adc_min() -> i32 {
+    0
+}
+
+fn default_adc_max() -> i32 {
+    1023
+}
+
+fn default_clip_threshold() -> f32 {
+    0.01
+}
+
+fn default_event_frames() -> usize {
+    10
+}
+
+
+impl HeartbeatConfig {
+    // Checks values serde can't, reporting every problem at once
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.node_id.is_empty() {
+            problems.push("node_id must not be empty".to_string());
+        } else if !self.node_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            problems.push(format!("node_id \"{}\" is used in file names, so it may only contain letters, digits, '-', '_' and '.'", self.node_id));
+        }
+
+        let ports = self.serial_port.ports();
+        if ports.is_empty() || ports.iter().any(|port| port.trim().is_empty()) {
+            problems.push("serial_port must name at least one port, and no port may be empty".to_string());
+        }
+
+        if self.file_duration_mins <= 0 {
+            problems.push(format!("file_duration_mins must be greater than 0, not {}", self.file_duration_mins));
+        }
+
+        if self.max_file_mb == Some(0) {
+            problems.push("max_file_mb must be greater than 0 when set".to_string());
+        }
+
+        if !(0..=9).contains(&self.gzip_level) {
+            problems.push(format!("gzip_level must be between 0 and 9, not {}", self.gzip_level));
+        }
+
+        if self.comments_gzip_level > 9 {
+            problems.push(format!("comments_gzip_level must be between 0 and 9, not {}", self.comments_gzip_level));
+        }
+
+        if self.samples_per_frame == 0 {
+            problems.push("samples_per_frame must be greater than 0".to_string());
+        }
+
+        if self.samples_chunk_rows == 0 {
+            problems.push("samples_chunk_rows must be greater than 0".to_string());
+        }
+
+        if self.flush_every_n_frames == 0 {
+            problems.push("flush_every_n_frames must be at least 1".to_string());
+        }
+
+        if self.flush_interval_secs == Some(0) {
+            problems.push("flush_interval_secs must be greater than 0 when set".to_string());
+        }
+
+        if self.decimation_factor == 0 {
+            problems.push("decimation_factor must be at least 1".to_string());
+        }
+
+        if self.writer_queue_capacity == 0 {
+            problems.push("writer_queue_capacity must be greater than 0".to_string());
+        }
+
+        if self.gap_tolerance_secs.is_nan() || self.gap_tolerance_secs < 0.0 {
+            problems.push(format!("gap_tolerance_secs must not be negative, not {}", self.gap_tolerance_secs));
+        }
+
+        if self.led_fix_debounce_frames == 0 {
+            problems.push("led_fix_debounce_frames must be greater than 0".to_string());
+        }
+
+        if self.broadcast_capacity == 0 {
+            problems.push("broadcast_capacity must be greater than 0".to_string());
+        }
+
+        if !(1..=self.sample_type.bits()).contains(&self.adc_bits) {
+            problems.push(format!("adc_bits must be between 1 and {} for {:?} samples, not {}", self.sample_type.bits(), self.sample_type, self.adc_bits));
+        }
+
+        if self.api_token.as_deref().is_some_and(|token| token.trim().is_empty()) {
+            problems.push("api_token must not be empty when set".to_string());
+        }
+
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            problems.push("tls_cert_path and tls_key_path must be set together".to_string());
+        }
+
+        if self.adc_min >= self.adc_max {
+            problems.push(format!("adc_min ({}) must be below adc_max ({})", self.adc_min, self.adc_max));
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow::anyhow!("Invalid config file:\n  - {}", problems.join("\n  - ")))
+    }
+
+    // The config as served by /config, with secrets redacted
+    fn sanitized(&self) -> anyhow::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in SECRET_FIELDS {
+                if let Some(secret) = fields.get_mut(*field).filter(|secret| !secret.is_null()) {
+                    *secret = serde_json::Value::String("***".to_string());
+                }
+            }
+        }
+        Ok(value)
+    }
 }
 
+// Fields whose values /config hides, showing "***" instead when set
+const SECRET_FIELDS: &[&str] = &["api_token"];
 
-fn load_config() -> HeartbeatConfig {
-    let config_contents = match fs::read_to_string("config.toml") {
+// Fields that hold strings, so that e.g. HEARTBEAT_NODE_ID=123 stays a
+// string instead of being read as a number
+const STRING_FIELDS: &[&str] = &["serial_port", "node_id", "output_dir", "output_format", "log_format", "log_level", "checksum_mode", "checksum_algorithm", "decimation_mode", "serial_framing", "sample_type", "tls_cert_path", "tls_key_path", "api_token", "filename_timezone"];
+
+// Every HEARTBEAT_<FIELD> environment variable overrides <field> from
+// config.toml, e.g. HEARTBEAT_OUTPUT_DIR=/data or
+// HEARTBEAT_FILE_DURATION_MINS=30. Values are read as TOML, so lists work
+// too: HEARTBEAT_SERIAL_PORT='["/dev/ttyACM0", "/dev/ttyACM1"]'
+fn apply_env_overrides(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (key, raw) in vars {
+        let field = match key.strip_prefix("HEARTBEAT_") {
+            Some(_) if key == CONFIG_ENV_VAR => continue,
+            Some(field) => field.to_lowercase(),
+            None => continue,
+        };
+
+        let parsed = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"));
+
+        let value = match parsed {
+            Some(toml::Value::Array(list)) => toml::Value::Array(list),
+            Some(value) if !STRING_FIELDS.contains(&field.as_str()) => value,
+            _ => toml::Value::String(raw),
+        };
+
+        table.insert(field, value);
+    }
+}
+
+// Command line overrides win over both the file and the environment
+fn apply_arg_overrides(table: &mut toml::Table, args: &Args) {
+    if let Some(node_id) = &args.node_id {
+        table.insert("node_id".to_string(), toml::Value::String(node_id.clone()));
+    }
+
+    match args.serial_port.as_slice() {
+        [] => {},
+        [port] => {
+            table.insert("serial_port".to_string(), toml::Value::String(port.clone()));
+        },
+        ports => {
+            table.insert("serial_port".to_string(), toml::Value::Array(ports.iter().map(|port| toml::Value::String(port.clone())).collect()));
+        },
+    }
+
+    if let Some(output_dir) = &args.output_dir {
+        table.insert("output_dir".to_string(), toml::Value::String(output_dir.clone()));
+    }
+}
+
+// Names the config file itself rather than overriding a field
+const CONFIG_ENV_VAR: &str = "HEARTBEAT_CONFIG";
+// Where packaged installs keep the config
+const SYSTEM_CONFIG_PATH: &str = "/etc/heartbeat/config.toml";
+
+// Where to look for the config file. An explicit --config, else
+// $HEARTBEAT_CONFIG, is the only candidate, so a wrong path fails instead of
+// quietly loading another file. Otherwise the working directory, then the
+// system-wide one.
+fn config_candidates(args: &Args, env_path: Option<PathBuf>) -> Vec<PathBuf> {
+    match args.config.clone().or(env_path) {
+        Some(path) => vec![path],
+        None => vec![PathBuf::from("config.toml"), PathBuf::from(SYSTEM_CONFIG_PATH)],
+    }
+}
+
+// The first candidate that exists
+fn find_config(candidates: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => {
+            let tried: Vec<String> = candidates.iter().map(|path| path.display().to_string()).collect();
+            Err(anyhow::anyhow!("No config file found, looked for {}", tried.join(", ")))
+        }
+    }
+}
+
+fn load_config(path: &Path, args: &Args) -> anyhow::Result<HeartbeatConfig> {
+    let config_contents = match fs::read_to_string(path) {
         Ok(contents) => contents,
-        Err(e) => panic!("Unable to open the config file: {:?}", e),
+        Err(e) => return Err(anyhow::anyhow!("Unable to open the config file {}: {}", path.display(), e)),
+    };
+
+    let mut table: toml::Table = match toml::from_str(&config_contents) {
+        Ok(data) => data,
+        Err(e) => return Err(anyhow::anyhow!("Unable to parse the config file: {}", e)),
     };
 
-    let config: HeartbeatConfig = match toml::from_str(&config_contents) {
+    apply_env_overrides(&mut table, std::env::vars());
+    apply_arg_overrides(&mut table, args);
+
+    let config: HeartbeatConfig = match toml::Value::Table(table).try_into() {
         Ok(data) => data,
-        Err(e) => panic!("Unable to parse the config file: {:?}", e),
-    };  
+        Err(e) => return Err(anyhow::anyhow!("Unable to parse the config file: {}", e)),
+    };
+
+    config.validate()?;
+
+    return Ok(config);
+}
+
+// Fields reload_config applies to the running acquisitions
+const RELOADABLE_FIELDS: &[&str] = &["log_level", "file_duration_mins", "gzip_level"];
 
-    return config;
+// The fields, other than the reloadable ones, that differ between the two
+// configs, sorted by name
+fn restart_only_changes(current: &HeartbeatConfig, new: &HeartbeatConfig) -> anyhow::Result<Vec<String>> {
+    let current = serde_json::to_value(current)?;
+    let new = serde_json::to_value(new)?;
+    let (Some(current), Some(new)) = (current.as_object(), new.as_object()) else {
+        return Err(anyhow::anyhow!("The config doesn't serialize to an object"));
+    };
+
+    Ok(new.iter()
+        .filter(|(field, value)| !RELOADABLE_FIELDS.contains(&field.as_str()) && current.get(*field) != Some(*value))
+        .map(|(field, _)| field.clone())
+        .collect())
+}
+
+// Re-reads the config file on SIGHUP. Only the log level, file duration and
+// gzip level are applied, the latter two from the next file on. Anything
+// else that changed is reported as needing a restart.
+fn reload_config(path: &Path, args: &Args, current: &mut HeartbeatConfig, control_tx: &tokio::sync::broadcast::Sender<services::ControlMessage>) {
+    let new = match load_config(path, args) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Not reloading the config: {}", e);
+            return;
+        }
+    };
+
+    if new.log_level != current.log_level {
+        if std::env::var("RUST_LOG").is_ok() {
+            log::warn!("Ignoring log_level change, RUST_LOG is set");
+        } else {
+            match new.log_level.trim().parse::<LevelFilter>() {
+                Ok(level) => {
+                    log::info!("log_level changed from {} to {}", current.log_level, new.log_level);
+                    set_log_level(level);
+                    current.log_level = new.log_level.clone();
+                },
+                Err(_) => log::error!("Invalid log_level \"{}\", keeping {}", new.log_level, current.log_level),
+            }
+        }
+    }
+
+    if new.file_duration_mins != current.file_duration_mins || new.gzip_level != current.gzip_level {
+        log::info!("file_duration_mins is now {} (was {}), gzip_level is now {} (was {}), both apply from the next file",
+            new.file_duration_mins, current.file_duration_mins, new.gzip_level, current.gzip_level);
+        current.file_duration_mins = new.file_duration_mins;
+        current.gzip_level = new.gzip_level;
+
+        let settings = ReloadableSettings {
+            file_duration: Duration::from_secs(new.file_duration_mins as u64 * 60),
+            gzip_level: new.gzip_level,
+        };
+        if let Err(e) = control_tx.send(services::ControlMessage::Reload(settings)) {
+            log::error!("Unable to send reloaded settings: {:?}", e);
+        }
+    }
+
+    match restart_only_changes(current, &new) {
+        Ok(changed) => {
+            for field in changed {
+                log::warn!("{} changed, restart to apply it", field);
+            }
+        },
+        Err(e) => log::error!("Unable to compare the reloaded config: {:?}", e),
+    }
+}
+
+// Tells the acquisitions to finish their files and the services to stop
+fn begin_shutdown(shutdown_tx: &tokio::sync::broadcast::Sender<()>, tx: &tokio::sync::broadcast::Sender<services::ServiceMessage>) {
+    // Either only fails when nothing is listening, so nothing is left to stop
+    let _ = shutdown_tx.send(());
+    let _ = tx.send(services::ServiceMessage::Shutdown);
+}
+
+// Shuts down the same way SIGTERM does once `max_runtime` has passed
+fn stop_after(max_runtime: Duration,
+    shutdown_tx: tokio::sync::broadcast::Sender<()>,
+    tx: tokio::sync::broadcast::Sender<services::ServiceMessage>) -> tokio::task::JoinHandle<()> {
+
+    tokio::spawn(async move {
+        tokio::time::sleep(max_runtime).await;
+        log::info!("Ran for max_runtime_mins ({:?}), shutting down...", max_runtime);
+        begin_shutdown(&shutdown_tx, &tx);
+    })
+}
+
+// Waits for shutdown to begin, then `timeout` more, and returns the nodes in
+// `running` whose acquisitions still haven't finished. None if shutdown
+// never begins.
+async fn shutdown_deadline(timeout: Duration,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+    running: std::sync::Arc<std::sync::Mutex<Vec<String>>>) -> Option<Vec<String>> {
+
+    shutdown_rx.recv().await.ok()?;
+    tokio::time::sleep(timeout).await;
+    Some(running.lock().map(|running| running.clone()).unwrap_or_default())
+}
+
+// An acquisition for each configured port, made by `open`. A port that
+// can't be opened is logged and left out so the others still record.
+fn open_acquisitions(config: &HeartbeatConfig,
+    parse_options: ParseOptions,
+    mut open: impl FnMut(AcquisitionConfig, usize) -> anyhow::Result<Acquisition>) -> Vec<Acquisition> {
+
+    let ports = config.serial_port.ports();
+    let mut acquisitions = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        // Every port gets its own node_id so the output files don't collide
+        let node_id = if ports.len() > 1 {
+            let port_name = std::path::Path::new(port).file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or(i.to_string());
+            format!("{}_{}", config.node_id, port_name)
+        } else {
+            config.node_id.clone()
+        };
+
+        let writer_config = writer_config(config, node_id.clone());
+
+        let acquisition = open(AcquisitionConfig {
+            node_id,
+            serial_port: port.clone(),
+            reconnect_attempts: config.serial_reconnect_attempts,
+            open_timeout: Duration::from_millis(config.serial_open_timeout_ms),
+            read_timeout: Duration::from_millis(config.serial_read_timeout_ms),
+            framing: config.serial_framing,
+            resync_on_open: config.resync_on_open,
+            skip_lines_on_open: config.skip_lines_on_open,
+            stall_timeout: Duration::from_secs(config.stall_timeout_secs),
+            file_duration: Duration::from_secs(config.file_duration_mins as u64 * 60),
+            resume_interrupted: config.resume_interrupted_files,
+            max_file_bytes: config.max_file_mb.map(|mb| mb * 1_000_000),
+            parse_options,
+            adc_min: config.adc_min,
+            adc_max: config.adc_max,
+            clip_threshold: config.clip_threshold,
+            writer_config,
+            min_free_bytes: config.min_free_mb * 1_000_000,
+            writer_queue_capacity: config.writer_queue_capacity,
+            gap_tolerance_secs: config.gap_tolerance_secs,
+            fix_debounce_frames: config.led_fix_debounce_frames,
+            events: (!config.event_triggers.is_empty()).then(|| EventConfig {
+                triggers: config.event_triggers.clone(),
+                pre_frames: config.event_pre_frames,
+                post_frames: config.event_post_frames,
+            }),
+        }, i);
+        match acquisition {
+            Ok(acquisition) => acquisitions.push(acquisition),
+            Err(e) => log::error!("Skipping serial port {}, unable to start acquiring from it: {:?}", port, e),
+        }
+    }
+    acquisitions
+}
+
+fn writer_config(config: &HeartbeatConfig, node_id: String) -> OutputWriterConfig {
+    let decimation = Decimation {
+        factor: config.decimation_factor,
+        mode: config.decimation_mode,
+    };
+    let flush = FlushPolicy {
+        every_n_frames: config.flush_every_n_frames,
+        interval: config.flush_interval_secs.map(Duration::from_secs),
+    };
+
+    match config.output_format {
+        #[cfg(feature = "hdf5")]
+        OutputFormat::Hdf5 => OutputWriterConfig::Hdf5(writer::hdf5::HDF5WriterConfig {
+            node_id,
+            output_path: config.output_dir.clone().into(),
+            gzip_level: config.gzip_level,
+            samples_chunk_rows: config.samples_chunk_rows,
+            comments_gzip_level: config.comments_gzip_level,
+            samples_per_frame: config.samples_per_frame,
+            pad_short_frames: config.pad_short_frames,
+            decimation,
+            adc_scale: AdcScale {
+                bits: config.adc_bits,
+                vref: config.adc_vref,
+            },
+            sample_type: config.sample_type,
+            flush,
+            filename_timezone: config.filename_timezone,
+        }),
+        // Also the fallback for builds without the hdf5 feature
+        _ => OutputWriterConfig::Csv(writer::csv::CsvWriterConfig {
+            node_id,
+            output_path: config.output_dir.clone().into(),
+            samples_per_frame: config.samples_per_frame,
+            decimation,
+            flush,
+            filename_timezone: config.filename_timezone,
+        }),
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    setup_logger()?;
+    let args = Args::parse();
+
+    // Subcommands work on existing files and don't need a config
+    match &args.command {
+        #[cfg(feature = "hdf5")]
+        Some(Command::Inspect { path }) => std::process::exit(if inspect::run(path) { 0 } else { 1 }),
+        #[cfg(feature = "hdf5")]
+        Some(Command::Export { format: ExportFormat::Csv, decimation, decimation_mode, input, output }) => {
+            let decimation = Decimation { factor: *decimation, mode: *decimation_mode };
+            match export::to_csv(input, output, decimation) {
+                Ok(frames) => println!("Wrote {} frames to {}", frames, output.display()),
+                Err(e) => {
+                    eprintln!("Unable to export {}: {:#}", input.display(), e);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        },
+        #[cfg(not(feature = "hdf5"))]
+        Some(Command::Inspect { .. } | Command::Export { .. }) => {
+            eprintln!("Built without HDF5 support, unable to read HDF5 files");
+            std::process::exit(1);
+        },
+        None => {}
+    }
+
+    // The log format comes from the config, so the logger can only be set
+    // up once it has loaded
+    let candidates = config_candidates(&args, std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from));
+    let loaded = find_config(&candidates)
+        .and_then(|path| load_config(&path, &args).map(|config| (path, config)));
+    let (config_path, config) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            setup_logger(LogFormat::default(), &default_log_level(), &HashMap::new())?;
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    setup_logger(config.log_format, &config.log_level, &config.log_levels)?;
+    log::info!("Loaded config from {}", config_path.display());
 
-    let config = load_config();
-    let mut led = led::LED::new(19, 20, 21)?;
+    if config.output_format == OutputFormat::Hdf5 && !cfg!(feature = "hdf5") {
+        log::warn!("Built without HDF5 support, writing CSV instead");
+    }
+
+    if args.selftest {
+        let passed = selftest::run(&config).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    let mut led = led::LED::new_or_disabled(19, 20, 21, config.led_active_low);
     led.set_color(led::LedColor::White)?;
 
     // Check for writability to the output directory
@@ -85,52 +955,74 @@ async fn main() -> anyhow::Result<()> {
         std::process::exit(1);
     }
 
-    // Test by writing a file
-    let test_file = output_dir.join("test_file");
-    match fs::write(&test_file, "test") {
-        Ok(_) => {
-            fs::remove_file(&test_file)?;
-        },
-        Err(e) => {
-            log::error!("Unable to write to output directory: {}", e);
-            led.set_color(led::LedColor::Red)?;
-            std::process::exit(1);
-        }
+    if let Err(e) = disk::check_writable(output_dir) {
+        log::error!("Unable to write to output directory: {}", e);
+        led.set_color(led::LedColor::Red)?;
+        std::process::exit(1);
     }
 
     log::info!("Starting Heartbeat node with node_id=\"{}\"", config.node_id);
-    log::debug!("Serial port: {}", config.serial_port);
-
-    let mut serial = SecTickModule::new(config.serial_port, 1_000_000, Duration::from_secs(5));
+    log::debug!("Serial port(s): {:?}", config.serial_port.ports());
 
-    serial.open().unwrap();
+    let (tx, _) = tokio::sync::broadcast::channel(config.broadcast_capacity);
+    let (control_tx, _) = tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY);
 
-    let (tx, _) = tokio::sync::broadcast::channel(16);
+    let metrics = std::sync::Arc::new(metrics::Metrics::new()?);
 
     let mut local = LocalService::new(LocalServiceConfig {
-        port: 8767,
+        port: LOCAL_SERVICE_PORT,
         node_id: config.node_id.clone(),
-    }, tx.clone());
+        health_stale_after: Duration::from_secs(config.health_stale_secs),
+        output_dir: config.output_dir.clone().into(),
+        recent_frames_capacity: config.recent_frames_capacity,
+        tls_cert_path: config.tls_cert_path.clone().map(Into::into),
+        tls_key_path: config.tls_key_path.clone().map(Into::into),
+        api_token: config.api_token.clone(),
+        effective_config: config.sanitized()?,
+    }, metrics.clone(), tx.clone(), control_tx.clone());
 
     let rx = tx.subscribe();
 
-    let writer_config = writer::hdf5::HDF5WriterConfig {
-        node_id: config.node_id.clone(),
-        output_path: config.output_dir.into(),
-        gzip_level: config.gzip_level,
+    let parse_options = ParseOptions {
+        checksum_mode: config.checksum_mode,
+        checksum_algorithm: config.checksum_algorithm,
+        sample_type: config.sample_type,
     };
-    let mut writer = writer::hdf5::HDF5Writer::new(writer_config.clone())?;
 
-    let (shutdown_tx, mut shutdown_rx) = tokio::sync::broadcast::channel::<()>(4);
+    log::info!("Writing output as {:?}", config.output_format);
+
+    if config.decimation_factor > 1 {
+        log::info!("Decimating frames by {} ({:?})", config.decimation_factor, config.decimation_mode);
+    }
+
+    let led = std::sync::Arc::new(led::SharedLed::new(led, config.serial_port.ports().len()));
+    let acquisitions = open_acquisitions(&config, parse_options, |acquisition_config, i| {
+        Acquisition::new(acquisition_config, led.clone(), i, metrics.clone(), tx.clone(), control_tx.subscribe())
+            // Keeps the LED red while a port is missing
+            .inspect_err(|_| { let _ = led.set_color(i, led::LedColor::Red); })
+    });
+    if acquisitions.is_empty() {
+        led.set_all(led::LedPattern::Solid(led::LedColor::Red))?;
+        return Err(anyhow::anyhow!("None of the serial ports could be opened"));
+    }
+
+    let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(4);
+    let shutdown_tx_inner = shutdown_tx.clone();
     let tx_arc = tx.clone();
+    let reload_control_tx = control_tx.clone();
+    let mut running_config = config.clone();
+    let reload_args = args.clone();
     thread::spawn(move || {
-        let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
         for sig in signals.forever() {
             match sig {
                 SIGINT | SIGTERM => {
                     log::info!("Shutting down, waiting for services...");
-                    shutdown_tx.send(()).unwrap();
-                    tx_arc.send(services::ServiceMessage::Shutdown).unwrap();
+                    begin_shutdown(&shutdown_tx_inner, &tx_arc);
+                },
+                SIGHUP => {
+                    log::info!("Reloading {}", config_path.display());
+                    reload_config(&config_path, &reload_args, &mut running_config, &reload_control_tx);
                 },
                 _ => {}
             }
@@ -139,66 +1031,438 @@ async fn main() -> anyhow::Result<()> {
 
     local.start().await?;
 
-    let mut last_start = Instant::now();
+    // Losing discovery isn't worth stopping the acquisition over
+    let mdns = if config.advertise_mdns {
+        match MdnsAdvertisement::start(&config.node_id, LOCAL_SERVICE_PORT) {
+            Ok(mdns) => Some(mdns),
+            Err(e) => {
+                log::error!("Unable to advertise over mDNS: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    loop {
-        tokio::select! {
-            _ = shutdown_rx.recv() => {
-                led.set_color(led::LedColor::Yellow)?;
-                break;
-            },
-            line = serial.read_line() => {
-                let when = chrono::Utc::now();
-                match line {
-                    Ok(line) => {
-                        if last_start.elapsed() > Duration::from_secs(config.file_duration_mins as u64 * 60) {
-                            writer = writer::hdf5::HDF5Writer::new(writer_config.clone())?;
-                            last_start = Instant::now();
-                        }
-
-                        if line.starts_with("#") {
-                            led.set_color(led::LedColor::Blue)?;
-                            writer.write_comment(&line).await?;
-                            continue;
-                        }
-                
-                        let frame = match Frame::parse(&line) {
-                            Ok(frame) => frame,
-                            Err(e) => {
-                                led.set_color(led::LedColor::Red)?;
-                                log::error!("Failed to parse frame: {:?}\n{}", e, &line[..line.len().min(60)]);
-                                continue;
-                            }
-                        };
-                
-
-                        if frame.metadata().has_gps_fix() {
-                            writer.write_frame(when, &frame).await?;
-                            led.set_color(led::LedColor::Green)?;
-                        } else {
-                            led.set_color(led::LedColor::Magenta)?;
-                        }
-                        tx.send(services::ServiceMessage::NewFrame(frame))?;
-                        
-                    },
-                    Err(e) => {
-                        log::error!("Error reading line: {:?}", e);
-                        led.set_color(led::LedColor::Red)?;
-                        continue;
-                    }
-                }
+    let node_ids: Vec<String> = acquisitions.iter().map(|acquisition| acquisition.node_id().to_string()).collect();
+
+    // Only does anything when running as a systemd service
+    if let Err(e) = SdNotifier.ready() {
+        log::warn!("Unable to notify systemd of startup: {:?}", e);
+    }
+    let systemd_watchdog = services::systemd::watchdog_timeout().map(|timeout| {
+        log::info!("Kicking the systemd watchdog while every node has frames within {:?}", timeout);
+        SystemdWatchdog::new(SdNotifier, &node_ids, timeout)
+            .start(LagCountingReceiver::new(tx.subscribe(), "systemd_watchdog", metrics.clone()))
+    });
+
+    // Like the LED, the display isn't worth stopping the acquisition over
+    let display = if config.oled_display {
+        match services::display::open(config.oled_i2c_bus, config.oled_i2c_address) {
+            Ok(display) => Some(DisplayService::new(display, &node_ids, config.output_dir.clone().into())
+                .start(LagCountingReceiver::new(tx.subscribe(), "status_display", metrics.clone()))),
+            Err(e) => {
+                log::warn!("Unable to open the status display, running without it: {:#}", e);
+                None
             }
-        }   
+        }
+    } else {
+        None
+    };
+
+    // Nodes whose acquisition hasn't finished yet, for the shutdown timeout
+    let running = std::sync::Arc::new(std::sync::Mutex::new(node_ids));
+
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let shutdown_deadline = shutdown_deadline(shutdown_timeout, shutdown_tx.subscribe(), running.clone());
+    let timeout_led = led.clone();
+    tokio::spawn(async move {
+        if let Some(still_running) = shutdown_deadline.await {
+            log::error!("Shutdown did not finish within {:?}, still waiting on: {}. Exiting anyway", shutdown_timeout, still_running.join(", "));
+            let _ = timeout_led.set_all(led::LedPattern::Solid(led::LedColor::Red));
+            std::process::exit(1);
+        }
+    });
+
+    let max_runtime = config.max_runtime_mins.filter(|mins| *mins > 0).map(|mins| {
+        let max_runtime = Duration::from_secs(mins * 60);
+        log::info!("Stopping after {:?}", max_runtime);
+        stop_after(max_runtime, shutdown_tx.clone(), tx.clone())
+    });
+
+    let handles: Vec<_> = acquisitions.into_iter()
+        .map(|acquisition| {
+            let running = running.clone();
+            let node_id = acquisition.node_id().to_string();
+            let shutdown_rx = shutdown_tx.subscribe();
+            tokio::spawn(async move {
+                let result = acquisition.run(shutdown_rx).await;
+                if let Ok(mut running) = running.lock() {
+                    running.retain(|id| id != &node_id);
+                }
+                result
+            })
+        })
+        .collect();
 
-        
+    for handle in handles {
+        match handle.await {
+            Ok(Ok(_)) => {},
+            Ok(Err(e)) => {
+                log::error!("Acquisition stopped with an error: {:?}", e);
+                led.set_all(led::LedPattern::Solid(led::LedColor::Red))?;
+            },
+            Err(e) => log::error!("Acquisition task failed: {:?}", e),
+        }
     }
 
+    led.set_all(led::LedPattern::Solid(led::LedColor::Yellow))?;
+
     local.stop();
+    if let Some(mdns) = mdns {
+        mdns.stop();
+    }
+    if let Some(max_runtime) = max_runtime {
+        max_runtime.abort();
+    }
+    if let Some(systemd_watchdog) = systemd_watchdog {
+        systemd_watchdog.abort();
+    }
+    if let Some(display) = display {
+        display.abort();
+    }
+
+    drop(rx);
 
     log::info!("All done!");
 
-    led.set_color(led::LedColor::Off)?;
+    led.set_all(led::LedPattern::Solid(led::LedColor::Off))?;
 
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::PathBuf, sync::Arc, time::Duration};
+
+    use clap::Parser;
+    use log::Level;
+
+    use super::{apply_arg_overrides, apply_env_overrides, config_candidates, find_config, load_config, logger, open_acquisitions, reload_config, restart_only_changes, shutdown_deadline, stop_after, writer_config, Args, Command, ExportFormat, HeartbeatConfig, LogFormat, SerialPortConfig};
+    use crate::{acquisition::Acquisition, led::{MockPin, SharedLed, LED}, metrics::Metrics, serial::{mock::MockSerialPort, ParseOptions, SecTickModule}, services::{ControlMessage, ServiceMessage}, testutil, writer::{manifest, DecimationMode, OutputWriterConfig}};
+
+    const MINIMAL: &str = r#"
+        serial_port = "/dev/ttyACM0"
+        node_id = "node"
+        file_duration_mins = 60
+        gzip_level = 4
+        output_dir = "/data"
+    "#;
+
+    fn args(args: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("heartbeat-acquisition").chain(args.iter().copied())).unwrap()
+    }
+
+    fn parse(table: toml::Table) -> HeartbeatConfig {
+        toml::Value::Table(table).try_into().unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    // Runs one record through the logger, returning the line written or None
+    // if it was filtered out. Every test sets the same default level, as it
+    // is shared.
+    fn log_line(format: LogFormat, level: &str, record_level: Level) -> Option<String> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (_, log) = logger(format, level, &HashMap::new(), &mut Vec::new())
+            .chain(fern::Output::call(move |record| {
+                let _ = tx.send(record.args().to_string());
+            }))
+            .into_log();
+        log.log(&log::Record::builder().level(record_level).target("heartbeat_acquisition::acquisition").args(format_args!("Opened node_1.h5")).build());
+        rx.try_recv().ok()
+    }
+
+    #[test]
+    fn json_log_lines_parse() {
+        let line = log_line(LogFormat::Json, "warn", Level::Warn).unwrap();
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "heartbeat_acquisition::acquisition");
+        assert_eq!(line["message"], "Opened node_1.h5");
+        assert!(humantime::parse_rfc3339(line["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn log_level_warn_filters_out_info() {
+        assert_eq!(log_line(LogFormat::Human, "warn", Level::Info), None);
+        assert!(log_line(LogFormat::Human, "warn", Level::Warn).unwrap().contains("Opened node_1.h5"));
+        assert!(log_line(LogFormat::Human, "warn", Level::Error).is_some());
+    }
+
+    #[tokio::test]
+    async fn stops_and_finishes_the_file_after_max_runtime() {
+        let dir = testutil::temp_dir("max-runtime");
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(4);
+        let (tx, _) = tokio::sync::broadcast::channel(4);
+        let mut service_rx = tx.subscribe();
+
+        let acquisition = Acquisition::without_port(&dir, tx.clone());
+        let run = tokio::spawn(acquisition.run(shutdown_tx.subscribe()));
+        stop_after(Duration::from_millis(50), shutdown_tx, tx);
+
+        tokio::time::timeout(Duration::from_secs(5), run).await
+            .expect("Acquisition didn't stop after max_runtime")
+            .unwrap()
+            .unwrap();
+        assert!(matches!(service_rx.recv().await, Ok(ServiceMessage::Shutdown)));
+
+        // Closed files are recorded in the manifest
+        let entries = manifest::entries(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(testutil::files_with_extension(&dir, "csv").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_each_port_to_its_own_file_and_skips_ports_that_fail() {
+        let dir = testutil::temp_dir("main-ports");
+        let mut table: toml::Table = toml::from_str(MINIMAL).unwrap();
+        table.insert("serial_port".to_string(), toml::Value::try_from(["/dev/mock0", "/dev/heartbeat-test-missing", "/dev/mock1"]).unwrap());
+        table.insert("output_dir".to_string(), toml::Value::String(dir.display().to_string()));
+        table.insert("output_format".to_string(), toml::Value::String("csv".to_string()));
+        table.insert("samples_per_frame".to_string(), toml::Value::Integer(3));
+        let config = parse(table);
+
+        let led = Arc::new(SharedLed::new(LED::with_pins(Box::new(MockPin::default()), Box::new(MockPin::default()), Box::new(MockPin::default()), false), 3));
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (tx, mut service_rx) = tokio::sync::broadcast::channel(16);
+        let (control_tx, _) = tokio::sync::broadcast::channel(4);
+        let acquisitions = open_acquisitions(&config, ParseOptions::default(), |acquisition_config, i| {
+            let port = match acquisition_config.serial_port.as_str() {
+                "/dev/mock0" => MockSerialPort::new().line(&testutil::frame_line(1, true, &[1, 2, 3])),
+                "/dev/mock1" => MockSerialPort::new().line(&testutil::frame_line(2, true, &[4, 5, 6])),
+                _ => return Acquisition::new(acquisition_config, led.clone(), i, metrics.clone(), tx.clone(), control_tx.subscribe()),
+            };
+            Acquisition::with_serial(acquisition_config, SecTickModule::with_port(Box::new(port)), led.clone(), i, metrics.clone(), tx.clone(), control_tx.subscribe())
+        });
+        assert_eq!(acquisitions.iter().map(|acquisition| acquisition.node_id()).collect::<Vec<_>>(), vec!["node_mock0", "node_mock1"]);
+
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(4);
+        let handles: Vec<_> = acquisitions.into_iter().map(|acquisition| tokio::spawn(acquisition.run(shutdown_tx.subscribe()))).collect();
+        let mut frames = 0;
+        while frames < 2 {
+            if let Ok(ServiceMessage::NewFrame { .. }) = tokio::time::timeout(Duration::from_secs(5), service_rx.recv()).await.expect("Timed out waiting for frames") {
+                frames += 1;
+            }
+        }
+        shutdown_tx.send(()).unwrap();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let files = testutil::files_with_extension(&dir, "csv");
+        assert_eq!(files.len(), 2);
+        for (file, node_id) in files.iter().zip(["node_mock0", "node_mock1"]) {
+            assert!(file.file_name().unwrap().to_string_lossy().contains(node_id), "{}", file.display());
+            assert_eq!(testutil::csv_rows(file).len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_deadline_reports_the_acquisitions_still_running() {
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(4);
+        let running = std::sync::Arc::new(std::sync::Mutex::new(vec!["node_a".to_string(), "node_b".to_string()]));
+        let deadline = tokio::spawn(shutdown_deadline(Duration::from_millis(50), shutdown_tx.subscribe(), running.clone()));
+
+        shutdown_tx.send(()).unwrap();
+        running.lock().unwrap().retain(|node_id| node_id != "node_a");
+        let still_running = tokio::time::timeout(Duration::from_secs(5), deadline).await.unwrap().unwrap();
+        assert_eq!(still_running, Some(vec!["node_b".to_string()]));
+
+        // Without a shutdown there is no deadline
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(4);
+        let deadline = shutdown_deadline(Duration::from_millis(50), shutdown_tx.subscribe(), running);
+        drop(shutdown_tx);
+        assert_eq!(deadline.await, None);
+    }
+
+    #[test]
+    fn minimal_config_gets_defaults() {
+        let config = parse(toml::from_str(MINIMAL).unwrap());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.serial_port.ports(), vec!["/dev/ttyACM0"]);
+        assert_eq!(config.samples_per_frame, 7200);
+        assert_eq!(config.api_token, None);
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut config = parse(toml::from_str(MINIMAL).unwrap());
+        config.node_id = "node/1".to_string();
+        config.serial_port = SerialPortConfig::Many(vec![]);
+        config.gzip_level = 10;
+        config.adc_min = 1023;
+        config.tls_cert_path = Some("cert.pem".to_string());
+
+        let message = config.validate().unwrap_err().to_string();
+        for field in ["node_id", "serial_port", "gzip_level", "adc_min", "tls_cert_path"] {
+            assert!(message.contains(field), "{} not reported in {}", field, message);
+        }
+    }
+
+    #[test]
+    fn sanitized_config_redacts_secrets() {
+        let mut config = parse(toml::from_str(MINIMAL).unwrap());
+        let value = config.sanitized().unwrap();
+        assert_eq!(value["api_token"], serde_json::Value::Null);
+
+        config.api_token = Some("secret".to_string());
+        let value = config.sanitized().unwrap();
+        assert_eq!(value["api_token"], "***");
+        assert_eq!(value["node_id"], "node");
+        assert_eq!(value["serial_port"], "/dev/ttyACM0");
+        assert_eq!(value["samples_per_frame"], 7200);
+        assert_eq!(value["output_format"], "hdf5");
+        assert!(!value.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn reload_applies_reloadable_fields_and_reports_the_rest() {
+        let dir = testutil::temp_dir("reload-config");
+        let path = dir.join("config.toml");
+        let mut current = parse(toml::from_str(MINIMAL).unwrap());
+        let (control_tx, mut control_rx) = tokio::sync::broadcast::channel(4);
+
+        fs::write(&path, MINIMAL.replace("file_duration_mins = 60", "file_duration_mins = 30").replace("gzip_level = 4", "gzip_level = 4\nstall_timeout_secs = 5")).unwrap();
+        let new = load_config(&path, &args(&[])).unwrap();
+        assert_eq!(restart_only_changes(&current, &new).unwrap(), vec!["stall_timeout_secs"]);
+
+        reload_config(&path, &args(&[]), &mut current, &control_tx);
+        assert_eq!(current.file_duration_mins, 30);
+        assert_ne!(current.stall_timeout_secs, new.stall_timeout_secs);
+        match control_rx.try_recv().unwrap() {
+            ControlMessage::Reload(settings) => assert_eq!(settings.file_duration, Duration::from_secs(30 * 60)),
+            _ => panic!("Expected reloaded settings"),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_are_read_as_toml() {
+        let mut table: toml::Table = toml::from_str(MINIMAL).unwrap();
+        apply_env_overrides(&mut table, vars(&[
+            ("HEARTBEAT_FILE_DURATION_MINS", "30"),
+            // A string field stays a string even when it looks like a number
+            ("HEARTBEAT_NODE_ID", "123"),
+            ("HEARTBEAT_SERIAL_PORT", r#"["/dev/ttyACM0", "/dev/ttyACM1"]"#),
+            ("HEARTBEAT_OUTPUT_DIR", "/mnt/data"),
+            ("HOME", "/root"),
+        ]));
+
+        assert!(!table.contains_key("home"));
+        let config = parse(table);
+        assert_eq!(config.file_duration_mins, 30);
+        assert_eq!(config.node_id, "123");
+        assert_eq!(config.serial_port.ports(), vec!["/dev/ttyACM0", "/dev/ttyACM1"]);
+        assert_eq!(config.output_dir, "/mnt/data");
+    }
+
+    #[test]
+    fn command_line_overrides_win() {
+        let mut table: toml::Table = toml::from_str(MINIMAL).unwrap();
+        apply_env_overrides(&mut table, vars(&[("HEARTBEAT_NODE_ID", "from-env")]));
+        apply_arg_overrides(&mut table, &args(&["--node-id", "from-args", "--serial-port", "/dev/a", "--serial-port", "/dev/b"]));
+
+        let config = parse(table);
+        assert_eq!(config.node_id, "from-args");
+        assert!(config.serial_port == SerialPortConfig::Many(vec!["/dev/a".to_string(), "/dev/b".to_string()]));
+
+        let mut table: toml::Table = toml::from_str(MINIMAL).unwrap();
+        apply_arg_overrides(&mut table, &args(&["--serial-port", "/dev/a"]));
+        assert!(parse(table).serial_port == SerialPortConfig::One("/dev/a".to_string()));
+    }
+
+    #[test]
+    fn parses_the_export_subcommand() {
+        let parsed = args(&["export", "--decimation", "4", "in.h5", "out.csv"]);
+        match parsed.command {
+            Some(Command::Export { format, decimation, decimation_mode, input, output }) => {
+                assert_eq!(format, ExportFormat::Csv);
+                assert_eq!(decimation, 4);
+                assert_eq!(decimation_mode, DecimationMode::Pick);
+                assert_eq!(input, std::path::PathBuf::from("in.h5"));
+                assert_eq!(output, std::path::PathBuf::from("out.csv"));
+            },
+            other => panic!("Expected export, got {:?}", other),
+        }
+        assert!(args(&[]).command.is_none());
+    }
+
+    #[test]
+    fn uses_the_first_config_file_that_exists() {
+        let dir = testutil::temp_dir("main-find-config");
+        let from_args = dir.join("args.toml");
+        let from_env = dir.join("env.toml");
+        let system = dir.join("system.toml");
+
+        // Stand-ins for ./config.toml and /etc/heartbeat/config.toml
+        let defaults = vec![dir.join("config.toml"), system.clone()];
+        let with_arg = args(&["--config", from_args.to_str().unwrap()]);
+
+        assert!(find_config(&defaults).unwrap_err().to_string().contains("system.toml"));
+        std::fs::write(&system, MINIMAL).unwrap();
+        assert_eq!(find_config(&defaults).unwrap(), system);
+
+        // A missing explicit path is an error, even with a default one to fall back on
+        assert!(find_config(&config_candidates(&args(&[]), Some(from_env.clone()))).unwrap_err().to_string().contains("env.toml"));
+        assert!(find_config(&config_candidates(&with_arg, Some(from_env.clone()))).unwrap_err().to_string().contains("args.toml"));
+
+        std::fs::write(&from_env, MINIMAL).unwrap();
+        assert_eq!(find_config(&config_candidates(&args(&[]), Some(from_env.clone()))).unwrap(), from_env);
+        assert!(find_config(&config_candidates(&with_arg, Some(from_env.clone()))).is_err());
+
+        std::fs::write(&from_args, MINIMAL).unwrap();
+        assert_eq!(find_config(&config_candidates(&with_arg, Some(from_env.clone()))).unwrap(), from_args);
+    }
+
+    #[test]
+    fn config_candidates_are_in_priority_order() {
+        assert_eq!(config_candidates(&args(&["--config", "a.toml"]), Some(PathBuf::from("b.toml"))), vec![PathBuf::from("a.toml")]);
+        assert_eq!(config_candidates(&args(&[]), Some(PathBuf::from("b.toml"))), vec![PathBuf::from("b.toml")]);
+        assert_eq!(config_candidates(&args(&[]), None), vec![PathBuf::from("config.toml"), PathBuf::from("/etc/heartbeat/config.toml")]);
+    }
+
+    #[test]
+    fn loads_validates_and_overrides_a_config_file() {
+        let dir = testutil::temp_dir("main-load-config");
+        let path = dir.join("heartbeat.toml");
+        std::fs::write(&path, MINIMAL).unwrap();
+
+        let config = load_config(&path, &args(&["--output-dir", "/tmp/out"])).unwrap();
+        assert_eq!(config.output_dir, "/tmp/out");
+
+        std::fs::write(&path, MINIMAL.replace("gzip_level = 4", "gzip_level = 12")).unwrap();
+        assert!(load_config(&path, &args(&[])).is_err());
+
+        std::fs::write(&path, "node_id = ").unwrap();
+        assert!(load_config(&path, &args(&[])).is_err());
+    }
+
+    #[test]
+    fn csv_output_gets_a_csv_writer() {
+        let mut config = parse(toml::from_str(MINIMAL).unwrap());
+        config.output_format = crate::writer::OutputFormat::Csv;
+        config.decimation_factor = 4;
+
+        match writer_config(&config, "node-1".to_string()) {
+            OutputWriterConfig::Csv(csv) => {
+                assert_eq!(csv.node_id, "node-1");
+                assert_eq!(csv.output_path, std::path::PathBuf::from("/data"));
+                assert_eq!(csv.decimation.factor, 4);
+            },
+            #[allow(unreachable_patterns)]
+            _ => panic!("Expected a CSV writer config"),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,131 @@
+use prometheus::{core::Collector, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+// From a frame written straight away up to a writer stuck behind slow
+// flushes for several seconds
+const FRAME_LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Metrics {
+    registry: Registry,
+    pub frames_written: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    pub serial_read_errors: IntCounterVec,
+    pub frames_dropped: IntCounterVec,
+    pub write_errors: IntCounterVec,
+    pub frames_skipped_disk_full: IntCounterVec,
+    pub clipping_frames: IntCounterVec,
+    pub non_monotonic_timestamps: IntCounterVec,
+    pub frame_gaps: IntCounterVec,
+    pub sample_rate: GaugeVec,
+    pub satellites: IntGaugeVec,
+    pub signal_rms: GaugeVec,
+    pub signal_peak_to_peak: IntGaugeVec,
+    pub seconds_since_last_frame: GaugeVec,
+    pub disk_free_bytes: IntGaugeVec,
+    pub bytes_written: IntCounterVec,
+    pub files_rotated: IntCounterVec,
+    pub current_file_bytes: IntGaugeVec,
+    pub broadcast_lag_events: IntCounterVec,
+    pub frame_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Metrics> {
+        let registry = Registry::new();
+
+        Ok(Metrics {
+            frames_written: register(&registry, IntCounterVec::new(Opts::new("heartbeat_frames_written_total", "Frames written to the output file"), &["node_id"])?)?,
+            parse_errors: register(&registry, IntCounterVec::new(Opts::new("heartbeat_parse_errors_total", "Serial lines that failed to parse as a frame"), &["node_id"])?)?,
+            serial_read_errors: register(&registry, IntCounterVec::new(Opts::new("heartbeat_serial_read_errors_total", "Failed serial reads by kind: timeout (device silent), io (port gone), lock or parse"), &["node_id", "kind"])?)?,
+            frames_dropped: register(&registry, IntCounterVec::new(Opts::new("heartbeat_frames_dropped_total", "Frames dropped because the writer queue was full"), &["node_id"])?)?,
+            write_errors: register(&registry, IntCounterVec::new(Opts::new("heartbeat_write_errors_total", "Writes to the output file that failed, not counting frames the writer rejected"), &["node_id"])?)?,
+            frames_skipped_disk_full: register(&registry, IntCounterVec::new(Opts::new("heartbeat_frames_skipped_disk_full_total", "Frames not written because free space was below min_free_mb"), &["node_id"])?)?,
+            clipping_frames: register(&registry, IntCounterVec::new(Opts::new("heartbeat_clipping_frames_total", "Frames with more samples at the ADC rails than clip_threshold allows"), &["node_id"])?)?,
+            non_monotonic_timestamps: register(&registry, IntCounterVec::new(Opts::new("heartbeat_non_monotonic_timestamps_total", "Frames whose GPS timestamp was not after the previous frame's"), &["node_id"])?)?,
+            frame_gaps: register(&registry, IntCounterVec::new(Opts::new("heartbeat_frame_gaps_total", "Jumps in the GPS timestamps suggesting the device dropped frames"), &["node_id"])?)?,
+            sample_rate: register(&registry, GaugeVec::new(Opts::new("heartbeat_sample_rate", "Sample rate reported by the last frame"), &["node_id"])?)?,
+            satellites: register(&registry, IntGaugeVec::new(Opts::new("heartbeat_satellites", "Satellite count reported by the last frame"), &["node_id"])?)?,
+            signal_rms: register(&registry, GaugeVec::new(Opts::new("heartbeat_signal_rms", "RMS about the mean of the last frame's samples, in ADC counts"), &["node_id"])?)?,
+            signal_peak_to_peak: register(&registry, IntGaugeVec::new(Opts::new("heartbeat_signal_peak_to_peak", "Peak-to-peak amplitude of the last frame's samples, in ADC counts"), &["node_id"])?)?,
+            seconds_since_last_frame: register(&registry, GaugeVec::new(Opts::new("heartbeat_seconds_since_last_frame", "Seconds since the last frame parsed successfully"), &["node_id"])?)?,
+            disk_free_bytes: register(&registry, IntGaugeVec::new(Opts::new("heartbeat_disk_free_bytes", "Free space on the filesystem a node writes to"), &["node_id"])?)?,
+            bytes_written: register(&registry, IntCounterVec::new(Opts::new("heartbeat_bytes_written_total", "Bytes added to output files on disk"), &["node_id"])?)?,
+            files_rotated: register(&registry, IntCounterVec::new(Opts::new("heartbeat_files_rotated_total", "Output files closed because their duration or size limit was reached"), &["node_id"])?)?,
+            current_file_bytes: register(&registry, IntGaugeVec::new(Opts::new("heartbeat_current_file_bytes", "Size on disk of the file currently being written"), &["node_id"])?)?,
+            broadcast_lag_events: register(&registry, IntCounterVec::new(Opts::new("heartbeat_broadcast_lag_events_total", "Times a subscriber fell behind the frame broadcast and missed messages"), &["subscriber"])?)?,
+            frame_latency: register(&registry, HistogramVec::new(HistogramOpts::new("heartbeat_frame_latency_seconds", "Time from reading a frame off the serial port until the writer finished writing it").buckets(FRAME_LATENCY_BUCKETS.to_vec()), &["node_id"])?)?,
+            registry,
+        })
+    }
+
+    // Encodes every registered metric in the Prometheus text format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: T) -> anyhow::Result<T> {
+    registry.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    #[test]
+    fn encodes_counters_and_gauges() {
+        let metrics = Metrics::new().unwrap();
+        metrics.frames_written.with_label_values(&["node_a"]).inc_by(3);
+        metrics.parse_errors.with_label_values(&["node_a"]).inc();
+        metrics.sample_rate.with_label_values(&["node_a"]).set(1000.5);
+        metrics.satellites.with_label_values(&["node_a"]).set(7);
+        metrics.frames_written.with_label_values(&["node_b"]).inc();
+
+        let text = metrics.encode().unwrap();
+        for line in [
+            "# TYPE heartbeat_frames_written_total counter",
+            "heartbeat_frames_written_total{node_id=\"node_a\"} 3",
+            "heartbeat_frames_written_total{node_id=\"node_b\"} 1",
+            "heartbeat_parse_errors_total{node_id=\"node_a\"} 1",
+            "# TYPE heartbeat_sample_rate gauge",
+            "heartbeat_sample_rate{node_id=\"node_a\"} 1000.5",
+            "heartbeat_satellites{node_id=\"node_a\"} 7",
+        ] {
+            assert!(text.lines().any(|encoded| encoded == line), "{:?} missing from:\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn frame_latency_fills_the_buckets() {
+        let metrics = Metrics::new().unwrap();
+        for latency in [0.003, 0.004, 0.2, 3.0] {
+            metrics.frame_latency.with_label_values(&["node_a"]).observe(latency);
+        }
+
+        let text = metrics.encode().unwrap();
+        for line in [
+            "# TYPE heartbeat_frame_latency_seconds histogram",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"0.001\"} 0",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"0.005\"} 2",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"0.25\"} 3",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"2.5\"} 3",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"5\"} 4",
+            "heartbeat_frame_latency_seconds_bucket{node_id=\"node_a\",le=\"+Inf\"} 4",
+            "heartbeat_frame_latency_seconds_count{node_id=\"node_a\"} 4",
+        ] {
+            assert!(text.lines().any(|encoded| encoded == line), "{:?} missing from:\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn labels_without_samples_are_not_encoded() {
+        let metrics = Metrics::new().unwrap();
+        metrics.frames_written.with_label_values(&["node_a"]).inc();
+
+        let text = metrics.encode().unwrap();
+        assert!(!text.contains("node_b"));
+        assert!(!text.contains("heartbeat_parse_errors_total{"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,94 @@
+// A one-off check of the hardware and output directory before a long
+// deployment, run with --selftest
+use std::{path::Path, time::Duration};
+
+use crate::{disk, led::{LedColor, LED}, serial::{Frame, ParseOptions, SecTickModule, SerialMessage}, writer::{OutputWriter, Writer}, HeartbeatConfig};
+
+const FRAMES_TO_READ: usize = 3;
+const MAX_READS: usize = 10;
+
+// Runs every check, prints a summary and returns whether all of them passed
+pub async fn run(config: &HeartbeatConfig) -> bool {
+    let mut results: Vec<(String, anyhow::Result<()>)> = Vec::new();
+
+    results.push(("Output directory is writable".to_string(), disk::check_writable(Path::new(&config.output_dir))));
+    results.push(("LED cycles through all colors".to_string(), cycle_led(config.led_active_low).await));
+
+    let mut frames = Vec::new();
+    for port in config.serial_port.ports() {
+        let result = read_frames(config, &port).await.map(|mut read| frames.append(&mut read));
+        results.push((format!("{} delivers {} frames that parse", port, FRAMES_TO_READ), result));
+    }
+
+    results.push(("Test file can be written and deleted".to_string(), write_test_file(config, frames.first()).await));
+
+    println!();
+    println!("Self-test summary:");
+    for (check, result) in &results {
+        match result {
+            Ok(_) => println!("  PASS  {}", check),
+            Err(e) => println!("  FAIL  {}: {}", check, e),
+        }
+    }
+
+    let passed = results.iter().all(|(_, result)| result.is_ok());
+    println!("{}", if passed { "Self-test passed" } else { "Self-test FAILED" });
+    passed
+}
+
+async fn cycle_led(active_low: bool) -> anyhow::Result<()> {
+    let mut led = LED::new(19, 20, 21, active_low)?;
+    for color in [LedColor::Red, LedColor::Green, LedColor::Blue, LedColor::Cyan, LedColor::Magenta, LedColor::Yellow, LedColor::White, LedColor::Off] {
+        log::info!("LED: {:?}", color);
+        led.set_color(color)?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    Ok(())
+}
+
+async fn read_frames(config: &HeartbeatConfig, port: &str) -> anyhow::Result<Vec<Frame>> {
+    let mut serial = SecTickModule::new(port.to_string(), 1_000_000, Duration::from_secs(5), Duration::from_millis(config.serial_read_timeout_ms), 0);
+    serial.set_framing(config.serial_framing);
+    serial.set_resync(config.resync_on_open, config.skip_lines_on_open);
+    serial.open()?;
+
+    let options = ParseOptions {
+        checksum_mode: config.checksum_mode,
+        checksum_algorithm: config.checksum_algorithm,
+        sample_type: config.sample_type,
+    };
+
+    let mut frames = Vec::new();
+    for _ in 0..MAX_READS {
+        let frame = match serial.read_message_with_reconnect().await? {
+            SerialMessage::Line(line) if line.starts_with('#') => continue,
+            SerialMessage::Line(line) => Frame::parse_with_options(&line, &options)?,
+            SerialMessage::Binary(bytes) => Frame::parse_binary_with_options(&bytes, &options)?,
+        };
+        log::info!("{}: read a frame with {} samples at {} Hz", port, frame.samples().len(), frame.sample_rate());
+
+        frames.push(frame);
+        if frames.len() == FRAMES_TO_READ {
+            return Ok(frames);
+        }
+    }
+
+    Err(anyhow::anyhow!("Only {} of {} frames arrived in {} reads", frames.len(), FRAMES_TO_READ, MAX_READS))
+}
+
+// Writes a frame (or just a comment without one) to a throwaway file in the
+// output directory
+async fn write_test_file(config: &HeartbeatConfig, frame: Option<&Frame>) -> anyhow::Result<()> {
+    let mut writer = OutputWriter::new(crate::writer_config(config, format!("{}_selftest", config.node_id)))?;
+    let file_path = writer.file_path().to_path_buf();
+
+    let result = match frame {
+        Some(frame) => writer.write_frame(chrono::Utc::now(), frame).await,
+        None => writer.write_comment("Self-test").await,
+    };
+
+    // Dropping the writer closes the file without recording it in the manifest
+    drop(writer);
+    std::fs::remove_file(&file_path)?;
+    result
+}
\ No newline at end of file
@@ -1,17 +1,133 @@
+use std::{fmt, str::FromStr};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumMode {
+    // Reject any frame whose checksum doesn't match
+    #[default]
+    Strict,
+    // Keep frames with a bad checksum, flagged via FrameMetadata::checksum_ok
+    WarnAndKeep,
+    // Additionally zero-fill missing or garbled samples and a missing checksum
+    Repair,
+}
+
+// How the checksum at the end of a frame is computed. Older firmware sends
+// the sum of the samples, newer firmware a CRC-16 of the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sum,
+    // CRC-16/CCITT-FALSE over everything before the checksum: the text
+    // between '$' and the last comma, or the binary payload up to the
+    // checksum field. Still sent as a decimal or i64 like the sum.
+    Crc16,
+}
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, no reflection
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Integer type of the samples the front end produces. Frames with a value
+// outside its range are rejected rather than truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleType {
+    #[default]
+    I16,
+    I32,
+}
+
+impl SampleType {
+    pub fn bits(&self) -> u8 {
+        match self {
+            SampleType::I16 => 16,
+            SampleType::I32 => 32,
+        }
+    }
+
+    pub fn contains(&self, value: i32) -> bool {
+        match self {
+            SampleType::I16 => i16::try_from(value).is_ok(),
+            SampleType::I32 => true,
+        }
+    }
+}
+
+// Why a frame was rejected, so the loop can tell a truncated line from a
+// corrupted one. Fields are named as in the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameParseError {
+    // The line or binary frame ended before this field
+    MissingField(&'static str),
+    InvalidNumber { field: &'static str, value: String },
+    // index counts from the first sample
+    InvalidSample { index: usize, value: String },
+    SampleOutOfRange { index: usize, value: i32, sample_type: SampleType },
+    // Fewer samples than the data count announced
+    DataCountMismatch { expected: usize, got: usize },
+    // expected is the checksum worked out with the configured algorithm, got
+    // the one the frame carried
+    ChecksumMismatch { expected: i64, got: i64 },
+}
+
+impl fmt::Display for FrameParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameParseError::MissingField(field) => write!(f, "Missing {}", field),
+            FrameParseError::InvalidNumber { field, value } => write!(f, "Failed to parse {} from {:?}", field, value),
+            FrameParseError::InvalidSample { index, value } => write!(f, "Failed to parse sample {} from {:?}", index, value),
+            FrameParseError::SampleOutOfRange { index, value, sample_type } => write!(f, "Sample {} ({}) doesn't fit in {:?}", index, value, sample_type),
+            FrameParseError::DataCountMismatch { expected, got } => write!(f, "Expected {} samples, got {}", expected, got),
+            FrameParseError::ChecksumMismatch { expected, got } => write!(f, "Checksum failed (expected {}, got {})", expected, got),
+        }
+    }
+}
+
+impl std::error::Error for FrameParseError {}
+
+// The next comma-separated field, parsed as T
+fn next_field<'a, T: FromStr>(iter: &mut impl Iterator<Item = &'a str>, field: &'static str) -> Result<T, FrameParseError> {
+    let part = iter.next().ok_or(FrameParseError::MissingField(field))?;
+    part.parse::<T>().map_err(|_| FrameParseError::InvalidNumber { field, value: part.to_string() })
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    pub checksum_mode: ChecksumMode,
+    pub checksum_algorithm: ChecksumAlgorithm,
+    pub sample_type: SampleType,
+}
+
+// Bits of the flags byte in a binary frame
+const BINARY_FLAG_GPS_FIX: u8 = 0x01;
+const BINARY_FLAG_CLIPPING: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FrameMetadata {
     has_gps_fix: bool,
     is_clipping: bool,
+    checksum_ok: bool,
 }
 
 impl FrameMetadata {
 
-    pub fn parse(line: &str) -> anyhow::Result<FrameMetadata> {
+    pub fn parse(line: &str) -> Result<FrameMetadata, FrameParseError> {
         return Ok(FrameMetadata {
             has_gps_fix: line.contains('G'),
             is_clipping: line.contains('O'),
+            checksum_ok: true,
         });
     }
 
@@ -22,9 +138,23 @@ impl FrameMetadata {
     pub fn is_clipping(&self) -> bool {
         return self.is_clipping;
     }
+
+    #[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+    pub fn checksum_ok(&self) -> bool {
+        return self.checksum_ok;
+    }
+}
+
+// Signal level of a frame in raw ADC counts, to spot dead or saturated
+// sensors at a glance
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SignalStats {
+    // Taken about the frame's mean, so the ADC's DC offset doesn't count
+    pub rms: f32,
+    pub peak_to_peak: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 
 pub struct Frame {
     timestamp: Option<i64>,
@@ -36,12 +166,21 @@ pub struct Frame {
     speed: f32,
     angle: f32,
     fix: u16,
-    data: Vec<i16>,
+    data: Vec<i32>,
 }
 
 impl Frame {
 
-    pub fn parse(line: &str) -> anyhow::Result<Frame> {
+    // Shorthands for the default ParseOptions, the acquisition loop always
+    // passes its own
+    #[allow(dead_code)]
+    pub fn parse(line: &str) -> Result<Frame, FrameParseError> {
+        return Frame::parse_with_options(line, &ParseOptions::default());
+    }
+
+    pub fn parse_with_options(line: &str, options: &ParseOptions) -> Result<Frame, FrameParseError> {
+        let repair = options.checksum_mode == ChecksumMode::Repair;
+
         let line = if line.starts_with('$') {
             line.chars().skip(1).collect::<String>()
         } else {
@@ -50,99 +189,213 @@ impl Frame {
 
         let mut iter = line.split(',');
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing timestamp"))?;
-        let timestamp = match part.parse::<i64>() {
-            Ok(timestamp) => Some(timestamp),
-            _ => None,
-        };
+        let part = iter.next().ok_or(FrameParseError::MissingField("timestamp"))?;
+        let timestamp = part.parse::<i64>().ok();
+
+        let part = iter.next().ok_or(FrameParseError::MissingField("flags"))?;
+        let mut metadata = FrameMetadata::parse(part)?;
+
+        let sample_rate = next_field::<f32>(&mut iter, "sample rate")?;
+        let latitude = next_field::<f32>(&mut iter, "latitude")?;
+        let longitude = next_field::<f32>(&mut iter, "longitude")?;
+        let elevation = next_field::<f32>(&mut iter, "elevation")?;
+        let fix = next_field::<u16>(&mut iter, "fix")?;
+        let speed = next_field::<f32>(&mut iter, "speed")?;
+        let angle = next_field::<f32>(&mut iter, "angle")?;
+        let data_count = next_field::<u16>(&mut iter, "data count")? as usize;
+
+        // The sum checksum is the plain signed sum of the sample values. Summing
+        // into an i64 keeps negative samples from sign-extending into a huge
+        // unsigned value, and 65535 i32 samples can never overflow it.
+        let mut data = Vec::<i32>::new();
+        let mut sum = 0i64;
+        let mut repaired = false;
+        for index in 0..data_count {
+            let part = iter.next();
+            let value = match part.map(|part| part.parse::<i32>()) {
+                Some(Ok(value)) if options.sample_type.contains(value) => value,
+                _ if repair => {
+                    repaired = true;
+                    0
+                },
+                None => return Err(FrameParseError::DataCountMismatch { expected: data_count, got: index }),
+                Some(Ok(value)) => return Err(FrameParseError::SampleOutOfRange { index, value, sample_type: options.sample_type }),
+                Some(Err(_)) => return Err(FrameParseError::InvalidSample { index, value: part.unwrap_or_default().to_string() }),
+            };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing flags"))?;
-        let metadata = FrameMetadata::parse(part)?;
+            sum += value as i64;
+            data.push(value);
+        }
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing sample rate"))?;
-        let sample_rate = match part.parse::<f32>() {
-            Ok(sample_rate) => sample_rate,
-            _ => return Err(anyhow::anyhow!("Failed to parse sample rate")),
+        let expected = match options.checksum_algorithm {
+            ChecksumAlgorithm::Sum => sum,
+            ChecksumAlgorithm::Crc16 => {
+                let covered = line.rfind(',').map_or(line.as_str(), |end| &line[..end]);
+                crc16(covered.as_bytes()) as i64
+            },
         };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing latitude"))?;
-        let latitude = match part.parse::<f32>() {
-            Ok(latitude) => latitude,
-            _ => return Err(anyhow::anyhow!("Failed to parse latitude")),
+        let part = iter.next();
+        let checksum = match part.map(|part| atoi::atoi::<i64>(part.trim().as_bytes())) {
+            Some(Some(checksum)) => Some(checksum),
+            _ if repair => None,
+            None => return Err(FrameParseError::MissingField("checksum")),
+            Some(None) => return Err(FrameParseError::InvalidNumber { field: "checksum", value: part.unwrap_or_default().to_string() }),
         };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing longitude"))?;
-        let longitude = match part.parse::<f32>() {
-            Ok(longitude) => longitude,
-            _ => return Err(anyhow::anyhow!("Failed to parse longitude")),
-        };
+        if repaired || checksum != Some(expected) {
+            match (options.checksum_mode, checksum) {
+                // Strict never repairs, so there always is a checksum here
+                (ChecksumMode::Strict, Some(checksum)) => return Err(FrameParseError::ChecksumMismatch { expected, got: checksum }),
+                _ => {
+                    log::warn!("Keeping frame with failed checksum (expected {}, got {:?})", expected, checksum);
+                    metadata.checksum_ok = false;
+                }
+            }
+        }
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing elevation"))?;
-        let elevation = match part.parse::<f32>() {
-            Ok(elevation) => elevation,
-            _ => return Err(anyhow::anyhow!("Failed to parse elevation")),
+        let frame = Frame {
+            timestamp: timestamp,
+            sample_rate: sample_rate,
+            metadata: metadata,
+            latitude: latitude,
+            longitude: longitude,
+            elevation: elevation,
+            fix: fix,
+            speed: speed,
+            angle: angle,
+            data: data,
         };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing fix"))?;
-        let fix = match part.parse::<u16>() {
-            Ok(fix) => fix,
-            _ => return Err(anyhow::anyhow!("Failed to parse fix")),
-        };
+        return Ok(frame);
+    }
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing speed"))?;
-        let speed = match part.parse::<f32>() {
-            Ok(speed) => speed,
-            _ => return Err(anyhow::anyhow!("Failed to parse speed")),
-        };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing angle"))?;
-        let angle = match part.parse::<f32>() {
-            Ok(angle) => angle,
-            _ => return Err(anyhow::anyhow!("Failed to parse angle")),
+    #[allow(dead_code)]
+    pub fn parse_binary(bytes: &[u8]) -> Result<Frame, FrameParseError> {
+        return Frame::parse_binary_with_options(bytes, &ParseOptions::default());
+    }
+
+    // Decodes the payload of a binary frame. All fields are little-endian:
+    //   timestamp i64 (-1 without GPS time), flags u8, sample_rate f32,
+    //   latitude f32, longitude f32, elevation f32, fix u16, speed f32,
+    //   angle f32, data count u16, data count x samples, checksum i64
+    // Samples are i16 or i32 as set by ParseOptions::sample_type. The checksum
+    // is the same signed sum of the samples the text format uses, or the
+    // CRC-16 of the payload before it.
+    pub fn parse_binary_with_options(bytes: &[u8], options: &ParseOptions) -> Result<Frame, FrameParseError> {
+        let mut reader = BinaryReader { bytes, offset: 0 };
+
+        let timestamp = match i64::from_le_bytes(reader.take("timestamp")?) {
+            -1 => None,
+            timestamp => Some(timestamp),
         };
 
-        let part = iter.next().ok_or(anyhow::anyhow!("Missing data count"))?;
-        let data_count: usize = match part.parse::<u16>() {
-            Ok(data_count) => data_count as usize,
-            _ => return Err(anyhow::anyhow!("Failed to parse data count")),
+        let flags = u8::from_le_bytes(reader.take("flags")?);
+        let mut metadata = FrameMetadata {
+            has_gps_fix: flags & BINARY_FLAG_GPS_FIX != 0,
+            is_clipping: flags & BINARY_FLAG_CLIPPING != 0,
+            checksum_ok: true,
         };
 
-        let mut data = Vec::<i16>::new();
-        let mut sum = 0u64;
-        for _ in 10..10usize + data_count {
-            let part = iter.next().ok_or(anyhow::anyhow!("Missing data"))?;
-            let value = match part.parse::<i16>() {
-                Ok(value) => value,
-                _ => return Err(anyhow::anyhow!("Failed to parse data")),
+        let sample_rate = f32::from_le_bytes(reader.take("sample rate")?);
+        let latitude = f32::from_le_bytes(reader.take("latitude")?);
+        let longitude = f32::from_le_bytes(reader.take("longitude")?);
+        let elevation = f32::from_le_bytes(reader.take("elevation")?);
+        let fix = u16::from_le_bytes(reader.take("fix")?);
+        let speed = f32::from_le_bytes(reader.take("speed")?);
+        let angle = f32::from_le_bytes(reader.take("angle")?);
+        let data_count = u16::from_le_bytes(reader.take("data count")?) as usize;
+
+        let mut data = Vec::<i32>::with_capacity(data_count);
+        let mut sum = 0i64;
+        let truncated = |index| FrameParseError::DataCountMismatch { expected: data_count, got: index };
+        for index in 0..data_count {
+            let value = match options.sample_type {
+                SampleType::I16 => i16::from_le_bytes(reader.take("data").map_err(|_| truncated(index))?) as i32,
+                SampleType::I32 => i32::from_le_bytes(reader.take("data").map_err(|_| truncated(index))?),
             };
-
-            sum += value as u64;
+            sum += value as i64;
             data.push(value);
         }
 
-        let checksum =
-            atoi::atoi::<u64>(iter.next().ok_or(anyhow::anyhow!("Missing checksum"))?.as_bytes()).unwrap();
+        let expected = match options.checksum_algorithm {
+            ChecksumAlgorithm::Sum => sum,
+            ChecksumAlgorithm::Crc16 => crc16(&bytes[..reader.offset]) as i64,
+        };
 
-        if checksum != sum {
-            return Err(anyhow::anyhow!("Checksum failed"));
+        let checksum = i64::from_le_bytes(reader.take("checksum")?);
+        if checksum != expected {
+            match options.checksum_mode {
+                ChecksumMode::Strict => return Err(FrameParseError::ChecksumMismatch { expected, got: checksum }),
+                ChecksumMode::WarnAndKeep | ChecksumMode::Repair => {
+                    log::warn!("Keeping binary frame with failed checksum (expected {}, got {})", expected, checksum);
+                    metadata.checksum_ok = false;
+                }
+            }
         }
 
-        let frame = Frame {
-            timestamp: timestamp,
-            sample_rate: sample_rate,
-            metadata: metadata,
-            latitude: latitude,
-            longitude: longitude,
-            elevation: elevation,
-            fix: fix,
-            speed: speed,
-            angle: angle,
-            data: data,
+        return Ok(Frame {
+            timestamp,
+            sample_rate,
+            metadata,
+            latitude,
+            longitude,
+            elevation,
+            speed,
+            angle,
+            fix,
+            data,
+        });
+    }
+
+    // The frame as the device would send it, without the line ending, for
+    // re-emitting frames to other processes. A frame without a timestamp gets
+    // an empty timestamp field, which parses back to None, and the checksum is
+    // recomputed with `algorithm`. Nothing in this binary re-emits frames yet.
+    #[allow(dead_code)]
+    pub fn to_line(&self, algorithm: ChecksumAlgorithm) -> String {
+        let mut flags = String::new();
+        if self.metadata.has_gps_fix {
+            flags.push('G');
+        }
+        if self.metadata.is_clipping {
+            flags.push('O');
+        }
+        if flags.is_empty() {
+            flags.push('-');
+        }
+
+        let timestamp = match self.timestamp {
+            Some(timestamp) => timestamp.to_string(),
+            None => String::new(),
         };
 
-        return Ok(frame);
-    }
+        let mut line = format!("${},{},{},{},{},{},{},{},{},{}",
+            timestamp,
+            flags,
+            self.sample_rate,
+            self.latitude,
+            self.longitude,
+            self.elevation,
+            self.fix,
+            self.speed,
+            self.angle,
+            self.data.len());
+
+        for value in &self.data {
+            line.push_str(&format!(",{}", value));
+        }
 
+        let checksum = match algorithm {
+            ChecksumAlgorithm::Sum => self.data.iter().map(|value| *value as i64).sum(),
+            // Everything after the '$' so far, as parsing covers it
+            ChecksumAlgorithm::Crc16 => crc16(&line.as_bytes()[1..]) as i64,
+        };
+        line.push_str(&format!(",{}", checksum));
+
+        return line;
+    }
 
     pub fn timestamp(&self) -> Option<i64> {
         return self.timestamp
@@ -152,10 +405,14 @@ impl Frame {
         return self.fix
     }
 
-    pub fn samples(&self) -> Vec<i16> {
+    pub fn samples(&self) -> Vec<i32> {
         return self.data.clone();
     }
 
+    pub fn sample_count(&self) -> usize {
+        return self.data.len();
+    }
+
     pub fn latitude(&self) -> f32 {
         return self.latitude;
     }
@@ -168,9 +425,348 @@ impl Frame {
         return self.elevation;
     }
 
-    pub fn metadata(&self) -> FrameMetadata {
-        return self.metadata.clone();
+    #[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+    pub fn speed(&self) -> f32 {
+        return self.speed;
+    }
+
+    #[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+    pub fn angle(&self) -> f32 {
+        return self.angle;
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        return self.sample_rate;
+    }
+
+    pub fn metadata(&self) -> &FrameMetadata {
+        return &self.metadata;
+    }
+
+    // Fraction of samples sitting at or beyond the ADC rails
+    pub fn clip_ratio(&self, rail_low: i32, rail_high: i32) -> f32 {
+        if self.data.is_empty() {
+            return 0.0;
+        }
+
+        let clipped = self.data.iter().filter(|v| **v <= rail_low || **v >= rail_high).count();
+        return clipped as f32 / self.data.len() as f32;
     }
 
+    pub fn signal_stats(&self) -> SignalStats {
+        let (min, max) = match (self.data.iter().min(), self.data.iter().max()) {
+            (Some(min), Some(max)) => (*min, *max),
+            _ => return SignalStats { rms: 0.0, peak_to_peak: 0 },
+        };
+
+        let count = self.data.len() as f64;
+        let mean = self.data.iter().map(|v| *v as f64).sum::<f64>() / count;
+        let variance = self.data.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / count;
+
+        return SignalStats {
+            rms: variance.sqrt() as f32,
+            peak_to_peak: max as i64 - min as i64,
+        };
+    }
+
+
+}
+
+struct BinaryReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl BinaryReader<'_> {
+    fn take<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], FrameParseError> {
+        let bytes = self.bytes.get(self.offset..self.offset + N).ok_or(FrameParseError::MissingField(field))?;
+        self.offset += N;
+        return Ok(bytes.try_into().expect("Slice is N bytes long"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, ChecksumAlgorithm, ChecksumMode, Frame, FrameMetadata, FrameParseError, ParseOptions, SampleType, SignalStats};
+    use crate::testutil;
 
+    const LINE: &str = "$1700000000,G,1000,52.5,4.25,12.5,7,1.5,90,4,100,-200,300,-400,-200";
+
+    fn options(checksum_mode: ChecksumMode) -> ParseOptions {
+        ParseOptions { checksum_mode, ..ParseOptions::default() }
+    }
+
+    // A binary frame payload with a sum checksum
+    fn binary(timestamp: i64, flags: u8, samples: &[i16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes.push(flags);
+        for value in [1000.0f32, 52.5, 4.25, 12.5] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&7u16.to_le_bytes());
+        for value in [1.5f32, 90.0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(samples.len() as u16).to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        let checksum: i64 = samples.iter().map(|sample| *sample as i64).sum();
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    // xorshift64*, so a failing case is the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545F4914F6CDD1D)
+        }
+
+        fn bool(&mut self) -> bool {
+            self.next() & 1 == 1
+        }
+
+        // Any finite value, from subnormals to the largest
+        fn f32(&mut self) -> f32 {
+            loop {
+                let value = f32::from_bits(self.next() as u32);
+                if value.is_finite() {
+                    return value;
+                }
+            }
+        }
+
+        fn frame(&mut self) -> Frame {
+            let samples = (self.next() % 64) as usize;
+            Frame {
+                timestamp: if self.bool() { Some(self.next() as i64) } else { None },
+                sample_rate: self.f32(),
+                metadata: FrameMetadata {
+                    has_gps_fix: self.bool(),
+                    is_clipping: self.bool(),
+                    checksum_ok: true,
+                },
+                latitude: self.f32(),
+                longitude: self.f32(),
+                elevation: self.f32(),
+                speed: self.f32(),
+                angle: self.f32(),
+                fix: self.next() as u16,
+                data: (0..samples).map(|_| self.next() as i16 as i32).collect(),
+            }
+        }
+    }
+
+    #[test]
+    fn to_line_round_trips_random_frames() {
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        for checksum_algorithm in [ChecksumAlgorithm::Sum, ChecksumAlgorithm::Crc16] {
+            let options = ParseOptions { checksum_algorithm, ..options(ChecksumMode::Strict) };
+            for _ in 0..1000 {
+                let frame = rng.frame();
+                let line = frame.to_line(checksum_algorithm);
+                assert_eq!(Frame::parse_with_options(&line, &options).unwrap(), frame, "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_every_field() {
+        let frame = Frame::parse(LINE).unwrap();
+        assert_eq!(frame.timestamp(), Some(1700000000));
+        assert!(frame.metadata().has_gps_fix());
+        assert!(!frame.metadata().is_clipping());
+        assert!(frame.metadata().checksum_ok());
+        assert_eq!(frame.sample_rate(), 1000.0);
+        assert_eq!(frame.latitude(), 52.5);
+        assert_eq!(frame.longitude(), 4.25);
+        assert_eq!(frame.elevation(), 12.5);
+        assert_eq!(frame.satellite_count(), 7);
+        assert_eq!(frame.speed(), 1.5);
+        assert_eq!(frame.angle(), 90.0);
+        assert_eq!(frame.samples(), vec![100, -200, 300, -400]);
+    }
+
+    #[test]
+    fn line_ending_and_missing_dollar_are_fine() {
+        let frame = Frame::parse(&format!("{}\r\n", LINE)).unwrap();
+        assert_eq!(frame.samples(), vec![100, -200, 300, -400]);
+        assert_eq!(Frame::parse(&LINE[1..]).unwrap().samples(), frame.samples());
+    }
+
+    #[test]
+    fn unparsable_timestamp_is_none() {
+        let frame = Frame::parse("$,GO,1000,0,0,0,0,0,0,1,5,5").unwrap();
+        assert_eq!(frame.timestamp(), None);
+        assert!(frame.metadata().is_clipping());
+    }
+
+    #[test]
+    fn checksum_sums_negative_samples_as_signed() {
+        // As an unsigned 16-bit sum this would be 65535 * 3 + 1, not -2
+        let frame = Frame::parse("$1,G,1000,0,0,0,0,0,0,4,-1,-1,-1,1,-2").unwrap();
+        assert_eq!(frame.samples(), vec![-1, -1, -1, 1]);
+
+        let frame = Frame::parse("$1,G,1000,0,0,0,0,0,0,2,-32768,-32768,-65536").unwrap();
+        assert_eq!(frame.samples(), vec![-32768, -32768]);
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_bad_checksum() {
+        let line = LINE.replacen("-400,-200", "-400,-199", 1);
+        assert_eq!(Frame::parse(&line), Err(FrameParseError::ChecksumMismatch { expected: -200, got: -199 }));
+    }
+
+    #[test]
+    fn warn_and_keep_flags_a_bad_checksum() {
+        let line = LINE.replacen("-400,-200", "-400,-199", 1);
+        let frame = Frame::parse_with_options(&line, &options(ChecksumMode::WarnAndKeep)).unwrap();
+        assert!(!frame.metadata().checksum_ok());
+        assert_eq!(frame.samples(), vec![100, -200, 300, -400]);
+
+        // But still rejects lines that are missing samples
+        let line = "$1,G,1000,0,0,0,0,0,0,4,1,2";
+        assert_eq!(Frame::parse_with_options(line, &options(ChecksumMode::WarnAndKeep)), Err(FrameParseError::DataCountMismatch { expected: 4, got: 2 }));
+    }
+
+    #[test]
+    fn repair_zero_fills_garbled_and_missing_samples() {
+        let frame = Frame::parse_with_options("$1,G,1000,0,0,0,0,0,0,4,1,x,3", &options(ChecksumMode::Repair)).unwrap();
+        assert_eq!(frame.samples(), vec![1, 0, 3, 0]);
+        assert!(!frame.metadata().checksum_ok());
+    }
+
+    #[test]
+    fn sample_type_bounds_the_samples() {
+        let line = "$1,G,1000,0,0,0,0,0,0,3,40000,-70000,1,-29999";
+        assert_eq!(Frame::parse(line), Err(FrameParseError::SampleOutOfRange { index: 0, value: 40000, sample_type: SampleType::I16 }));
+
+        let options = ParseOptions { sample_type: SampleType::I32, ..ParseOptions::default() };
+        let frame = Frame::parse_with_options(line, &options).unwrap();
+        assert_eq!(frame.samples(), vec![40000, -70000, 1]);
+    }
+
+    #[test]
+    fn malformed_lines_say_what_is_wrong() {
+        assert_eq!(Frame::parse("$1,G,1000,52.5"), Err(FrameParseError::MissingField("longitude")));
+        assert_eq!(Frame::parse("$1,G,1000,north,4.25,12.5,7,1.5,90,0,0"),
+            Err(FrameParseError::InvalidNumber { field: "latitude", value: "north".to_string() }));
+        assert_eq!(Frame::parse("$1,G,1000,0,0,0,0,0,0,many,0"),
+            Err(FrameParseError::InvalidNumber { field: "data count", value: "many".to_string() }));
+        assert_eq!(Frame::parse("$1,G,1000,0,0,0,0,0,0,3,1,x,3,4"),
+            Err(FrameParseError::InvalidSample { index: 1, value: "x".to_string() }));
+        assert_eq!(Frame::parse("$1,G,1000,0,0,0,0,0,0,2,1,2"), Err(FrameParseError::MissingField("checksum")));
+        assert_eq!(Frame::parse("$1,G,1000,0,0,0,0,0,0,2,1,2,sum"),
+            Err(FrameParseError::InvalidNumber { field: "checksum", value: "sum".to_string() }));
+    }
+
+    #[test]
+    fn malformed_binary_frames_say_what_is_wrong() {
+        let bytes = binary(1, 0x00, &[1, 2, 3]);
+        assert_eq!(Frame::parse_binary(&bytes[..20]), Err(FrameParseError::MissingField("longitude")));
+        // Data count, then the first sample
+        assert_eq!(Frame::parse_binary(&bytes[..39]), Err(FrameParseError::DataCountMismatch { expected: 3, got: 1 }));
+        assert_eq!(Frame::parse_binary(&bytes[..bytes.len() - 1]), Err(FrameParseError::MissingField("checksum")));
+
+        let mut bytes = bytes;
+        let end = bytes.len();
+        bytes[end - 8..].copy_from_slice(&7i64.to_le_bytes());
+        assert_eq!(Frame::parse_binary(&bytes), Err(FrameParseError::ChecksumMismatch { expected: 6, got: 7 }));
+    }
+
+    #[test]
+    fn crc16_matches_the_standard_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), 0xFFFF);
+    }
+
+    #[test]
+    fn crc16_checksums_catch_swapped_samples() {
+        let options = ParseOptions { checksum_algorithm: ChecksumAlgorithm::Crc16, ..ParseOptions::default() };
+
+        let frame = Frame::parse_with_options("$1,G,1000,0,0,0,0,0,0,2,1,2,57578\r\n", &options).unwrap();
+        assert_eq!(frame.samples(), vec![1, 2]);
+        assert_eq!(frame.to_line(ChecksumAlgorithm::Crc16), "$1,G,1000,0,0,0,0,0,0,2,1,2,57578");
+        assert_eq!(frame.to_line(ChecksumAlgorithm::Sum), "$1,G,1000,0,0,0,0,0,0,2,1,2,3");
+
+        // Same sum, so only the CRC notices
+        assert_eq!(Frame::parse_with_options("$1,G,1000,0,0,0,0,0,0,2,2,1,57578", &options),
+            Err(FrameParseError::ChecksumMismatch { expected: 35289, got: 57578 }));
+        assert!(Frame::parse("$1,G,1000,0,0,0,0,0,0,2,2,1,3").is_ok());
+    }
+
+    #[test]
+    fn crc16_checksums_binary_frames() {
+        let options = ParseOptions { checksum_algorithm: ChecksumAlgorithm::Crc16, ..ParseOptions::default() };
+        let mut bytes = binary(1, 0x00, &[1, 2]);
+        let end = bytes.len() - 8;
+        let crc = crc16(&bytes[..end]) as i64;
+
+        // The sum checksum binary() writes doesn't pass
+        assert_eq!(Frame::parse_binary_with_options(&bytes, &options), Err(FrameParseError::ChecksumMismatch { expected: crc, got: 3 }));
+
+        bytes[end..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Frame::parse_binary_with_options(&bytes, &options).unwrap().samples(), vec![1, 2]);
+    }
+
+    #[test]
+    fn parses_binary_frames() {
+        let frame = Frame::parse_binary(&binary(1700000000, 0x03, &[100, -200, 300])).unwrap();
+        assert_eq!(frame.timestamp(), Some(1700000000));
+        assert!(frame.metadata().has_gps_fix());
+        assert!(frame.metadata().is_clipping());
+        assert_eq!(frame.latitude(), 52.5);
+        assert_eq!(frame.satellite_count(), 7);
+        assert_eq!(frame.angle(), 90.0);
+        assert_eq!(frame.samples(), vec![100, -200, 300]);
+
+        let frame = Frame::parse_binary(&binary(-1, 0x00, &[])).unwrap();
+        assert_eq!(frame.timestamp(), None);
+        assert!(!frame.metadata().has_gps_fix());
+    }
+
+    #[test]
+    fn parses_binary_i32_samples() {
+        let mut bytes = binary(1, 0x00, &[]);
+        bytes.truncate(bytes.len() - 10);
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        for sample in [100_000i32, -100_000] {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0i64.to_le_bytes());
+
+        let options = ParseOptions { sample_type: SampleType::I32, ..ParseOptions::default() };
+        let frame = Frame::parse_binary_with_options(&bytes, &options).unwrap();
+        assert_eq!(frame.samples(), vec![100_000, -100_000]);
+    }
+
+    #[test]
+    fn dc_has_no_signal() {
+        let frame = Frame::parse(&testutil::frame_line(1, true, &[512; 100])).unwrap();
+        assert_eq!(frame.signal_stats(), SignalStats { rms: 0.0, peak_to_peak: 0 });
+    }
+
+    #[test]
+    fn full_scale_sine_stats() {
+        // 10 whole periods of 100 samples
+        let samples: Vec<i32> = (0..1000)
+            .map(|i| (32767.0 * (2.0 * std::f64::consts::PI * i as f64 / 100.0).sin()).round() as i32)
+            .collect();
+        let stats = Frame::parse(&testutil::frame_line(1, true, &samples)).unwrap().signal_stats();
+
+        assert!((stats.rms - 32767.0 / 2f32.sqrt()).abs() < 1.0, "{}", stats.rms);
+        assert_eq!(stats.peak_to_peak, 65534);
+    }
+
+    #[test]
+    fn clip_ratio_counts_samples_at_the_rails() {
+        let frame = Frame::parse("$1,G,1000,0,0,0,0,0,0,4,-32768,0,32767,5,4").unwrap();
+        assert_eq!(frame.clip_ratio(-32768, 32767), 0.5);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,202 @@
+// A scripted stand-in for a serial port, so the reading and acquisition code
+// can be tested without a device. Build one with the script the device
+// should play, then hand it to SecTickModule::with_port.
+use std::{collections::VecDeque, io::{Read, Write}, sync::{Arc, Mutex}, time::Duration};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+enum Step {
+    Data(Vec<u8>),
+    Delay(Duration),
+    Error(std::io::Error),
+}
+
+#[derive(Clone)]
+pub struct MockSerialPort {
+    // Shared with clones, as the handles of a real port share the device
+    script: Arc<Mutex<VecDeque<Step>>>,
+    baud_rate: u32,
+    timeout: Duration,
+}
+
+impl MockSerialPort {
+    pub fn new() -> MockSerialPort {
+        MockSerialPort {
+            script: Default::default(),
+            baud_rate: 1_000_000,
+            timeout: Duration::from_millis(50),
+        }
+    }
+
+    pub fn bytes(self, bytes: &[u8]) -> MockSerialPort {
+        self.push(Step::Data(bytes.to_vec()))
+    }
+
+    // Adds the line ending
+    pub fn line(self, line: &str) -> MockSerialPort {
+        self.bytes(format!("{}\r\n", line).as_bytes())
+    }
+
+    // Holds up the next read, e.g. to split a line across two reads
+    pub fn delay(self, delay: Duration) -> MockSerialPort {
+        self.push(Step::Delay(delay))
+    }
+
+    // The next read fails with this
+    pub fn error(self, error: std::io::Error) -> MockSerialPort {
+        self.push(Step::Error(error))
+    }
+
+    fn push(self, step: Step) -> MockSerialPort {
+        self.script.lock().unwrap().push_back(step);
+        self
+    }
+}
+
+impl Read for MockSerialPort {
+    // Once the script has run out every read times out, as a port does when
+    // the device goes quiet
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let step = self.script.lock().unwrap().pop_front();
+            match step {
+                Some(Step::Data(mut data)) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    if len < data.len() {
+                        self.script.lock().unwrap().push_front(Step::Data(data.split_off(len)));
+                    }
+                    return Ok(len);
+                },
+                Some(Step::Delay(delay)) => std::thread::sleep(delay),
+                Some(Step::Error(e)) => return Err(e),
+                None => {
+                    std::thread::sleep(self.timeout);
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+    }
+}
+
+// Nothing is ever sent to the device, so writes go nowhere
+impl Write for MockSerialPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockSerialPort {
+    fn name(&self) -> Option<String> {
+        Some("/dev/heartbeat-test-mock".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let script = self.script.lock().unwrap();
+        Ok(script.iter().map_while(|step| match step {
+            Step::Data(data) => Some(data.len() as u32),
+            _ => None,
+        }).sum())
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            self.script.lock().unwrap().retain(|step| !matches!(step, Step::Data(_)));
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -1,64 +1,491 @@
 pub mod data;
+#[cfg(test)]
+pub mod mock;
+pub mod nmea;
 
-use anyhow::Context;
-pub use data::Frame;
+pub use data::{ChecksumAlgorithm, ChecksumMode, Frame, ParseOptions, SampleType, SignalStats};
+pub use nmea::GpsRecord;
+use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
-use std::io::BufRead;
+use std::io::{BufRead, Read};
 
 use std::time::Duration;
 
-pub struct SecTickData {
-    pub timestamp: u64
+// Why a read from the serial port failed. read_message_with_reconnect
+// returns these inside the anyhow::Error, alongside failures to reconnect.
+#[derive(Debug)]
+pub enum ReadError {
+    // Nothing arrived within the read timeout. The port is still open, the
+    // device just went quiet.
+    Timeout,
+    // The port itself failed, e.g. the device was unplugged
+    Io(std::io::Error),
+    // The port's mutex was poisoned by a read that panicked
+    Lock,
+    // Data arrived but isn't a line or frame, e.g. invalid UTF-8
+    Parse(String),
 }
 
+impl ReadError {
+    // Metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReadError::Timeout => "timeout",
+            ReadError::Io(_) => "io",
+            ReadError::Lock => "lock",
+            ReadError::Parse(_) => "parse",
+        }
+    }
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Timeout => write!(f, "Timeout reading serial port"),
+            ReadError::Io(e) => write!(f, "Error reading serial port: {}", e),
+            ReadError::Lock => write!(f, "Error locking serial port mutex"),
+            ReadError::Parse(message) => write!(f, "Unreadable data from serial port: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => ReadError::Timeout,
+            _ => ReadError::Io(e),
+        }
+    }
+}
+
+// Binary frames start with this marker followed by a little-endian u32
+// payload length. Scanning for the marker also resyncs after opening the
+// port in the middle of a frame.
+const BINARY_SYNC: [u8; 2] = [0xA5, 0x5A];
+const BINARY_MAX_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    // One CSV frame or # comment per line
+    #[default]
+    Text,
+    // Length-prefixed binary frames, see Frame::parse_binary
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum SerialMessage {
+    Line(String),
+    Binary(Vec<u8>),
+}
+
+impl SerialMessage {
+    // A short, printable piece of the message for logging
+    pub fn excerpt(&self) -> String {
+        match self {
+            SerialMessage::Line(line) => line.chars().take(60).collect(),
+            SerialMessage::Binary(bytes) => format!("<{} byte binary frame>", bytes.len()),
+        }
+    }
+}
+
+// An open port, and the start of a line cut off by a read timeout, which
+// the next read carries on from
+struct Port {
+    reader: std::io::BufReader<Box<dyn Read + Send>>,
+    partial_line: Vec<u8>,
+}
+
+impl Port {
+    fn new(port: Box<dyn Read + Send>) -> Port {
+        Port { reader: std::io::BufReader::new(port), partial_line: Vec::new() }
+    }
+}
+
+// Opens a port, see SecTickModule::opener
+type PortOpener = Box<dyn FnOnce() -> anyhow::Result<Box<dyn serialport::SerialPort>> + Send>;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
 pub struct SecTickModule {
     serial_port: String,
     baud_rate: u32,
-    timeout: Duration,
-    port: Option<std::sync::Arc<std::sync::Mutex<std::io::BufReader<Box<dyn serialport::SerialPort>>>>>
+    // How long opening the port may take before the attempt counts as failed
+    open_timeout: Duration,
+    // How long a read from the port waits for data before giving up
+    read_timeout: Duration,
+    max_reconnect_attempts: u32,
+    framing: Framing,
+    // Throw away the (likely partial) first line after opening the port, and
+    // optionally a few more full lines after it
+    resync_on_open: bool,
+    skip_lines_on_open: usize,
+    // Lines still to be thrown away since the port was last opened
+    discard_lines: usize,
+    port: Option<std::sync::Arc<std::sync::Mutex<Port>>>,
+    // Set by with_port: opening clones this handle instead of opening
+    // serial_port
+    device: Option<Box<dyn serialport::SerialPort>>,
 }
 
 impl SecTickModule {
     
-    pub fn new(serial_port: String, baud_rate: u32, timeout: Duration) -> SecTickModule {
-        SecTickModule { serial_port, baud_rate, timeout, port: None }
+    pub fn new(serial_port: String, baud_rate: u32, open_timeout: Duration, read_timeout: Duration, max_reconnect_attempts: u32) -> SecTickModule {
+        SecTickModule { serial_port, baud_rate, open_timeout, read_timeout, max_reconnect_attempts, framing: Framing::Text, resync_on_open: false, skip_lines_on_open: 0, discard_lines: 0, port: None, device: None }
+    }
+
+    // Reads from `port` as if it had just been opened. Reconnecting tries
+    // once, with another handle to the same port.
+    #[cfg(test)]
+    pub fn with_port(port: Box<dyn serialport::SerialPort>) -> SecTickModule {
+        let serial_port = port.name().unwrap_or_default();
+        let baud_rate = port.baud_rate().unwrap_or(1_000_000);
+        let timeout = port.timeout();
+        let mut serial = SecTickModule::new(serial_port, baud_rate, timeout, timeout, 1);
+        serial.device = Some(port.try_clone().expect("Unable to clone the test port"));
+        serial.port = Some(std::sync::Arc::new(std::sync::Mutex::new(Port::new(Box::new(port)))));
+        serial
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn set_resync(&mut self, resync_on_open: bool, skip_lines_on_open: usize) {
+        self.resync_on_open = resync_on_open;
+        self.skip_lines_on_open = skip_lines_on_open;
     }
 
     pub fn open(&mut self) -> anyhow::Result<()> {
         log::info!("Opening serial port: {} at baud rate: {}", self.serial_port, self.baud_rate);
+        let port = self.opener()?()?;
+        self.opened(port);
+        Ok(())
+    }
 
-        // Open serial port
-        let port = serialport::new(self.serial_port.clone(), self.baud_rate)
-            .timeout(self.timeout)
-            .open()?;
+    // As open, but waits for the port on a blocking thread so the runtime
+    // isn't held up for as long as open_timeout
+    async fn open_async(&mut self) -> anyhow::Result<()> {
+        log::info!("Opening serial port: {} at baud rate: {}", self.serial_port, self.baud_rate);
+        let opener = self.opener()?;
+        let port = tokio::task::spawn_blocking(opener).await??;
+        self.opened(port);
+        Ok(())
+    }
 
-        let port = std::sync::Arc::new(std::sync::Mutex::new(std::io::BufReader::new(port)));
+    // Opens the port without touching the module, so that it can run on
+    // another thread
+    fn opener(&self) -> anyhow::Result<PortOpener> {
+        if let Some(device) = &self.device {
+            let port = device.try_clone()?;
+            return Ok(Box::new(move || Ok(port)));
+        }
 
-        self.port = Some(port);
+        let serial_port = self.serial_port.clone();
+        let open_timeout = self.open_timeout;
+        let builder = serialport::new(self.serial_port.clone(), self.baud_rate).timeout(self.read_timeout);
+        Ok(Box::new(move || {
+            // serialport has no timeout for opening itself, and a USB serial device
+            // in a bad state can hang it, so open on a thread of its own. A port
+            // that opens after all is closed again when the thread drops it.
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = tx.send(builder.open());
+            });
+            match rx.recv_timeout(open_timeout) {
+                Ok(port) => Ok(port?),
+                Err(_) => Err(anyhow::anyhow!("Timed out opening serial port {} after {:?}", serial_port, open_timeout)),
+            }
+        }))
+    }
 
-        Ok(())
+    fn opened(&mut self, port: Box<dyn serialport::SerialPort>) {
+        self.port = Some(std::sync::Arc::new(std::sync::Mutex::new(Port::new(Box::new(port)))));
+        if self.framing == Framing::Text {
+            self.discard_lines = self.skip_lines_on_open + if self.resync_on_open { 1 } else { 0 };
+        }
+    }
+
+    pub async fn read_line(&mut self) -> Result<String, ReadError> {
+        self.read_blocking(|port| {
+            // On a timeout whatever arrived so far stays in partial_line
+            match port.reader.read_until(b'\n', &mut port.partial_line) {
+                // A zero-length read means the device went away
+                Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(_) => String::from_utf8(std::mem::take(&mut port.partial_line)).map_err(|e| ReadError::Parse(e.to_string())),
+                Err(e) => Err(e.into()),
+            }
+        }).await
     }
 
-    pub async fn read_line(&mut self) -> anyhow::Result<String> {
-        let port = self.port.as_ref().context("No port open")?.clone();
-        let serial_read_future: JoinHandle<anyhow::Result<String>> = tokio::task::spawn_blocking(move || {
-            let mut line = String::new();
-            let mut port = port.lock().map_err(|_| anyhow::anyhow!("Error locking mutex"))?;
-            
-            port.read_line(&mut line)?;
+    pub async fn read_binary(&mut self) -> Result<Vec<u8>, ReadError> {
+        self.read_blocking(|port| {
+            // Skip ahead to the sync marker
+            let mut byte = [0u8; 1];
+            let mut previous = 0u8;
+            loop {
+                port.reader.read_exact(&mut byte)?;
+                if previous == BINARY_SYNC[0] && byte[0] == BINARY_SYNC[1] {
+                    break;
+                }
+                previous = byte[0];
+            }
+
+            let mut length = [0u8; 4];
+            port.reader.read_exact(&mut length)?;
+            let length = u32::from_le_bytes(length) as usize;
+            if length > BINARY_MAX_LENGTH {
+                return Err(ReadError::Parse(format!("Binary frame length {} is over the {} byte limit", length, BINARY_MAX_LENGTH)));
+            }
+
+            let mut payload = vec![0u8; length];
+            port.reader.read_exact(&mut payload)?;
+            Ok(payload)
+        }).await
+    }
 
-            Ok(line)
+    // Runs a blocking read on the port off the async runtime. The port itself
+    // gives up after read_timeout, so the read is never abandoned while the
+    // blocking task still holds the port. A line cut off by the timeout is
+    // finished by the next read, while a binary frame cut off is lost and the
+    // next read resyncs on the marker.
+    async fn read_blocking<T: Send + 'static>(&mut self, read: fn(&mut Port) -> Result<T, ReadError>) -> Result<T, ReadError> {
+        let port = match &self.port {
+            Some(port) => port.clone(),
+            None => return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "No port open").into()),
+        };
+        let serial_read_future: JoinHandle<Result<T, ReadError>> = tokio::task::spawn_blocking(move || {
+            let mut port = port.lock().map_err(|_| ReadError::Lock)?;
+            read(&mut port)
         });
 
-        match tokio::time::timeout(self.timeout, serial_read_future).await {
-            Ok(serial_read_future) => return serial_read_future?,
-            Err(_) => return Err(anyhow::anyhow!("Timeout reading serial port"))
+        // The read only fails to join if it panicked, which poisons the lock
+        return serial_read_future.await.unwrap_or(Err(ReadError::Lock));
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<SerialMessage> {
+        match self.framing {
+            Framing::Text => Ok(SerialMessage::Line(self.read_line().await?)),
+            Framing::Binary => Ok(SerialMessage::Binary(self.read_binary().await?)),
+        }
+    }
+
+    // Reads the next line or binary frame, depending on the framing, and
+    // re-opens the port with exponential backoff if the device has been
+    // unplugged or reset.
+    pub async fn read_message_with_reconnect(&mut self) -> anyhow::Result<SerialMessage> {
+        loop {
+            match self.next_message_with_reconnect().await? {
+                SerialMessage::Line(line) if self.discard_lines > 0 => {
+                    self.discard_lines -= 1;
+                    log::debug!("Discarding line after opening {}: {:?}", self.serial_port, line.chars().take(40).collect::<String>());
+                },
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn next_message_with_reconnect(&mut self) -> anyhow::Result<SerialMessage> {
+        if self.port.is_none() {
+            self.reconnect().await?;
+        }
+
+        match self.read_message().await {
+            Ok(message) => Ok(message),
+            Err(e) if Self::is_disconnect(&e) => {
+                log::warn!("Serial port {} disconnected: {}", self.serial_port, e);
+                self.port = None;
+                self.reconnect().await?;
+                self.read_message().await
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        let mut delay = RECONNECT_MIN_DELAY;
+
+        for attempt in 1..=self.max_reconnect_attempts {
+            tokio::time::sleep(delay).await;
+
+            match self.open_async().await {
+                Ok(_) => {
+                    log::info!("Reconnected to serial port {} after {} attempt(s)", self.serial_port, attempt);
+                    return Ok(());
+                },
+                Err(e) => {
+                    log::warn!("Reconnect attempt {}/{} to {} failed: {}", attempt, self.max_reconnect_attempts, self.serial_port, e);
+                }
+            }
+
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
         }
 
+        Err(anyhow::anyhow!("Unable to reconnect to serial port {} after {} attempts", self.serial_port, self.max_reconnect_attempts))
     }
 
-    pub async fn next_data(&mut self) -> anyhow::Result<SecTickData> {
-        return Ok(SecTickData { timestamp: 0 });
+    fn is_disconnect(e: &anyhow::Error) -> bool {
+        let e = match e.downcast_ref::<ReadError>() {
+            Some(ReadError::Io(e)) => e,
+            _ => return false,
+        };
+
+        match e.kind() {
+            std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::NotFound => true,
+            // EIO is what Linux reports when a USB serial device is unplugged
+            _ => e.raw_os_error() == Some(5),
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{mock::MockSerialPort, Frame, ReadError, SecTickModule, SerialMessage, RECONNECT_MIN_DELAY};
+    use crate::testutil;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
 
+    fn module_reading(port: MockSerialPort) -> SecTickModule {
+        SecTickModule::with_port(Box::new(port))
+    }
+
+    #[tokio::test]
+    async fn reads_scripted_frames() {
+        let line = testutil::frame_line(2, true, &[4, 5, 6]);
+        let (start, end) = line.split_at(10);
+        let port = MockSerialPort::new()
+            .line(&testutil::frame_line(1, true, &[1, 2, 3]))
+            // Half a line, then a pause longer than the read timeout
+            .bytes(start.as_bytes())
+            .delay(TIMEOUT * 2)
+            .line(end)
+            .error(std::io::ErrorKind::TimedOut.into())
+            .line(&testutil::frame_line(3, false, &[7, 8, 9]));
+        let mut serial = module_reading(port);
+
+        let mut timestamps = Vec::new();
+        while timestamps.len() < 3 {
+            match serial.read_message_with_reconnect().await {
+                Ok(SerialMessage::Line(line)) => timestamps.push(Frame::parse(&line).unwrap().timestamp()),
+                Ok(SerialMessage::Binary(_)) => panic!("Text framing returned a binary frame"),
+                Err(e) => assert!(matches!(e.downcast_ref::<ReadError>(), Some(ReadError::Timeout)), "{:?}", e),
+            }
+        }
+        assert_eq!(timestamps, vec![Some(1), Some(2), Some(3)]);
+
+        // Then the device goes quiet
+        assert!(matches!(serial.read_line().await, Err(ReadError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn read_errors_say_what_went_wrong() {
+        let timeout = MockSerialPort::new().error(std::io::ErrorKind::TimedOut.into());
+        assert!(matches!(module_reading(timeout).read_line().await, Err(ReadError::Timeout)));
+
+        // EIO, as when a USB serial device is unplugged
+        let unplugged = MockSerialPort::new().error(std::io::Error::from_raw_os_error(5));
+        let e = module_reading(unplugged).read_line().await.unwrap_err();
+        assert!(matches!(e, ReadError::Io(_)));
+        assert!(SecTickModule::is_disconnect(&e.into()));
+
+        let garbled = MockSerialPort::new().bytes(&[0xff, 0xfe, b'\n']);
+        assert!(matches!(module_reading(garbled).read_line().await, Err(ReadError::Parse(_))));
+
+        let line = MockSerialPort::new().bytes(b"$1,G\n");
+        assert_eq!(module_reading(line).read_line().await.unwrap(), "$1,G\n");
+    }
+
+    #[tokio::test]
+    async fn a_poisoned_port_is_a_lock_error() {
+        let mut serial = module_reading(MockSerialPort::new());
+        let port = serial.port.clone().unwrap();
+        let _ = std::thread::spawn(move || {
+            let _guard = port.lock().unwrap();
+            panic!("poisoning the port");
+        }).join();
+
+        assert!(matches!(serial.read_line().await, Err(ReadError::Lock)));
+    }
+
+    #[tokio::test]
+    async fn retries_a_lost_port_with_backoff_then_gives_up() {
+        let mut serial = SecTickModule::new("/dev/heartbeat-test-missing".to_string(), 1_000_000, TIMEOUT, TIMEOUT, 1);
+
+        let started = Instant::now();
+        let e = serial.read_message_with_reconnect().await.unwrap_err();
+        assert!(started.elapsed() >= RECONNECT_MIN_DELAY);
+        assert!(e.to_string().contains("after 1 attempts"), "{}", e);
+        assert!(serial.port.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_port_fails_and_keeps_reading() {
+        let port = MockSerialPort::new()
+            .line(&testutil::frame_line(1, true, &[1, 2, 3]))
+            // EIO, as when a USB serial device is unplugged
+            .error(std::io::Error::from_raw_os_error(5))
+            .line(&testutil::frame_line(2, true, &[4, 5, 6]))
+            .line(&testutil::frame_line(3, true, &[7, 8, 9]));
+        let mut serial = module_reading(port);
+
+        let mut timestamps = Vec::new();
+        let started = Instant::now();
+        while timestamps.len() < 3 {
+            match serial.read_message_with_reconnect().await.unwrap() {
+                SerialMessage::Line(line) => timestamps.push(Frame::parse(&line).unwrap().timestamp()),
+                SerialMessage::Binary(_) => panic!("Text framing returned a binary frame"),
+            }
+        }
+        assert_eq!(timestamps, vec![Some(1), Some(2), Some(3)]);
+        // Waited before reopening
+        assert!(started.elapsed() >= RECONNECT_MIN_DELAY);
+    }
+
+    #[tokio::test]
+    async fn resync_drops_the_partial_first_line() {
+        // Opened partway through the first frame
+        let mid_line = || MockSerialPort::new()
+            .line(&testutil::frame_line(1, true, &[1, 2, 3])[20..])
+            .line(&testutil::frame_line(2, true, &[4, 5, 6]));
+        async fn next_line(serial: &mut SecTickModule) -> String {
+            match serial.read_message_with_reconnect().await.unwrap() {
+                SerialMessage::Line(line) => line,
+                SerialMessage::Binary(_) => panic!("Text framing returned a binary frame"),
+            }
+        }
+
+        let mut serial = module_reading(mid_line());
+        assert!(Frame::parse(&next_line(&mut serial).await).is_err());
+
+        let mut serial = module_reading(mid_line());
+        serial.set_resync(true, 0);
+        serial.open().unwrap();
+        assert_eq!(Frame::parse(&next_line(&mut serial).await).unwrap().timestamp(), Some(2));
+    }
+
+    #[test]
+    fn opening_a_missing_port_fails_without_waiting_out_the_open_timeout() {
+        let mut serial = SecTickModule::new("/dev/heartbeat-test-missing".to_string(), 1_000_000, Duration::from_secs(5), TIMEOUT, 0);
+
+        let started = Instant::now();
+        assert!(serial.open().is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(serial.port.is_none());
+    }
+
+    #[test]
+    fn excerpts_are_short() {
+        assert_eq!(SerialMessage::Line("x".repeat(100)).excerpt(), "x".repeat(60));
+        assert_eq!(SerialMessage::Binary(vec![0; 12]).excerpt(), "<12 byte binary frame>");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+// Structured GPS records from the NMEA sentences some devices pass through
+// as # comments. Only GGA and RMC sentences are recognized, anything else
+// stays a free-text comment.
+
+// Only the HDF5 writer stores the parsed fields, CSV keeps the sentence
+#[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpsRecord {
+    // The sentence as received, without the leading #
+    pub sentence: String,
+    // UTC time of day in seconds, NMEA sentences carry no date of their own
+    pub time: Option<f64>,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    // Meters above mean sea level, GGA only
+    pub altitude: Option<f32>,
+    // Knots over ground, RMC only
+    pub speed: Option<f32>,
+    // Degrees true, RMC only
+    pub course: Option<f32>,
+    // GGA only
+    pub satellites: Option<u16>,
+}
+
+// Parses a comment line as a GGA or RMC sentence. Returns None if it is not
+// one, or if its checksum doesn't match.
+pub fn parse(line: &str) -> Option<GpsRecord> {
+    let sentence = line.trim_start_matches('#').trim();
+    let body = sentence.strip_prefix('$')?;
+
+    let body = match body.split_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum.trim(), 16).ok()?;
+            if body.bytes().fold(0u8, |acc, b| acc ^ b) != expected {
+                return None;
+            }
+            body
+        },
+        None => body,
+    };
+
+    let fields: Vec<&str> = body.split(',').collect();
+    let field = |i: usize| fields.get(i).copied().filter(|f| !f.is_empty());
+
+    let mut record = GpsRecord {
+        sentence: sentence.to_string(),
+        time: None,
+        latitude: None,
+        longitude: None,
+        altitude: None,
+        speed: None,
+        course: None,
+        satellites: None,
+    };
+
+    // The first two letters are the talker (GP, GN, GL...), the rest the type
+    match fields[0].get(2..)? {
+        "GGA" => {
+            record.time = field(1).and_then(parse_time);
+            record.latitude = coordinate(field(2), field(3));
+            record.longitude = coordinate(field(4), field(5));
+            record.satellites = field(7).and_then(|f| f.parse().ok());
+            record.altitude = field(9).and_then(|f| f.parse().ok());
+        },
+        "RMC" => {
+            record.time = field(1).and_then(parse_time);
+            record.latitude = coordinate(field(3), field(4));
+            record.longitude = coordinate(field(5), field(6));
+            record.speed = field(7).and_then(|f| f.parse().ok());
+            record.course = field(8).and_then(|f| f.parse().ok());
+        },
+        _ => return None,
+    }
+
+    return Some(record);
+}
+
+// hhmmss.ss to seconds since midnight
+fn parse_time(field: &str) -> Option<f64> {
+    let hours: f64 = field.get(0..2)?.parse().ok()?;
+    let minutes: f64 = field.get(2..4)?.parse().ok()?;
+    let seconds: f64 = field.get(4..)?.parse().ok()?;
+    return Some(hours * 3600.0 + minutes * 60.0 + seconds);
+}
+
+// (d)ddmm.mmmm plus a hemisphere to signed decimal degrees
+fn coordinate(value: Option<&str>, hemisphere: Option<&str>) -> Option<f32> {
+    let value: f64 = value?.parse().ok()?;
+    let degrees = (value / 100.0).trunc();
+    let decimal = degrees + (value - degrees * 100.0) / 60.0;
+
+    match hemisphere? {
+        "N" | "E" => Some(decimal as f32),
+        "S" | "W" => Some(-decimal as f32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    const GGA: &str = "#$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+    const RMC: &str = "#$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+
+    fn close(value: Option<f32>, expected: f32) -> bool {
+        return value.is_some_and(|value| (value - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn parses_gga() {
+        let record = parse(GGA).unwrap();
+        assert_eq!(record.sentence, &GGA[1..]);
+        assert_eq!(record.time, Some(12.0 * 3600.0 + 35.0 * 60.0 + 19.0));
+        assert!(close(record.latitude, 48.0 + 7.038 / 60.0));
+        assert!(close(record.longitude, 11.0 + 31.0 / 60.0));
+        assert_eq!(record.satellites, Some(8));
+        assert_eq!(record.altitude, Some(545.4));
+        assert_eq!(record.speed, None);
+        assert_eq!(record.course, None);
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let record = parse(RMC).unwrap();
+        assert_eq!(record.time, Some(12.0 * 3600.0 + 35.0 * 60.0 + 19.0));
+        assert!(close(record.latitude, 48.0 + 7.038 / 60.0));
+        assert_eq!(record.speed, Some(22.4));
+        assert_eq!(record.course, Some(84.4));
+        assert_eq!(record.altitude, None);
+        assert_eq!(record.satellites, None);
+    }
+
+    #[test]
+    fn southern_and_western_hemispheres_are_negative() {
+        let record = parse("#$GNRMC,000000.50,A,3351.000,S,15112.000,W,,,010124,,*23").unwrap();
+        assert_eq!(record.time, Some(0.5));
+        assert!(close(record.latitude, -33.85));
+        assert!(close(record.longitude, -151.2));
+        assert_eq!(record.speed, None);
+    }
+
+    #[test]
+    fn checksum_is_optional_but_checked() {
+        assert!(parse(GGA.split('*').next().unwrap()).is_some());
+        assert_eq!(parse(&GGA.replace("*47", "*48")), None);
+        assert_eq!(parse(&GGA.replace("*47", "*zz")), None);
+    }
+
+    #[test]
+    fn other_comments_are_not_records() {
+        assert_eq!(parse("# battery low"), None);
+        assert_eq!(parse("#$GPGSV,3,1,11,03,03,111,00"), None);
+        assert_eq!(parse("#$"), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,197 @@
+// Shows each node's GPS fix, satellites and sample rate, and the free disk
+// space, on a small screen next to the status LED. Follows the same frames
+// the LED does. Only SSD1306 OLEDs on I2C are supported, in builds with the
+// oled feature.
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use super::{LagCountingReceiver, ServiceMessage};
+use crate::disk;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub trait StatusDisplay: Send + 'static {
+    // Replaces what is on the screen with these lines, from the top
+    fn show(&mut self, lines: &[String]) -> anyhow::Result<()>;
+}
+
+#[cfg(all(feature = "oled", target_os = "linux"))]
+mod ssd1306_display {
+    use embedded_graphics::{mono_font::{ascii::FONT_6X10, MonoTextStyle}, pixelcolor::BinaryColor, prelude::*, text::{Baseline, Text}};
+    use ssd1306::{mode::BufferedGraphicsMode, prelude::*, I2CDisplayInterface, Ssd1306};
+
+    use super::StatusDisplay;
+
+    // 128x64 pixels, so six lines of 21 characters in the 6x10 font
+    pub struct Ssd1306Display {
+        display: Ssd1306<I2CInterface<rppal::i2c::I2c>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
+    }
+
+    impl Ssd1306Display {
+        pub fn new(bus: u8, address: u8) -> anyhow::Result<Ssd1306Display> {
+            let i2c = rppal::i2c::I2c::with_bus(bus)?;
+            let mut display = Ssd1306::new(I2CDisplayInterface::new_custom_address(i2c, address), DisplaySize128x64, DisplayRotation::Rotate0)
+                .into_buffered_graphics_mode();
+            display.init().map_err(|e| anyhow::anyhow!("Unable to set up the display: {:?}", e))?;
+            Ok(Ssd1306Display { display })
+        }
+    }
+
+    impl StatusDisplay for Ssd1306Display {
+        fn show(&mut self, lines: &[String]) -> anyhow::Result<()> {
+            let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+            self.display.clear_buffer();
+            for (i, line) in lines.iter().enumerate() {
+                Text::with_baseline(line, Point::new(0, i as i32 * 10), style, Baseline::Top)
+                    .draw(&mut self.display)
+                    .map_err(|e| anyhow::anyhow!("Unable to draw on the display: {:?}", e))?;
+            }
+            self.display.flush().map_err(|e| anyhow::anyhow!("Unable to update the display: {:?}", e))
+        }
+    }
+}
+
+// The display configured with oled_display
+#[cfg(all(feature = "oled", target_os = "linux"))]
+pub fn open(bus: u8, address: u8) -> anyhow::Result<Box<dyn StatusDisplay>> {
+    Ok(Box::new(ssd1306_display::Ssd1306Display::new(bus, address)?))
+}
+
+#[cfg(not(all(feature = "oled", target_os = "linux")))]
+pub fn open(_bus: u8, _address: u8) -> anyhow::Result<Box<dyn StatusDisplay>> {
+    Err(anyhow::anyhow!("Built without OLED display support, rebuild with --features oled"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NodeStatus {
+    has_fix: bool,
+    satellites: u16,
+    sample_rate: f32,
+}
+
+pub struct DisplayService {
+    display: Box<dyn StatusDisplay>,
+    output_dir: PathBuf,
+    // From each node's last frame, None until its first one
+    nodes: BTreeMap<String, Option<NodeStatus>>,
+}
+
+impl DisplayService {
+    pub fn new(display: Box<dyn StatusDisplay>, node_ids: &[String], output_dir: PathBuf) -> DisplayService {
+        DisplayService {
+            display,
+            output_dir,
+            nodes: node_ids.iter().map(|node_id| (node_id.clone(), None)).collect(),
+        }
+    }
+
+    pub fn frame(&mut self, node_id: &str, frame: &crate::serial::Frame) {
+        if let Some(status) = self.nodes.get_mut(node_id) {
+            *status = Some(NodeStatus {
+                has_fix: frame.metadata().has_gps_fix(),
+                satellites: frame.satellite_count(),
+                sample_rate: frame.sample_rate(),
+            });
+        }
+    }
+
+    // Two lines per node, then the free space on the output filesystem
+    fn lines(&self, free_bytes: Option<u64>) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (node_id, status) in &self.nodes {
+            lines.push(node_id.clone());
+            lines.push(match status {
+                Some(status) => format!("{} {}sat {:.0}Hz", if status.has_fix { "FIX" } else { "NOFIX" }, status.satellites, status.sample_rate),
+                None => "Waiting for frames".to_string(),
+            });
+        }
+        lines.push(match free_bytes {
+            Some(free_bytes) => format!("Disk {:.1} GB free", free_bytes as f64 / 1e9),
+            None => "Disk unknown".to_string(),
+        });
+        lines
+    }
+
+    pub fn refresh(&mut self) {
+        let free_bytes = match disk::free_bytes(&self.output_dir) {
+            Ok(free_bytes) => Some(free_bytes),
+            Err(e) => {
+                log::warn!("Unable to check free space for the display: {:?}", e);
+                None
+            }
+        };
+
+        let lines = self.lines(free_bytes);
+        if let Err(e) = self.display.show(&lines) {
+            log::warn!("Unable to update the status display: {:?}", e);
+        }
+    }
+
+    pub fn start(mut self, mut rx: LagCountingReceiver) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Ok(ServiceMessage::NewFrame { node_id, frame }) => self.frame(&node_id, &frame),
+                        Ok(ServiceMessage::Shutdown) | Err(RecvError::Closed) => break,
+                        Ok(_) | Err(RecvError::Lagged(_)) => {},
+                    },
+                    _ = interval.tick() => self.refresh(),
+                }
+            }
+
+            if let Err(e) = self.display.show(&["Stopped".to_string()]) {
+                log::warn!("Unable to update the status display: {:?}", e);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{DisplayService, StatusDisplay};
+    use crate::testutil;
+
+    // Keeps the last lines shown, shared with the test
+    #[derive(Clone, Default)]
+    struct MockDisplay {
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StatusDisplay for MockDisplay {
+        fn show(&mut self, lines: &[String]) -> anyhow::Result<()> {
+            *self.lines.lock().unwrap() = lines.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shows_each_node_and_the_free_space() {
+        let display = MockDisplay::default();
+        let node_ids = ["node_a".to_string(), "node_b".to_string()];
+        let mut service = DisplayService::new(Box::new(display.clone()), &node_ids, testutil::temp_dir("display"));
+
+        service.frame("node_a", &testutil::frame(1, true, &[1, 2, 3]));
+        // Not one of ours
+        service.frame("node_c", &testutil::frame(1, true, &[1, 2, 3]));
+        assert_eq!(service.lines(Some(12_345_000_000)), vec![
+            "node_a",
+            "FIX 7sat 1000Hz",
+            "node_b",
+            "Waiting for frames",
+            "Disk 12.3 GB free",
+        ]);
+
+        service.frame("node_b", &testutil::frame(2, false, &[1, 2, 3]));
+        assert_eq!(service.lines(None)[3..], ["NOFIX 7sat 1000Hz", "Disk unknown"]);
+
+        service.refresh();
+        let shown = display.lines.lock().unwrap().clone();
+        assert_eq!(shown.len(), 5);
+        assert!(shown[4].starts_with("Disk ") && shown[4].ends_with(" GB free"));
+    }
+}
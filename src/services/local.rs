@@ -1,22 +1,44 @@
-use std::{path::PathBuf, sync::{Arc, Mutex}};
+use std::{collections::VecDeque, net::SocketAddr, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
-use futures::TryFutureExt;
+use axum::{extract::{Query, State}, http::{header, StatusCode}, response::{sse::{Event, KeepAlive, Sse}, IntoResponse}, routing::{get, post}, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::Stream;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::serial::Frame;
+use crate::{disk, metrics::Metrics, serial::{Frame, SignalStats}, writer::manifest};
 
-use super::ServiceMessage;
+use super::{ControlMessage, LagCountingReceiver, ServiceMessage};
 
 #[derive(Debug, Clone)]
 pub struct LocalServiceConfig {
     pub port: u16,
     pub node_id: String,
+    // How long after the last frame /health keeps reporting healthy
+    pub health_stale_after: Duration,
+    // Where the capture files listed by /files live
+    pub output_dir: PathBuf,
+    // How many frames /frames/recent can return
+    pub recent_frames_capacity: usize,
+    // Serve HTTPS instead of HTTP when both are set
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    // When set, every route but /health needs "Authorization: Bearer <token>"
+    pub api_token: Option<String>,
+    // The config the process loaded, secrets already redacted, for /config.
+    // Settings reloaded on SIGHUP aren't reflected.
+    pub effective_config: serde_json::Value,
 }
 
+const DEFAULT_FILES_LIMIT: usize = 50;
+const MAX_COMMENT_LENGTH: usize = 1000;
+const DEFAULT_PREVIEW_POINTS: usize = 720;
+
 pub struct LocalService {
     config: LocalServiceConfig,
     last_frame: std::sync::Arc<std::sync::Mutex<AppState>>,
+    metrics: Arc<Metrics>,
     tx: tokio::sync::broadcast::Sender<ServiceMessage>,
+    control_tx: tokio::sync::broadcast::Sender<ControlMessage>,
     watch_tx: tokio::sync::watch::Sender<Option<()>>,
 }
 
@@ -24,21 +46,128 @@ pub struct LocalService {
 pub struct AppState {
     frame: Option<Frame>,
     node_id: String,
+    #[serde(skip)]
+    last_frame_at: Option<Instant>,
+    // Oldest first, at most recent_frames_capacity long
+    #[serde(skip)]
+    recent: VecDeque<FrameResponse>,
+}
+
+impl AppState {
+    fn record(&mut self, node_id: String, frame: Frame, recent_capacity: usize) {
+        if recent_capacity > 0 {
+            if self.recent.len() == recent_capacity {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(FrameResponse::new(node_id.clone(), frame.clone()));
+        }
+
+        self.frame = Some(frame);
+        self.node_id = node_id;
+        self.last_frame_at = Some(Instant::now());
+    }
+}
+
+#[derive(Clone)]
+pub struct RouterState {
+    app: Arc<Mutex<AppState>>,
+    config: LocalServiceConfig,
+    metrics: Arc<Metrics>,
+    tx: tokio::sync::broadcast::Sender<ServiceMessage>,
+    control_tx: tokio::sync::broadcast::Sender<ControlMessage>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FrameResponse {
     frame: Option<Frame>,
     node_id: String,
+    stats: Option<SignalStats>,
+}
+
+impl FrameResponse {
+    fn new(node_id: String, frame: Frame) -> FrameResponse {
+        FrameResponse {
+            stats: Some(frame.signal_stats()),
+            frame: Some(frame),
+            node_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HealthResponse {
+    healthy: bool,
+    reason: String,
+    seconds_since_last_frame: Option<f64>,
+    node_id: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileResponse {
+    filename: String,
+    size_bytes: u64,
+    modified: String,
+    // Closed and recorded in the manifest, as opposed to still being written
+    complete: bool,
+    created: Option<String>,
+    node_id: Option<String>,
+    sha256: Option<String>,
+    frame_count: Option<usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StorageResponse {
+    // Of the filesystem holding the output directory
+    total_bytes: u64,
+    used_bytes: u64,
+    free_bytes: u64,
+    capture_files: usize,
+    capture_bytes: u64,
+    // Modification times of the oldest and newest capture file
+    oldest_modified: Option<String>,
+    newest_modified: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CommentRequest {
+    comment: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PreviewResponse {
+    node_id: String,
+    sample_rate: f32,
+    // Samples the points were reduced from
+    samples: usize,
+    points: Vec<i32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PreviewQuery {
+    points: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RecentQuery {
+    n: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct FilesQuery {
+    limit: Option<usize>,
 }
 
 impl LocalService {
     pub fn new(config: LocalServiceConfig,
-        tx: tokio::sync::broadcast::Sender<ServiceMessage>) -> LocalService {
+        metrics: Arc<Metrics>,
+        tx: tokio::sync::broadcast::Sender<ServiceMessage>,
+        control_tx: tokio::sync::broadcast::Sender<ControlMessage>) -> LocalService {
 
         let appstate = std::sync::Arc::new(std::sync::Mutex::new(AppState{
             frame: None,
             node_id: config.node_id.clone(),
+            last_frame_at: None,
+            recent: VecDeque::with_capacity(config.recent_frames_capacity),
         }));
 
         let (w_tx, _) = tokio::sync::watch::channel(Option::<()>::None);
@@ -46,7 +175,9 @@ impl LocalService {
         LocalService {
             config, 
             last_frame: appstate,
+            metrics,
             tx: tx,
+            control_tx,
             watch_tx: w_tx,
         }
     }
@@ -54,43 +185,76 @@ impl LocalService {
     pub async fn start(&mut self) -> anyhow::Result<()> {
 
         let last_frame_inner = self.last_frame.clone();
-        let tx = self.tx.clone();
-        let node_id = self.config.node_id.clone();
+        let mut rx = LagCountingReceiver::new(self.tx.subscribe(), "local_service", self.metrics.clone());
+        let recent_capacity = self.config.recent_frames_capacity;
         tokio::spawn(async move {
-            let mut rx = tx.subscribe();
             loop {
                 match rx.recv().await {
-                    Ok(ServiceMessage::NewFrame(frame)) => {
+                    Ok(ServiceMessage::NewFrame { node_id, frame }) => {
                         log::debug!("Received new frame");
                         match last_frame_inner.lock() {
-                            Ok(mut guard) => {
-                                *guard = AppState {
-                                    frame: Some(frame.clone()),
-                                    node_id: node_id.clone(),
-                                };
-                            }
+                            Ok(mut guard) => guard.record(node_id, frame, recent_capacity),
                             Err(e) => {
                                 log::error!("Unable to lock last_frame: {:?}", e);
                             }
                         }
-                    }
+                    },
+                    Err(RecvError::Closed) => break,
                     _ => {}
                 }
             }
         });
 
+        // Load the certificate up front so a bad one fails startup
+        let tls_config = match (&self.config.tls_cert_path, &self.config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let tls_config = RustlsConfig::from_pem_file(cert_path, key_path).await
+                    .map_err(|e| anyhow::anyhow!("Unable to load TLS certificate {} and key {}: {}", cert_path.display(), key_path.display(), e))?;
+                Some(tls_config)
+            },
+            (None, None) => None,
+            _ => return Err(anyhow::anyhow!("tls_cert_path and tls_key_path must be set together")),
+        };
+
         let last_frame_inner = self.last_frame.clone();
         let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        let tx = self.tx.clone();
+        let control_tx = self.control_tx.clone();
         let watch_rx = self.watch_tx.subscribe();
         tokio::spawn(async move {
-            let router = Router::new()
-                .route("/frame", get(Self::get_frame))
-                .with_state(last_frame_inner);
-            let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await.unwrap();
+            let state = RouterState {
+                app: last_frame_inner,
+                config: config.clone(),
+                metrics,
+                tx,
+                control_tx,
+            };
+            let router = Self::router(state);
+
+            match tls_config {
+                Some(tls_config) => {
+                    let handle = axum_server::Handle::new();
+                    let shutdown_handle = handle.clone();
+                    tokio::spawn(async move {
+                        Self::graceful_shutdown_signal(watch_rx).await;
+                        shutdown_handle.graceful_shutdown(Some(Duration::from_secs(5)));
+                    });
 
-            axum::serve(listener, router)
-                .with_graceful_shutdown(Self::graceful_shutdown_signal(watch_rx))
-                .await.unwrap();
+                    log::info!("Serving HTTPS on port {}", config.port);
+                    axum_server::bind_rustls(SocketAddr::from(([0, 0, 0, 0], config.port)), tls_config)
+                        .handle(handle)
+                        .serve(router.into_make_service())
+                        .await.unwrap();
+                },
+                None => {
+                    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await.unwrap();
+
+                    axum::serve(listener, router)
+                        .with_graceful_shutdown(Self::graceful_shutdown_signal(watch_rx))
+                        .await.unwrap();
+                }
+            }
 
             log::info!("Server shutdown");
         });
@@ -98,6 +262,22 @@ impl LocalService {
         Ok(())
     }
 
+    fn router(state: RouterState) -> Router {
+        Router::new()
+            .route("/frame", get(Self::get_frame))
+            .route("/frame/preview", get(Self::get_frame_preview))
+            .route("/frames/stream", get(Self::get_frame_stream))
+            .route("/frames/recent", get(Self::get_recent_frames))
+            .route("/metrics", get(Self::get_metrics))
+            .route("/files", get(Self::get_files))
+            .route("/storage", get(Self::get_storage))
+            .route("/config", get(Self::get_config))
+            .route("/comment", post(Self::post_comment))
+            .route_layer(axum::middleware::from_fn_with_state(state.clone(), Self::require_token))
+            .route("/health", get(Self::get_health))
+            .with_state(state)
+    }
+
     pub async fn graceful_shutdown_signal(mut watch_rx: tokio::sync::watch::Receiver<Option<()>>) {
         watch_rx.changed().await.unwrap();
     }
@@ -106,21 +286,666 @@ impl LocalService {
         self.watch_tx.send(Some(())).unwrap();
     }
 
-    pub async fn get_frame(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
-        let state = state.lock().unwrap();
+    pub async fn require_token(State(state): State<RouterState>, request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+        let token = match &state.config.api_token {
+            Some(token) => token,
+            None => return next.run(request).await,
+        };
+
+        let provided = request.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => next.run(request).await,
+            _ => (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "Missing or invalid bearer token").into_response(),
+        }
+    }
+
+    pub async fn get_config(State(state): State<RouterState>) -> impl IntoResponse {
+        Json(state.config.effective_config.clone())
+    }
+
+    pub async fn get_frame(State(state): State<RouterState>) -> impl IntoResponse {
+        let state = state.app.lock().unwrap();
         match state.frame.as_ref() {
             Some(frame) => {
-                (StatusCode::OK, Json(FrameResponse {
-                        frame: Some(frame.clone()),
-                        node_id: state.node_id.clone(),
-                    }))
+                (StatusCode::OK, Json(FrameResponse::new(state.node_id.clone(), frame.clone())))
             }
             None => {
                 (StatusCode::NOT_FOUND, Json(FrameResponse {
                         frame: None,
                         node_id: state.node_id.clone(),
+                        stats: None,
                     }))
             }
         }
     }
+
+    // The last frame's samples reduced to a few points for plotting. Each
+    // bucket contributes its min and max, in order, so peaks survive.
+    pub async fn get_frame_preview(State(state): State<RouterState>, Query(query): Query<PreviewQuery>) -> impl IntoResponse {
+        let app = state.app.lock().unwrap();
+        let frame = match app.frame.as_ref() {
+            Some(frame) => frame,
+            None => return (StatusCode::NOT_FOUND, "No frames received yet").into_response(),
+        };
+
+        let samples = frame.samples();
+        let points = query.points.unwrap_or(DEFAULT_PREVIEW_POINTS).min(samples.len() - samples.len() % 2);
+        if points < 2 || points % 2 == 1 {
+            return (StatusCode::BAD_REQUEST, "points must be an even number of at least 2").into_response();
+        }
+
+        Json(PreviewResponse {
+            node_id: app.node_id.clone(),
+            sample_rate: frame.sample_rate(),
+            samples: samples.len(),
+            points: min_max_preview(&samples, points),
+        }).into_response()
+    }
+
+    // Up to n of the most recent frames, oldest first
+    pub async fn get_recent_frames(State(state): State<RouterState>, Query(query): Query<RecentQuery>) -> impl IntoResponse {
+        let app = state.app.lock().unwrap();
+        let n = query.n.unwrap_or(state.config.recent_frames_capacity).min(app.recent.len());
+        let frames: Vec<FrameResponse> = app.recent.iter().skip(app.recent.len() - n).cloned().collect();
+        Json(frames)
+    }
+
+    pub async fn get_health(State(state): State<RouterState>) -> impl IntoResponse {
+        let stale_after = state.config.health_stale_after;
+        let app = state.app.lock().unwrap();
+        let since_last_frame = app.last_frame_at.map(|at| at.elapsed());
+
+        let (healthy, reason) = match since_last_frame {
+            None => (false, "No frames received yet".to_string()),
+            Some(elapsed) if elapsed > stale_after => {
+                (false, format!("Last frame is stale ({:.1}s old, limit {}s)", elapsed.as_secs_f64(), stale_after.as_secs()))
+            }
+            Some(_) => (true, "OK".to_string()),
+        };
+
+        let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        (status, Json(HealthResponse {
+            healthy,
+            reason,
+            seconds_since_last_frame: since_last_frame.map(|elapsed| elapsed.as_secs_f64()),
+            node_id: app.node_id.clone(),
+        }))
+    }
+
+    pub async fn get_frame_stream(State(state): State<RouterState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+        let rx = LagCountingReceiver::new(state.tx.subscribe(), "frame_stream", state.metrics.clone());
+
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            let event = match rx.recv().await {
+                Ok(ServiceMessage::NewFrame { node_id, frame }) => {
+                    let response = FrameResponse::new(node_id, frame);
+                    Event::default().event("frame").json_data(&response)
+                }
+                // Clients following a node want to know when its fix comes and goes
+                Ok(ServiceMessage::GpsFixChanged { node_id, has_fix, satellites }) => {
+                    let change = serde_json::json!({ "node_id": node_id, "has_fix": has_fix, "satellites": satellites });
+                    Event::default().event("gps_fix").json_data(&change)
+                }
+                Ok(ServiceMessage::Shutdown) => return None,
+                // The channel dropped frames for this client, let it know
+                Err(RecvError::Lagged(skipped)) => Ok(Event::default().comment(format!("lagged {}", skipped))),
+                Err(RecvError::Closed) => return None,
+            };
+            Some((event, rx))
+        });
+
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+
+    pub async fn get_metrics(State(state): State<RouterState>) -> impl IntoResponse {
+        match state.metrics.encode() {
+            Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+            Err(e) => {
+                log::error!("Unable to encode metrics: {:?}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], String::new())
+            }
+        }
+    }
+
+    // Adds an operator note to the current file of every node. The Json
+    // extractor already rejects bodies that aren't valid UTF-8.
+    pub async fn post_comment(State(state): State<RouterState>, Json(request): Json<CommentRequest>) -> impl IntoResponse {
+        // Comments are written one per line, so keep them on one line
+        let comment: String = request.comment.trim()
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+
+        if comment.is_empty() {
+            return (StatusCode::BAD_REQUEST, "Comment is empty".to_string());
+        }
+
+        if comment.chars().count() > MAX_COMMENT_LENGTH {
+            return (StatusCode::PAYLOAD_TOO_LARGE, format!("Comment is longer than {} characters", MAX_COMMENT_LENGTH));
+        }
+
+        log::info!("Operator comment: {}", comment);
+        match state.control_tx.send(ControlMessage::Comment(comment)) {
+            Ok(_) => (StatusCode::ACCEPTED, String::new()),
+            Err(e) => {
+                log::error!("Unable to forward comment: {:?}", e);
+                (StatusCode::SERVICE_UNAVAILABLE, "No acquisition is running".to_string())
+            }
+        }
+    }
+
+    // Capture files in the output directory, newest first
+    pub async fn get_files(State(state): State<RouterState>, Query(query): Query<FilesQuery>) -> impl IntoResponse {
+        match Self::list_files(&state.config.output_dir, query.limit.unwrap_or(DEFAULT_FILES_LIMIT)) {
+            Ok(files) => (StatusCode::OK, Json(files)).into_response(),
+            Err(e) => {
+                log::error!("Unable to list capture files: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    fn list_files(output_dir: &std::path::Path, limit: usize) -> anyhow::Result<Vec<FileResponse>> {
+        let recorded = manifest::entries(output_dir)?;
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(output_dir)? {
+            let entry = entry?;
+            if !is_capture(&entry.path()) {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let modified: chrono::DateTime<chrono::Utc> = metadata.modified()?.into();
+            let filename = entry.file_name().to_string_lossy().to_string();
+            let record = recorded.iter().rev().find(|record| record.filename == filename);
+
+            files.push((modified, FileResponse {
+                filename,
+                size_bytes: metadata.len(),
+                modified: modified.to_rfc3339(),
+                complete: record.is_some(),
+                created: record.map(|record| record.created.clone()),
+                node_id: record.map(|record| record.node_id.clone()),
+                sha256: record.map(|record| record.sha256.clone()),
+                frame_count: record.map(|record| record.frame_count),
+            }));
+        }
+
+        files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+        Ok(files.into_iter().take(limit).map(|(_, file)| file).collect())
+    }
+
+    // How full the output filesystem is, and how much of it is captures
+    pub async fn get_storage(State(state): State<RouterState>) -> impl IntoResponse {
+        match Self::storage(&state.config.output_dir) {
+            Ok(storage) => (StatusCode::OK, Json(storage)).into_response(),
+            Err(e) => {
+                log::error!("Unable to report storage: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    fn storage(output_dir: &std::path::Path) -> anyhow::Result<StorageResponse> {
+        let usage = disk::usage(output_dir)?;
+
+        let mut capture_files = 0;
+        let mut capture_bytes = 0;
+        let mut modified_range: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> = None;
+        for entry in std::fs::read_dir(output_dir)? {
+            let entry = entry?;
+            if !is_capture(&entry.path()) {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let modified: chrono::DateTime<chrono::Utc> = metadata.modified()?.into();
+            capture_files += 1;
+            capture_bytes += metadata.len();
+            modified_range = Some(match modified_range {
+                Some((oldest, newest)) => (oldest.min(modified), newest.max(modified)),
+                None => (modified, modified),
+            });
+        }
+
+        Ok(StorageResponse {
+            total_bytes: usage.total,
+            used_bytes: usage.used,
+            free_bytes: usage.free,
+            capture_files,
+            capture_bytes,
+            oldest_modified: modified_range.map(|(oldest, _)| oldest.to_rfc3339()),
+            newest_modified: modified_range.map(|(_, newest)| newest.to_rfc3339()),
+        })
+    }
+}
+
+fn is_capture(path: &std::path::Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("h5") | Some("csv"))
+}
+
+// Splits the samples into points / 2 buckets and keeps each bucket's min and
+// max in the order they occur. points must be even and at most samples.len().
+fn min_max_preview(samples: &[i32], points: usize) -> Vec<i32> {
+    let buckets = points / 2;
+    let mut preview = Vec::with_capacity(points);
+
+    for bucket in 0..buckets {
+        let start = bucket * samples.len() / buckets;
+        let end = (bucket + 1) * samples.len() / buckets;
+        let chunk = &samples[start..end];
+
+        let (mut min, mut max) = (0, 0);
+        for (i, value) in chunk.iter().enumerate() {
+            if *value < chunk[min] {
+                min = i;
+            }
+            if *value > chunk[max] {
+                max = i;
+            }
+        }
+
+        preview.push(chunk[min.min(max)]);
+        preview.push(chunk[min.max(max)]);
+    }
+
+    preview
+}
+
+// Takes the same time wherever the first difference is, so the token can't
+// be guessed byte by byte from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+    use chrono::TimeZone;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::broadcast};
+
+    use super::{min_max_preview, AppState, LocalService, LocalServiceConfig, RouterState};
+    use crate::{metrics::Metrics, services::{ControlMessage, ServiceMessage}, testutil, writer::manifest};
+
+    fn config(output_dir: &Path) -> LocalServiceConfig {
+        LocalServiceConfig {
+            port: 0,
+            node_id: "node".to_string(),
+            health_stale_after: Duration::from_secs(60),
+            output_dir: output_dir.to_path_buf(),
+            recent_frames_capacity: 3,
+            tls_cert_path: None,
+            tls_key_path: None,
+            api_token: None,
+            effective_config: serde_json::json!({ "node_id": "node", "api_token": "***" }),
+        }
+    }
+
+    // Serves the routes on a loopback port. Frames are recorded into the
+    // returned state directly rather than through the broadcast channel.
+    async fn serve(config: LocalServiceConfig) -> (SocketAddr, Arc<Mutex<AppState>>, broadcast::Receiver<ControlMessage>) {
+        serve_with(config, broadcast::channel(4).0).await
+    }
+
+    // As serve, with tx feeding /frames/stream
+    async fn serve_with(config: LocalServiceConfig, tx: broadcast::Sender<ServiceMessage>) -> (SocketAddr, Arc<Mutex<AppState>>, broadcast::Receiver<ControlMessage>) {
+        let app = Arc::new(Mutex::new(AppState {
+            frame: None,
+            node_id: config.node_id.clone(),
+            last_frame_at: None,
+            recent: Default::default(),
+        }));
+        let (control_tx, control_rx) = broadcast::channel(4);
+        let state = RouterState {
+            app: app.clone(),
+            config,
+            metrics: Arc::new(Metrics::new().unwrap()),
+            tx,
+            control_tx,
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, LocalService::router(state)).await.unwrap() });
+        (addr, app, control_rx)
+    }
+
+    // A bare HTTP/1.1 request, returning the status code and body
+    async fn request(addr: SocketAddr, method: &str, path: &str, token: Option<&str>, body: Option<&str>) -> (u16, String) {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, path);
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        if let Some(body) = body {
+            request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body.unwrap_or(""));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> (u16, serde_json::Value) {
+        let (status, body) = request(addr, "GET", path, None, None).await;
+        (status, serde_json::from_str(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn post_comment(comment: &str) -> String {
+        serde_json::json!({ "comment": comment }).to_string()
+    }
+
+    #[tokio::test]
+    async fn health_follows_the_last_frame() {
+        let dir = testutil::temp_dir("local-health");
+        let (addr, app, _control_rx) = serve(config(&dir)).await;
+
+        let (status, health) = get(addr, "/health").await;
+        assert_eq!(status, 503);
+        assert_eq!(health["healthy"], false);
+        assert_eq!(health["seconds_since_last_frame"], serde_json::Value::Null);
+
+        app.lock().unwrap().record("node".to_string(), testutil::frame(1, true, &[1, 2]), 3);
+        let (status, health) = get(addr, "/health").await;
+        assert_eq!(status, 200);
+        assert_eq!(health["healthy"], true);
+        assert_eq!(health["node_id"], "node");
+    }
+
+    #[tokio::test]
+    async fn health_goes_stale() {
+        let dir = testutil::temp_dir("local-health-stale");
+        let mut config = config(&dir);
+        config.health_stale_after = Duration::from_millis(10);
+        let (addr, app, _control_rx) = serve(config).await;
+
+        app.lock().unwrap().record("node".to_string(), testutil::frame(1, true, &[1, 2]), 3);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (status, health) = get(addr, "/health").await;
+        assert_eq!(status, 503);
+        assert!(health["reason"].as_str().unwrap().starts_with("Last frame is stale"));
+    }
+
+    #[tokio::test]
+    async fn serves_health_over_https() {
+        let dir = testutil::temp_dir("local-https");
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+
+        // start() binds the configured port, so find a free one first
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut config = config(&dir);
+        config.port = port;
+        config.tls_cert_path = Some(dir.join("cert.pem"));
+        config.tls_key_path = Some(dir.join("key.pem"));
+        let mut local = LocalService::new(config, Arc::new(Metrics::new().unwrap()), broadcast::channel(4).0, broadcast::channel(4).0);
+        local.start().await.unwrap();
+
+        // Trust only the self-signed certificate
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let tcp = wait_for_listener(addr).await;
+        let mut stream = connector.connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        // The server may close without a TLS close_notify, keep what was read
+        let _ = stream.read_to_end(&mut response).await;
+        let response = String::from_utf8(response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 503"), "{}", head);
+        let health: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(health["node_id"], "node");
+        assert_eq!(health["healthy"], false);
+        local.stop();
+    }
+
+    // The server is bound on a spawned task, so retry until it accepts
+    async fn wait_for_listener(addr: SocketAddr) -> tokio::net::TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = tokio::net::TcpStream::connect(addr).await {
+                return stream;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Nothing is listening on {}", addr);
+    }
+
+    #[tokio::test]
+    async fn serves_the_last_frame() {
+        let dir = testutil::temp_dir("local-frame");
+        let (addr, app, _control_rx) = serve(config(&dir)).await;
+
+        let (status, frame) = get(addr, "/frame").await;
+        assert_eq!(status, 404);
+        assert_eq!(frame["frame"], serde_json::Value::Null);
+
+        app.lock().unwrap().record("node".to_string(), testutil::frame(7, true, &[3, 1, 3, 1]), 3);
+        let (status, frame) = get(addr, "/frame").await;
+        assert_eq!(status, 200);
+        assert_eq!(frame["frame"]["timestamp"], 7);
+        assert_eq!(frame["stats"], serde_json::json!({ "rms": 1.0, "peak_to_peak": 2 }));
+    }
+
+    #[tokio::test]
+    async fn streams_each_frame_as_an_event() {
+        let dir = testutil::temp_dir("local-stream");
+        let tx = broadcast::channel(4).0;
+        let (addr, _app, _control_rx) = serve_with(config(&dir), tx.clone()).await;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /frames/stream HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        // The handler has subscribed once the headers are back
+        let mut received = String::new();
+        let mut buffer = [0u8; 4096];
+        while !received.contains("\r\n\r\n") {
+            let read = stream.read(&mut buffer).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        }
+        assert!(received.starts_with("HTTP/1.1 200"));
+        assert!(received.contains("text/event-stream"));
+
+        for timestamp in [1, 2] {
+            tx.send(ServiceMessage::NewFrame { node_id: "node".to_string(), frame: testutil::frame(timestamp, true, &[1, 2]) }).unwrap();
+        }
+        let events = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let events: Vec<serde_json::Value> = received.lines()
+                    .filter_map(|line| line.strip_prefix("data: "))
+                    // A line still being received doesn't parse yet
+                    .filter_map(|data| serde_json::from_str(data).ok())
+                    .collect();
+                if events.len() == 2 {
+                    return events;
+                }
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.push_str(&String::from_utf8_lossy(&buffer[..read]));
+            }
+        }).await.expect("Two frame events");
+
+        assert_eq!(received.matches("event: frame").count(), 2);
+        assert_eq!(events[0]["frame"]["timestamp"], 1);
+        assert_eq!(events[1]["frame"]["timestamp"], 2);
+        assert_eq!(events[1]["node_id"], "node");
+    }
+
+    #[tokio::test]
+    async fn recent_frames_are_capped_and_oldest_first() {
+        let dir = testutil::temp_dir("local-recent");
+        let (addr, app, _control_rx) = serve(config(&dir)).await;
+
+        for timestamp in 0..5 {
+            app.lock().unwrap().record("node".to_string(), testutil::frame(timestamp, true, &[1]), 3);
+        }
+
+        let timestamps = |frames: serde_json::Value| -> Vec<i64> {
+            frames.as_array().unwrap().iter().map(|frame| frame["frame"]["timestamp"].as_i64().unwrap()).collect()
+        };
+        assert_eq!(timestamps(get(addr, "/frames/recent").await.1), vec![2, 3, 4]);
+        assert_eq!(timestamps(get(addr, "/frames/recent?n=2").await.1), vec![3, 4]);
+        assert_eq!(timestamps(get(addr, "/frames/recent?n=100").await.1), vec![2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn previews_the_last_frame() {
+        let dir = testutil::temp_dir("local-preview");
+        let (addr, app, _control_rx) = serve(config(&dir)).await;
+
+        assert_eq!(get(addr, "/frame/preview").await.0, 404);
+
+        app.lock().unwrap().record("node".to_string(), testutil::frame(1, true, &[0, 5, -3, 2, 7, 1, -8, 4]), 3);
+        let (status, preview) = get(addr, "/frame/preview").await;
+        assert_eq!(status, 200);
+        assert_eq!(preview["samples"], 8);
+        assert_eq!(preview["points"], serde_json::json!([0, 5, -3, 2, 7, 1, -8, 4]));
+
+        let (_, preview) = get(addr, "/frame/preview?points=4").await;
+        assert_eq!(preview["points"], serde_json::json!([5, -3, 7, -8]));
+        assert_eq!(get(addr, "/frame/preview?points=3").await.0, 400);
+        assert_eq!(get(addr, "/frame/preview?points=0").await.0, 400);
+    }
+
+    #[test]
+    fn preview_keeps_each_buckets_extremes_in_order() {
+        assert_eq!(min_max_preview(&[0, 5, -3, 2, 7, 1, -8, 4], 2), vec![7, -8]);
+        assert_eq!(min_max_preview(&[1, 2, 3, 4, 5, 6], 4), vec![1, 3, 4, 6]);
+        // A flat bucket repeats its value
+        assert_eq!(min_max_preview(&[9, 9, 9, 9], 2), vec![9, 9]);
+    }
+
+    #[tokio::test]
+    async fn comments_are_forwarded_on_one_line() {
+        let dir = testutil::temp_dir("local-comment");
+        let (addr, _app, mut control_rx) = serve(config(&dir)).await;
+
+        let (status, _) = request(addr, "POST", "/comment", None, Some(&post_comment("  moved the\nsensor \t"))).await;
+        assert_eq!(status, 202);
+        match control_rx.try_recv() {
+            Ok(ControlMessage::Comment(comment)) => assert_eq!(comment, "moved the sensor"),
+            other => panic!("Expected a comment, got {:?}", other),
+        }
+
+        assert_eq!(request(addr, "POST", "/comment", None, Some(&post_comment(" \n "))).await.0, 400);
+        assert_eq!(request(addr, "POST", "/comment", None, Some(&post_comment(&"x".repeat(1001)))).await.0, 413);
+        assert!(control_rx.try_recv().is_err());
+
+        drop(control_rx);
+        assert_eq!(request(addr, "POST", "/comment", None, Some(&post_comment("nobody listening"))).await.0, 503);
+    }
+
+    #[tokio::test]
+    async fn token_guards_everything_but_health() {
+        let dir = testutil::temp_dir("local-auth");
+        let mut config = config(&dir);
+        config.api_token = Some("secret".to_string());
+        let (addr, _app, _control_rx) = serve(config).await;
+
+        assert_eq!(request(addr, "GET", "/frame", None, None).await.0, 401);
+        assert_eq!(request(addr, "GET", "/frame", Some("guess"), None).await.0, 401);
+        assert_eq!(request(addr, "GET", "/frame", Some("secret"), None).await.0, 404);
+        assert_eq!(request(addr, "POST", "/comment", None, Some(&post_comment("hi"))).await.0, 401);
+        assert_eq!(request(addr, "GET", "/health", None, None).await.0, 503);
+    }
+
+    #[tokio::test]
+    async fn serves_the_effective_config() {
+        let dir = testutil::temp_dir("local-config");
+        let (addr, _app, _control_rx) = serve(config(&dir)).await;
+
+        let (status, body) = get(addr, "/config").await;
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({ "node_id": "node", "api_token": "***" }));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let dir = testutil::temp_dir("local-metrics");
+        let (addr, _app, _control_rx) = serve(config(&dir)).await;
+
+        assert_eq!(request(addr, "GET", "/metrics", None, None).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn lists_capture_files_newest_first() {
+        let dir = testutil::temp_dir("local-files");
+        let older = dir.join("node_older.csv");
+        let newer = dir.join("node_newer.h5");
+        std::fs::write(&older, "gps_time\n").unwrap();
+        std::fs::write(&newer, "not really hdf5").unwrap();
+        let now = SystemTime::now();
+        std::fs::File::options().write(true).open(&older).unwrap().set_modified(now - Duration::from_secs(60)).unwrap();
+        std::fs::File::options().write(true).open(&newer).unwrap().set_modified(now).unwrap();
+        let sha256 = manifest::record(&older, "node", chrono::Utc::now(), 12).unwrap();
+
+        let (status, files) = get(serve(config(&dir)).await.0, "/files").await;
+        assert_eq!(status, 200);
+        let files = files.as_array().unwrap();
+        // The .sha256 and manifest.jsonl aren't capture files
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["filename"], "node_newer.h5");
+        assert_eq!(files[0]["complete"], false);
+        assert_eq!(files[1]["filename"], "node_older.csv");
+        assert_eq!(files[1]["complete"], true);
+        assert_eq!(files[1]["sha256"], sha256);
+        assert_eq!(files[1]["frame_count"], 12);
+        assert_eq!(files[1]["size_bytes"], 9);
+
+        let (_, files) = get(serve(config(&dir)).await.0, "/files?limit=1").await;
+        assert_eq!(files.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_storage_of_the_output_directory() {
+        let dir = testutil::temp_dir("local-storage");
+        let (addr, _, _control_rx) = serve(config(&dir)).await;
+
+        let (status, storage) = get(addr, "/storage").await;
+        assert_eq!(status, 200);
+        assert_eq!(storage["capture_files"], 0);
+        assert_eq!(storage["oldest_modified"], serde_json::Value::Null);
+
+        let older = dir.join("node_older.csv");
+        let newer = dir.join("node_newer.h5");
+        std::fs::write(&older, "gps_time\n").unwrap();
+        std::fs::write(&newer, "not really hdf5").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a capture").unwrap();
+        let older_modified = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let newer_modified = chrono::Utc.with_ymd_and_hms(2024, 3, 2, 12, 0, 0).unwrap();
+        std::fs::File::options().write(true).open(&older).unwrap().set_modified(older_modified.into()).unwrap();
+        std::fs::File::options().write(true).open(&newer).unwrap().set_modified(newer_modified.into()).unwrap();
+
+        let (_, storage) = get(addr, "/storage").await;
+        assert_eq!(storage["capture_files"], 2);
+        assert_eq!(storage["capture_bytes"], 9 + 15);
+        assert_eq!(storage["oldest_modified"], older_modified.to_rfc3339());
+        assert_eq!(storage["newest_modified"], newer_modified.to_rfc3339());
+        let total = storage["total_bytes"].as_u64().unwrap();
+        assert!(total > 0);
+        assert!(storage["used_bytes"].as_u64().unwrap() + storage["free_bytes"].as_u64().unwrap() <= total);
+    }
 }
\ No newline at end of file
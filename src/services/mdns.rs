@@ -0,0 +1,94 @@
+// Advertises the local service over mDNS so a laptop on the same LAN can
+// find every node without knowing their addresses, e.g. with
+// `avahi-browse -r _heartbeat._tcp`
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+pub const SERVICE_TYPE: &str = "_heartbeat._tcp.local.";
+
+// How long stop() waits for the goodbye packets to go out
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct MdnsAdvertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertisement {
+    pub fn start(node_id: &str, port: u16) -> anyhow::Result<MdnsAdvertisement> {
+        Self::start_with(ServiceDaemon::new()?, node_id, port)
+    }
+
+    fn start_with(daemon: ServiceDaemon, node_id: &str, port: u16) -> anyhow::Result<MdnsAdvertisement> {
+        // node_id may contain dots, which would split the host name into labels
+        let host_name = format!("{}.local.", node_id.replace('.', "-"));
+        let properties = [("node_id", node_id)];
+        let service = ServiceInfo::new(SERVICE_TYPE, node_id, &host_name, "", port, &properties[..])?
+            .enable_addr_auto();
+
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+        log::info!("Advertising {} over mDNS on port {}", fullname, port);
+
+        Ok(MdnsAdvertisement { daemon, fullname })
+    }
+
+    // Unregisters the service, which tells browsers the node is gone instead
+    // of leaving them to time it out
+    pub fn stop(self) {
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => {
+                if let Err(e) = status.recv_timeout(UNREGISTER_TIMEOUT) {
+                    log::warn!("No answer unregistering {} from mDNS: {:?}", self.fullname, e);
+                }
+            },
+            Err(e) => log::warn!("Unable to unregister {} from mDNS: {:?}", self.fullname, e),
+        }
+
+        if let Err(e) = self.daemon.shutdown() {
+            log::warn!("Unable to shut down the mDNS daemon: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent};
+
+    use super::{MdnsAdvertisement, SERVICE_TYPE};
+
+    // Loopback is off by default, and the only interface every test machine has
+    fn loopback_daemon() -> ServiceDaemon {
+        let daemon = ServiceDaemon::new().unwrap();
+        daemon.disable_interface(IfKind::All).unwrap();
+        daemon.enable_interface(IfKind::LoopbackV4).unwrap();
+        daemon
+    }
+
+    #[test]
+    fn advertises_the_node_id() {
+        let advertisement = MdnsAdvertisement::start_with(loopback_daemon(), "mdns-test-node", 8767).unwrap();
+
+        let browser = loopback_daemon();
+        let events = browser.browse(SERVICE_TYPE).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut found = None;
+        while found.is_none() && Instant::now() < deadline {
+            if let Ok(ServiceEvent::ServiceResolved(info)) = events.recv_timeout(Duration::from_millis(500)) {
+                if info.get_fullname() == advertisement.fullname {
+                    found = Some(info);
+                }
+            }
+        }
+
+        let info = found.expect("Service wasn't resolved");
+        assert_eq!(info.get_property_val_str("node_id"), Some("mdns-test-node"));
+        assert_eq!(info.get_port(), 8767);
+
+        advertisement.stop();
+        let _ = browser.shutdown();
+    }
+}
@@ -1,7 +1,87 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::metrics::Metrics;
+
+pub mod display;
 pub mod local;
+pub mod mdns;
+pub mod systemd;
 
 #[derive(Debug, Clone)]
 pub enum ServiceMessage {
-    NewFrame(crate::serial::Frame),
+    NewFrame {
+        // node_id of the acquisition loop the frame came from
+        node_id: String,
+        frame: crate::serial::Frame,
+    },
+    // Sent when a node gains or loses its GPS fix, not on every frame
+    GpsFixChanged {
+        node_id: String,
+        has_fix: bool,
+        satellites: u16,
+    },
     Shutdown
-}
\ No newline at end of file
+}
+
+// For the acquisition loops. Kept off the ServiceMessage channel, which
+// carries every frame, so these are neither lost to lag nor wake the loops
+// for each other's frames.
+#[derive(Debug, Clone)]
+pub enum ControlMessage {
+    // Settings re-read from config.toml on SIGHUP
+    Reload(crate::acquisition::ReloadableSettings),
+    // An operator note to add to every node's current file
+    Comment(String),
+}
+
+// A ServiceMessage receiver that counts and logs the messages its subscriber
+// was too slow to see. Only that subscriber misses them, the files on disk
+// are unaffected. Lagged is still returned so callers can react to it.
+pub struct LagCountingReceiver {
+    rx: broadcast::Receiver<ServiceMessage>,
+    // Metric label, e.g. "frame_stream"
+    subscriber: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl LagCountingReceiver {
+    pub fn new(rx: broadcast::Receiver<ServiceMessage>, subscriber: &'static str, metrics: Arc<Metrics>) -> LagCountingReceiver {
+        LagCountingReceiver { rx, subscriber, metrics }
+    }
+
+    pub async fn recv(&mut self) -> Result<ServiceMessage, RecvError> {
+        let result = self.rx.recv().await;
+        if let Err(RecvError::Lagged(skipped)) = &result {
+            log::warn!("{} fell behind the frame broadcast and missed {} messages, the files on disk are unaffected", self.subscriber, skipped);
+            self.metrics.broadcast_lag_events.with_label_values(&[self.subscriber]).inc();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    use super::{LagCountingReceiver, ServiceMessage};
+    use crate::metrics::Metrics;
+
+    #[tokio::test]
+    async fn counts_when_a_slow_subscriber_lags() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (tx, rx) = broadcast::channel(2);
+        let mut rx = LagCountingReceiver::new(rx, "test", metrics.clone());
+
+        for _ in 0..5 {
+            tx.send(ServiceMessage::Shutdown).unwrap();
+        }
+
+        assert!(matches!(rx.recv().await, Err(RecvError::Lagged(3))));
+        assert!(matches!(rx.recv().await, Ok(ServiceMessage::Shutdown)));
+        assert_eq!(metrics.broadcast_lag_events.with_label_values(&["test"]).get(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+// Tells systemd when the process is up and, for units with WatchdogSec=,
+// keeps kicking the watchdog while every node is still producing frames. A
+// node that goes quiet lets the watchdog run out, and systemd restarts the
+// unit.
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use super::{LagCountingReceiver, ServiceMessage};
+
+pub trait Notifier: Send + 'static {
+    fn ready(&mut self) -> anyhow::Result<()>;
+    fn kick(&mut self) -> anyhow::Result<()>;
+}
+
+// Talks to systemd over $NOTIFY_SOCKET, and does nothing when not started by
+// systemd or off Linux
+pub struct SdNotifier;
+
+impl Notifier for SdNotifier {
+    #[cfg(target_os = "linux")]
+    fn ready(&mut self) -> anyhow::Result<()> {
+        Ok(sd_notify::notify(false, &[sd_notify::NotifyState::Ready])?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn ready(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn kick(&mut self) -> anyhow::Result<()> {
+        Ok(sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog])?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn kick(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// The watchdog timeout systemd set for this process, if it set one
+#[cfg(target_os = "linux")]
+pub fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watchdog_timeout() -> Option<Duration> {
+    None
+}
+
+pub struct SystemdWatchdog<N: Notifier> {
+    notifier: N,
+    timeout: Duration,
+    // Starts out as when the watchdog was created, so nodes get one timeout
+    // to deliver their first frame
+    last_frame: HashMap<String, Instant>,
+    // Whether the last tick held back the kick, to log only the first time
+    stale: bool,
+}
+
+impl<N: Notifier> SystemdWatchdog<N> {
+    pub fn new(notifier: N, node_ids: &[String], timeout: Duration) -> SystemdWatchdog<N> {
+        let now = Instant::now();
+        SystemdWatchdog {
+            notifier,
+            timeout,
+            last_frame: node_ids.iter().map(|node_id| (node_id.clone(), now)).collect(),
+            stale: false,
+        }
+    }
+
+    pub fn frame(&mut self, node_id: &str, at: Instant) {
+        if let Some(last_frame) = self.last_frame.get_mut(node_id) {
+            *last_frame = at;
+        }
+    }
+
+    // Kicks the watchdog unless a node has gone a whole timeout without a
+    // frame. Returns whether it kicked.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        let mut quiet: Vec<&str> = self.last_frame.iter()
+            .filter(|(_, last_frame)| now.saturating_duration_since(**last_frame) >= self.timeout)
+            .map(|(node_id, _)| node_id.as_str())
+            .collect();
+
+        if !quiet.is_empty() {
+            if !self.stale {
+                quiet.sort();
+                log::error!("No frames from {} for {:?}, letting the systemd watchdog run out", quiet.join(", "), self.timeout);
+                self.stale = true;
+            }
+            return false;
+        }
+
+        if self.stale {
+            log::info!("Frames are arriving again, kicking the systemd watchdog");
+            self.stale = false;
+        }
+        if let Err(e) = self.notifier.kick() {
+            log::warn!("Unable to kick the systemd watchdog: {:?}", e);
+        }
+        true
+    }
+
+    // Kicks at half the timeout, as systemd recommends, until shutdown
+    pub fn start(mut self, mut rx: LagCountingReceiver) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.timeout / 2);
+            loop {
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Ok(ServiceMessage::NewFrame { node_id, .. }) => self.frame(&node_id, Instant::now()),
+                        Ok(ServiceMessage::Shutdown) | Err(RecvError::Closed) => break,
+                        Ok(_) | Err(RecvError::Lagged(_)) => {},
+                    },
+                    _ = interval.tick() => {
+                        self.tick(Instant::now());
+                    },
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
+
+    use super::{Notifier, SystemdWatchdog};
+
+    #[derive(Clone, Default)]
+    struct CountingNotifier {
+        kicks: Arc<AtomicUsize>,
+    }
+
+    impl Notifier for CountingNotifier {
+        fn ready(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn kick(&mut self) -> anyhow::Result<()> {
+            self.kicks.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn kicks_while_every_node_has_frames() {
+        let notifier = CountingNotifier::default();
+        let nodes = vec!["a".to_string(), "b".to_string()];
+        let timeout = Duration::from_secs(10);
+        let mut watchdog = SystemdWatchdog::new(notifier.clone(), &nodes, timeout);
+        let start = Instant::now();
+
+        // Both nodes still within their first timeout
+        assert!(watchdog.tick(start + Duration::from_secs(5)));
+
+        watchdog.frame("a", start + Duration::from_secs(8));
+        watchdog.frame("b", start + Duration::from_secs(8));
+        assert!(watchdog.tick(start + Duration::from_secs(15)));
+
+        // b has gone quiet
+        watchdog.frame("a", start + Duration::from_secs(16));
+        assert!(!watchdog.tick(start + Duration::from_secs(20)));
+        assert!(!watchdog.tick(start + Duration::from_secs(25)));
+        assert_eq!(notifier.kicks.load(Ordering::Relaxed), 2);
+
+        // And is back
+        watchdog.frame("a", start + Duration::from_secs(26));
+        watchdog.frame("b", start + Duration::from_secs(26));
+        assert!(watchdog.tick(start + Duration::from_secs(27)));
+        assert_eq!(notifier.kicks.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,79 @@
+// Helpers shared by the tests of several modules
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "hdf5")]
+use crate::{serial::SampleType, writer::{hdf5::HDF5WriterConfig, AdcScale, Decimation, FilenameTimezone, FlushPolicy}};
+use crate::serial::Frame;
+
+// A fresh, empty directory under the system temp dir, unique to the test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("heartbeat-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Unable to create test directory");
+    dir
+}
+
+// A text frame as the device sends it, with a valid sum checksum
+pub fn frame_line(timestamp: i64, has_fix: bool, samples: &[i32]) -> String {
+    let mut line = format!("${},{},1000,52.1,4.3,10,7,0,0,{}", timestamp, if has_fix { "G" } else { "-" }, samples.len());
+    for sample in samples {
+        line.push_str(&format!(",{}", sample));
+    }
+    line.push_str(&format!(",{}", samples.iter().map(|sample| *sample as i64).sum::<i64>()));
+    line
+}
+
+pub fn frame(timestamp: i64, has_fix: bool, samples: &[i32]) -> Frame {
+    Frame::parse(&frame_line(timestamp, has_fix, samples)).expect("Test frame doesn't parse")
+}
+
+#[cfg(feature = "hdf5")]
+pub fn hdf5_config(node_id: &str, output_path: &Path, samples_per_frame: usize) -> HDF5WriterConfig {
+    HDF5WriterConfig {
+        node_id: node_id.to_string(),
+        output_path: output_path.to_path_buf(),
+        gzip_level: 4,
+        samples_chunk_rows: 16,
+        comments_gzip_level: 4,
+        samples_per_frame,
+        pad_short_frames: false,
+        decimation: Decimation::default(),
+        adc_scale: AdcScale { bits: 12, vref: 3.3 },
+        sample_type: SampleType::I16,
+        flush: FlushPolicy::default(),
+        filename_timezone: FilenameTimezone::default(),
+    }
+}
+
+// Files in `dir` with the given extension, sorted by name
+pub fn files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir).expect("Unable to list test directory")
+        .map(|entry| entry.expect("Unable to read test directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect();
+    files.sort();
+    files
+}
+
+// The data rows of a CSV capture file, without the header and comments
+pub fn csv_rows(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path).expect("Unable to read CSV file")
+        .lines()
+        .skip(1)
+        .filter(|line| !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+// Files in `dir`'s manifest whose recorded sha256 no longer matches the file
+pub fn stale_manifest_entries(dir: &Path) -> Vec<String> {
+    use sha2::{Digest, Sha256};
+
+    crate::writer::manifest::entries(dir).expect("Unable to read manifest")
+        .into_iter()
+        .filter(|entry| {
+            let contents = std::fs::read(dir.join(&entry.filename)).unwrap_or_default();
+            format!("{:x}", Sha256::digest(contents)) != entry.sha256
+        })
+        .map(|entry| entry.filename)
+        .collect()
+}
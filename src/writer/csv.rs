@@ -0,0 +1,244 @@
+use std::{fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+
+use super::{Decimation, FilenameTimezone, FlushPolicy, FlushTimer, Writer, RESUMED_COMMENT};
+
+#[derive(Clone)]
+pub struct CsvWriterConfig {
+    pub node_id: String,
+    pub output_path: PathBuf,
+    pub samples_per_frame: usize,
+    pub decimation: Decimation,
+    pub flush: FlushPolicy,
+    pub filename_timezone: FilenameTimezone,
+}
+
+pub struct CsvWriter {
+    node_id: String,
+    file_path: PathBuf,
+    created: chrono::DateTime<Utc>,
+    file: BufWriter<File>,
+    decimation: Decimation,
+    flush: FlushTimer,
+    index: usize
+}
+
+// Columns are named after the sample's position in the original frame
+fn header(config: &CsvWriterConfig) -> String {
+    let mut header = String::from("gps_time,cpu_time,latitude,longitude,elevation,satellites");
+    for i in 0..config.decimation.width(config.samples_per_frame) {
+        header.push_str(&format!(",sample_{}", i * config.decimation.factor));
+    }
+    header
+}
+
+impl CsvWriter {
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    // Sends later writes somewhere else, e.g. /dev/full to make them fail
+    #[cfg(test)]
+    pub fn redirect(&mut self, path: &Path) {
+        self.file = BufWriter::new(File::options().write(true).open(path).expect("Unable to open redirect target"));
+    }
+}
+
+impl Writer for CsvWriter {
+    type Config = CsvWriterConfig;
+
+    fn new(config: CsvWriterConfig) -> anyhow::Result<CsvWriter> {
+        config.decimation.check()?;
+        config.flush.check()?;
+
+        let created = chrono::Utc::now();
+        let (file_path, file) = config.filename_timezone.create_file(&config.output_path, &config.node_id, created, "csv")?;
+        let mut file = BufWriter::new(file);
+        writeln!(file, "{}", header(&config))?;
+
+        Ok(CsvWriter {
+            node_id: config.node_id,
+            file_path,
+            created,
+            file,
+            decimation: config.decimation,
+            flush: FlushTimer::new(config.flush),
+            index: 0
+        })
+    }
+
+    fn resume(config: CsvWriterConfig, file_path: &Path, created: DateTime<Utc>) -> anyhow::Result<CsvWriter> {
+        config.decimation.check()?;
+        config.flush.check()?;
+
+        // A crash can cut the last row short, that one is dropped
+        let contents = std::fs::read(file_path)?;
+        let end = contents.iter().rposition(|byte| *byte == b'\n').map_or(0, |i| i + 1);
+        let mut lines = std::str::from_utf8(&contents[..end])?.lines();
+        if lines.next() != Some(header(&config).as_str()) {
+            return Err(anyhow::anyhow!("{} has different columns", file_path.display()));
+        }
+        let index = lines.filter(|line| !line.starts_with('#')).count();
+
+        let file = File::options().append(true).open(file_path)?;
+        file.set_len(end as u64)?;
+        let mut file = BufWriter::new(file);
+        writeln!(file, "# {}", RESUMED_COMMENT)?;
+
+        Ok(CsvWriter {
+            node_id: config.node_id,
+            file_path: file_path.to_path_buf(),
+            created,
+            file,
+            decimation: config.decimation,
+            flush: FlushTimer::new(config.flush),
+            index
+        })
+    }
+
+    fn close(mut self) -> anyhow::Result<PathBuf> {
+        self.file.flush()?;
+        drop(self.file);
+
+        if let Err(e) = super::manifest::record(&self.file_path, &self.node_id, self.created, self.index) {
+            log::error!("Unable to record {} in the manifest: {:?}", self.file_path.display(), e);
+        }
+
+        Ok(self.file_path)
+    }
+
+    async fn write_frame(&mut self, when: chrono::DateTime<Utc>, frame: &crate::serial::Frame) -> anyhow::Result<()> {
+        log::debug!("Writing frame to CSV file at index: {}", self.index);
+
+        let gps_time = match frame.timestamp() {
+            Some(timestamp) => timestamp.to_string(),
+            None => String::new(),
+        };
+
+        let mut row = format!("{},{},{},{},{},{}",
+            gps_time,
+            when.timestamp(),
+            frame.latitude(),
+            frame.longitude(),
+            frame.elevation(),
+            frame.satellite_count());
+
+        for sample in self.decimation.apply(&frame.samples()) {
+            row.push_str(&format!(",{}", sample));
+        }
+
+        writeln!(self.file, "{}", row)?;
+        if self.flush.frame_written() {
+            self.file.flush()?;
+        }
+
+        self.index += 1;
+
+        Ok(())
+    }
+
+    async fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
+        writeln!(self.file, "# {}", comment.trim_end())?;
+        Ok(())
+    }
+
+    // CSV has nowhere else to put them, so GPS sentences stay comments
+    async fn write_gps(&mut self, _when: chrono::DateTime<Utc>, record: &crate::serial::GpsRecord) -> anyhow::Result<()> {
+        writeln!(self.file, "# {}", record.sentence)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::TimeZone;
+
+    use super::{CsvWriter, CsvWriterConfig};
+    use crate::{serial::nmea, testutil, writer::{manifest, Decimation, DecimationMode, FilenameTimezone, FlushPolicy, Writer}};
+
+    fn config(dir: &Path, samples_per_frame: usize, decimation: Decimation) -> CsvWriterConfig {
+        CsvWriterConfig {
+            node_id: "node".to_string(),
+            output_path: dir.to_path_buf(),
+            samples_per_frame,
+            decimation,
+            flush: FlushPolicy::default(),
+            filename_timezone: FilenameTimezone::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_a_header_and_a_row_per_frame() {
+        let dir = testutil::temp_dir("csv-rows");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut writer = CsvWriter::new(config(&dir, 3, Decimation::default())).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294400, true, &[1, 2, 3])).await.unwrap();
+        writer.write_comment("battery low\n").await.unwrap();
+        let untimed = crate::serial::Frame::parse(&testutil::frame_line(0, false, &[4, 5, 6]).replacen("$0,", "$,", 1)).unwrap();
+        writer.write_frame(when, &untimed).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines, vec![
+            "gps_time,cpu_time,latitude,longitude,elevation,satellites,sample_0,sample_1,sample_2",
+            "1709294400,1709294400,52.1,4.3,10,7,1,2,3",
+            "# battery low",
+            // Without GPS time the field stays empty
+            ",1709294400,52.1,4.3,10,7,4,5,6",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn names_columns_after_the_original_sample() {
+        let dir = testutil::temp_dir("csv-decimation");
+
+        let mut writer = CsvWriter::new(config(&dir, 5, Decimation { factor: 2, mode: DecimationMode::Pick })).unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[10, 11, 12, 13, 14])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("gps_time,cpu_time,latitude,longitude,elevation,satellites,sample_0,sample_2,sample_4\n"));
+        assert!(testutil::csv_rows(&path)[0].ends_with(",10,12,14"));
+    }
+
+    #[tokio::test]
+    async fn keeps_gps_sentences_as_comments() {
+        let dir = testutil::temp_dir("csv-gps");
+        let sentence = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+
+        let mut writer = CsvWriter::new(config(&dir, 1, Decimation::default())).unwrap();
+        writer.write_gps(chrono::Utc::now(), &nmea::parse(sentence).unwrap()).await.unwrap();
+        let path = writer.close().unwrap();
+
+        assert!(std::fs::read_to_string(&path).unwrap().contains(&format!("\n# {}\n", sentence)));
+    }
+
+    #[tokio::test]
+    async fn close_records_the_file_in_the_manifest() {
+        let dir = testutil::temp_dir("csv-manifest");
+
+        let mut writer = CsvWriter::new(config(&dir, 1, Decimation::default())).unwrap();
+        for i in 0..3 {
+            writer.write_frame(chrono::Utc::now(), &testutil::frame(i, true, &[i as i32])).await.unwrap();
+        }
+        let path = writer.close().unwrap();
+
+        let entries = manifest::entries(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, path.file_name().unwrap().to_string_lossy());
+        assert_eq!(entries[0].node_id, "node");
+        assert_eq!(entries[0].frame_count, 3);
+    }
+
+    #[test]
+    fn rejects_a_zero_decimation_factor() {
+        let dir = testutil::temp_dir("csv-bad-config");
+        assert!(CsvWriter::new(config(&dir, 1, Decimation { factor: 0, mode: DecimationMode::Pick })).is_err());
+        assert!(testutil::files_with_extension(&dir, "csv").is_empty());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,180 @@
+// Keeps the last few frames in memory so that when something interesting
+// happens, the frames leading up to it can be saved along with the ones
+// after it in a file of their own
+use std::{collections::VecDeque, path::PathBuf};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use super::{OutputWriter, OutputWriterConfig, Writer};
+use crate::serial::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTrigger {
+    // More samples at the ADC rails than clip_threshold allows
+    Clipping,
+    // The GPS fix was acquired or lost
+    GpsFix,
+    // A jump in the GPS timestamps, see gap_tolerance_secs
+    FrameGap,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventConfig {
+    pub triggers: Vec<EventTrigger>,
+    // Frames kept from before the trigger, and written after it
+    pub pre_frames: usize,
+    pub post_frames: usize,
+}
+
+pub struct EventRecorder {
+    pre_frames: usize,
+    post_frames: usize,
+    writer_config: OutputWriterConfig,
+    buffer: VecDeque<(chrono::DateTime<Utc>, Frame)>,
+    // Set by trigger(), taken by the next frame pushed
+    pending: Option<String>,
+    // The open event file, and how many more frames go into it
+    current: Option<(OutputWriter, usize)>,
+}
+
+impl EventRecorder {
+    // Event files are written with `writer_config`, which should point
+    // somewhere other than the regular files
+    pub fn new(config: &EventConfig, writer_config: OutputWriterConfig) -> EventRecorder {
+        EventRecorder {
+            pre_frames: config.pre_frames,
+            post_frames: config.post_frames,
+            writer_config,
+            buffer: VecDeque::with_capacity(config.pre_frames),
+            pending: None,
+            current: None,
+        }
+    }
+
+    // Applies from the next event file on
+    pub fn set_gzip_level(&mut self, gzip_level: i8) {
+        self.writer_config.set_gzip_level(gzip_level);
+    }
+
+    // Starts an event file with the next frame pushed, or keeps the open one
+    // going for another post_frames frames
+    pub fn trigger(&mut self, reason: String) {
+        self.pending = Some(reason);
+    }
+
+    // Returns the path of the event file if this frame finished it
+    pub async fn push(&mut self, when: chrono::DateTime<Utc>, frame: &Frame) -> anyhow::Result<Option<PathBuf>> {
+        if let Some(reason) = self.pending.take() {
+            match &mut self.current {
+                Some((writer, remaining)) => {
+                    writer.write_comment(&format!("Event: {}", reason)).await?;
+                    *remaining = self.post_frames + 1;
+                },
+                None => {
+                    std::fs::create_dir_all(self.writer_config.output_path())?;
+                    let mut writer = OutputWriter::new(self.writer_config.clone())?;
+                    log::info!("Event ({}), saving {} earlier frames to {}", reason, self.buffer.len(), writer.file_path().display());
+                    writer.write_comment(&format!("Event: {}, {} frames before it", reason, self.buffer.len())).await?;
+                    for (when, frame) in self.buffer.drain(..) {
+                        writer.write_frame(when, &frame).await?;
+                    }
+                    // The frame that triggered it plus the ones after
+                    self.current = Some((writer, self.post_frames + 1));
+                }
+            }
+        }
+
+        match self.current.take() {
+            Some((mut writer, remaining)) => {
+                writer.write_frame(when, frame).await?;
+                if remaining > 1 {
+                    self.current = Some((writer, remaining - 1));
+                } else {
+                    return Ok(Some(writer.close()?));
+                }
+            },
+            None if self.pre_frames > 0 => {
+                if self.buffer.len() == self.pre_frames {
+                    self.buffer.pop_front();
+                }
+                self.buffer.push_back((when, frame.clone()));
+            },
+            None => {}
+        }
+
+        Ok(None)
+    }
+
+    // Finishes an event file cut short by shutdown
+    pub fn close(self) -> anyhow::Result<Option<PathBuf>> {
+        match self.current {
+            Some((writer, _)) => Ok(Some(writer.close()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::Utc;
+
+    use super::{EventConfig, EventRecorder, EventTrigger};
+    use crate::{testutil, writer::{csv::CsvWriterConfig, Decimation, FilenameTimezone, FlushPolicy, OutputWriterConfig}};
+
+    fn recorder(dir: &Path, pre_frames: usize, post_frames: usize) -> EventRecorder {
+        let config = EventConfig { triggers: vec![EventTrigger::Clipping], pre_frames, post_frames };
+        EventRecorder::new(&config, OutputWriterConfig::Csv(CsvWriterConfig {
+            node_id: "node".to_string(),
+            output_path: dir.join("events"),
+            samples_per_frame: 1,
+            decimation: Decimation::default(),
+            flush: FlushPolicy::default(),
+            filename_timezone: FilenameTimezone::default(),
+        }))
+    }
+
+    fn gps_times(path: &Path) -> Vec<String> {
+        testutil::csv_rows(path).iter().map(|row| row.split(',').next().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn saves_the_pre_roll_and_the_frames_after_a_trigger() {
+        let dir = testutil::temp_dir("event-recorder");
+        let mut recorder = recorder(&dir, 2, 2);
+
+        for timestamp in 1..=4 {
+            assert_eq!(recorder.push(Utc::now(), &testutil::frame(timestamp, true, &[0])).await.unwrap(), None);
+        }
+        recorder.trigger("clipping".to_string());
+        assert_eq!(recorder.push(Utc::now(), &testutil::frame(5, true, &[0])).await.unwrap(), None);
+        assert_eq!(recorder.push(Utc::now(), &testutil::frame(6, true, &[0])).await.unwrap(), None);
+        let path = recorder.push(Utc::now(), &testutil::frame(7, true, &[0])).await.unwrap().expect("Event file wasn't finished");
+
+        assert_eq!(path.parent(), Some(dir.join("events").as_path()));
+        assert_eq!(gps_times(&path), vec!["3", "4", "5", "6", "7"]);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("# Event: clipping, 2 frames before it"));
+
+        // Back to buffering once the event is over
+        assert_eq!(recorder.push(Utc::now(), &testutil::frame(8, true, &[0])).await.unwrap(), None);
+        assert_eq!(recorder.close().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_trigger_during_an_event_extends_it() {
+        let dir = testutil::temp_dir("event-recorder-extend");
+        let mut recorder = recorder(&dir, 0, 1);
+
+        recorder.trigger("clipping".to_string());
+        assert_eq!(recorder.push(Utc::now(), &testutil::frame(1, true, &[0])).await.unwrap(), None);
+        recorder.trigger("clipping".to_string());
+        assert_eq!(recorder.push(Utc::now(), &testutil::frame(2, true, &[0])).await.unwrap(), None);
+        let path = recorder.push(Utc::now(), &testutil::frame(3, true, &[0])).await.unwrap().expect("Event file wasn't finished");
+
+        assert_eq!(gps_times(&path), vec!["1", "2", "3"]);
+    }
+}
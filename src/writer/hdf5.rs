@@ -1,10 +1,12 @@
 use std::{path::{Path, PathBuf}, str::FromStr};
 
 use chrono::Utc;
-use hdf5::types::{FixedUnicode, VarLenUnicode};
-use ndarray::{arr2, s, Array2, Array1};
+use hdf5::types::VarLenUnicode;
+use ndarray::Array1;
 
-use super::Writer;
+use crate::serial::SampleType;
+
+use super::{sidecar::Sidecar, AdcScale, Decimation, FilenameTimezone, FlushPolicy, FlushTimer, Rejected, Writer, RESUMED_COMMENT};
 
 #[macro_export]
 macro_rules! a_dataset {
@@ -16,53 +18,408 @@ macro_rules! a_dataset {
     };
 }
 
+// Written to gps_time for frames without a GPS timestamp
+const MISSING_GPS_TIME: i64 = -1;
+// Written to gps/satellites when the sentence didn't include it
+const MISSING_SATELLITES: i8 = -1;
+// Written to time_skew for frames without a GPS timestamp
+const MISSING_TIME_SKEW: i64 = i64::MIN;
+// The cpu_time datasets hold Unix time in milliseconds. Files from before
+// this have seconds and no UNITS attribute on them.
+pub const CPU_TIME_UNITS: &str = "ms";
+
+// Running mean and standard deviation of the CPU - GPS time skew, using
+// Welford's method so a long file doesn't need every value kept around
+#[derive(Debug, Default)]
+struct SkewStats {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl SkewStats {
+    fn add(&mut self, skew: i64) {
+        self.count += 1;
+        let delta = skew as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (skew as f64 - self.mean);
+    }
+
+    // NaN until a frame with a GPS timestamp was written
+    fn mean(&self) -> f64 {
+        if self.count == 0 { f64::NAN } else { self.mean }
+    }
+
+    // Population standard deviation
+    fn stddev(&self) -> f64 {
+        if self.count == 0 { f64::NAN } else { (self.m2 / self.count as f64).sqrt() }
+    }
+}
+
 #[derive(Clone)]
 pub struct HDF5WriterConfig {
     pub node_id: String,
     pub output_path: PathBuf,
-    pub gzip_level: i8
+    pub gzip_level: i8,
+    // Frames per chunk of the samples dataset. Larger chunks compress better
+    // and make reading many frames at once faster.
+    pub samples_chunk_rows: usize,
+    pub comments_gzip_level: u8,
+    pub samples_per_frame: usize,
+    pub pad_short_frames: bool,
+    pub decimation: Decimation,
+    pub adc_scale: AdcScale,
+    // Element type of the samples dataset
+    pub sample_type: SampleType,
+    pub flush: FlushPolicy,
+    pub filename_timezone: FilenameTimezone,
 }
 
 pub struct HDF5Writer {
-    output_path: PathBuf,
+    node_id: String,
+    file_path: PathBuf,
+    created: chrono::DateTime<Utc>,
     file: hdf5::File,
     ds_gps_time: hdf5::Dataset,
     ds_cpu_time: hdf5::Dataset,
+    ds_time_skew: hdf5::Dataset,
     ds_latitude: hdf5::Dataset,
     ds_longitude: hdf5::Dataset,
     ds_elevation: hdf5::Dataset,
     ds_satellites: hdf5::Dataset,
+    ds_speed: hdf5::Dataset,
+    ds_angle: hdf5::Dataset,
     ds_comments: hdf5::Dataset,
     data_set_samples: hdf5::Dataset,
     ds_gps_fix: hdf5::Dataset,
     ds_clipping: hdf5::Dataset,
+    ds_valid: hdf5::Dataset,
+    gps: GpsDatasets,
+    summary: SummaryDatasets,
+    samples_per_frame: usize,
+    pad_short_frames: bool,
+    decimation: Decimation,
+    sample_type: SampleType,
+    flush: FlushTimer,
+    sample_rate: Option<f32>,
+    skew: SkewStats,
+    sidecar: Sidecar,
     index: usize
 }
 
+// The gps group, one row per GGA or RMC sentence from the device. Fields a
+// sentence doesn't carry are NaN, or -1 for satellites.
+struct GpsDatasets {
+    cpu_time: hdf5::Dataset,
+    time: hdf5::Dataset,
+    latitude: hdf5::Dataset,
+    longitude: hdf5::Dataset,
+    altitude: hdf5::Dataset,
+    speed: hdf5::Dataset,
+    course: hdf5::Dataset,
+    satellites: hdf5::Dataset,
+    sentence: hdf5::Dataset,
+    index: usize,
+}
+
+impl GpsDatasets {
+    fn create(file: &hdf5::File) -> anyhow::Result<()> {
+        let group = file.create_group("gps")?;
+        let cpu_time = a_dataset!(group, "cpu_time", i64, [0..], 1);
+        set_units(&cpu_time, CPU_TIME_UNITS)?;
+
+        a_dataset!(group, "time", f64, [0..], 1);
+        a_dataset!(group, "latitude", f32, [0..], 1);
+        a_dataset!(group, "longitude", f32, [0..], 1);
+        a_dataset!(group, "altitude", f32, [0..], 1);
+        a_dataset!(group, "speed", f32, [0..], 1);
+        a_dataset!(group, "course", f32, [0..], 1);
+        a_dataset!(group, "satellites", i8, [0..], 1);
+        a_dataset!(group, "sentence", VarLenUnicode, [0..], 1);
+        Ok(())
+    }
+
+    // Continues after the last row all of them have
+    fn open(file: &hdf5::File) -> anyhow::Result<GpsDatasets> {
+        let group = file.group("gps")?;
+        let mut gps = GpsDatasets {
+            cpu_time: group.dataset("cpu_time")?,
+            time: group.dataset("time")?,
+            latitude: group.dataset("latitude")?,
+            longitude: group.dataset("longitude")?,
+            altitude: group.dataset("altitude")?,
+            speed: group.dataset("speed")?,
+            course: group.dataset("course")?,
+            satellites: group.dataset("satellites")?,
+            sentence: group.dataset("sentence")?,
+            index: 0,
+        };
+        gps.index = [&gps.cpu_time, &gps.time, &gps.latitude, &gps.longitude, &gps.altitude, &gps.speed, &gps.course, &gps.satellites, &gps.sentence]
+            .iter()
+            .map(|dataset| dataset.size())
+            .min()
+            .unwrap_or(0);
+        Ok(gps)
+    }
+
+    fn write(&mut self, when: chrono::DateTime<Utc>, record: &crate::serial::GpsRecord) -> anyhow::Result<()> {
+        let sentence = VarLenUnicode::from_str(&record.sentence).map_err(|e| Rejected(format!("Invalid GPS sentence: {:?}", e)))?;
+
+        append(&self.cpu_time, self.index, when.timestamp_millis())?;
+        append(&self.time, self.index, record.time.unwrap_or(f64::NAN))?;
+        append(&self.latitude, self.index, record.latitude.unwrap_or(f32::NAN))?;
+        append(&self.longitude, self.index, record.longitude.unwrap_or(f32::NAN))?;
+        append(&self.altitude, self.index, record.altitude.unwrap_or(f32::NAN))?;
+        append(&self.speed, self.index, record.speed.unwrap_or(f32::NAN))?;
+        append(&self.course, self.index, record.course.unwrap_or(f32::NAN))?;
+        append(&self.satellites, self.index, record.satellites.map_or(MISSING_SATELLITES, |s| s as i8))?;
+        append(&self.sentence, self.index, sentence)?;
+
+        self.index += 1;
+        Ok(())
+    }
+}
+
+// The summary group, one row per frame with the envelope of its samples, so
+// viewers can plot a whole file without decompressing the samples. Taken
+// from the frame as received, before padding or decimation.
+struct SummaryDatasets {
+    min: hdf5::Dataset,
+    max: hdf5::Dataset,
+    // About the frame's mean, as in SignalStats
+    rms: hdf5::Dataset,
+}
+
+impl SummaryDatasets {
+    fn create(file: &hdf5::File) -> anyhow::Result<()> {
+        let group = file.create_group("summary")?;
+        a_dataset!(group, "min", i32, [0..], 1);
+        a_dataset!(group, "max", i32, [0..], 1);
+        a_dataset!(group, "rms", f32, [0..], 1);
+        Ok(())
+    }
+
+    fn open(file: &hdf5::File) -> anyhow::Result<SummaryDatasets> {
+        let group = file.group("summary")?;
+
+        Ok(SummaryDatasets {
+            min: group.dataset("min")?,
+            max: group.dataset("max")?,
+            rms: group.dataset("rms")?,
+        })
+    }
+
+    // Frames with a complete summary row
+    fn rows(&self) -> usize {
+        self.min.size().min(self.max.size()).min(self.rms.size())
+    }
+
+    fn write(&self, index: usize, frame: &crate::serial::Frame) -> anyhow::Result<()> {
+        let samples = frame.samples();
+        append(&self.min, index, samples.iter().copied().min().unwrap_or(0))?;
+        append(&self.max, index, samples.iter().copied().max().unwrap_or(0))?;
+        append(&self.rms, index, frame.signal_stats().rms)?;
+        Ok(())
+    }
+}
+
+// Records the unit of a dataset's values in its UNITS attribute
+fn set_units(dataset: &hdf5::Dataset, units: &str) -> anyhow::Result<()> {
+    let attr = dataset.new_attr::<VarLenUnicode>().create("UNITS")?;
+    attr.write_scalar(&VarLenUnicode::from_str(units).unwrap())?;
+    Ok(())
+}
+
+fn append<T: hdf5::H5Type>(dataset: &hdf5::Dataset, index: usize, value: T) -> anyhow::Result<()> {
+    dataset.resize([index + 1])?;
+    dataset.write_slice(&[value], &[index])?;
+    Ok(())
+}
 
 impl HDF5Writer {
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    // Picks up the datasets of a file laid out by new(), continuing after the
+    // last row all the per-frame datasets have
+    fn open(config: HDF5WriterConfig, file: hdf5::File, file_path: PathBuf, created: chrono::DateTime<Utc>) -> anyhow::Result<HDF5Writer> {
+        let width = config.decimation.width(config.samples_per_frame);
+        let data_set_samples = file.dataset("samples")?;
+        let shape = data_set_samples.shape();
+        if shape.get(1) != Some(&width) {
+            return Err(anyhow::anyhow!("{} has {:?} samples per frame, not {}", file_path.display(), shape.get(1), width));
+        }
+        let stored = match config.sample_type {
+            SampleType::I16 => data_set_samples.dtype()?.is::<i16>(),
+            SampleType::I32 => data_set_samples.dtype()?.is::<i32>(),
+        };
+        if !stored {
+            return Err(anyhow::anyhow!("{} doesn't hold {:?} samples", file_path.display(), config.sample_type));
+        }
+
+        let ds_cpu_time = file.dataset("cpu_time")?;
+        // Files from before cpu_time had milliseconds can't be continued
+        if ds_cpu_time.attr("UNITS")?.read_scalar::<VarLenUnicode>()?.as_str() != CPU_TIME_UNITS {
+            return Err(anyhow::anyhow!("{} keeps cpu_time in other units", file_path.display()));
+        }
+
+        let ds_gps_time = file.dataset("gps_time")?;
+        let ds_time_skew = file.dataset("time_skew")?;
+        let ds_latitude = file.dataset("latitude")?;
+        let ds_longitude = file.dataset("longitude")?;
+        let ds_elevation = file.dataset("elevation")?;
+        let ds_satellites = file.dataset("satellites")?;
+        let ds_speed = file.dataset("speed")?;
+        let ds_angle = file.dataset("angle")?;
+        let ds_gps_fix = file.dataset("gps_fix")?;
+        let ds_clipping = file.dataset("clipping")?;
+        let ds_valid = file.dataset("valid")?;
+        let summary = SummaryDatasets::open(&file)?;
+
+        // Each dataset is grown just before its value is written, so a crash
+        // mid-frame leaves some a row longer. That row is written over.
+        let index = [&ds_gps_time, &ds_cpu_time, &ds_time_skew, &ds_latitude, &ds_longitude, &ds_elevation, &ds_satellites, &ds_speed, &ds_angle, &ds_gps_fix, &ds_clipping, &ds_valid]
+            .iter()
+            .map(|dataset| dataset.size())
+            .chain([shape[0], summary.rows()])
+            .min()
+            .unwrap_or(0);
+
+        let sample_rate = match file.attr_names()?.iter().any(|name| name == "SAMPLE_RATE") {
+            true => Some(file.attr("SAMPLE_RATE")?.read_scalar::<f32>()?),
+            false => None,
+        };
+
+        // Rebuilt from the rows already in the file, so they describe all of it
+        let mut skew = SkewStats::default();
+        let mut sidecar = Sidecar::new(&config.node_id, config.gzip_level);
+        if index > 0 {
+            let gps_time = ds_gps_time.read_raw::<i64>()?;
+            let cpu_time = ds_cpu_time.read_raw::<i64>()?;
+            let latitude = ds_latitude.read_raw::<f32>()?;
+            let longitude = ds_longitude.read_raw::<f32>()?;
+            let gps_fix = ds_gps_fix.read_raw::<bool>()?;
+            for time_skew in ds_time_skew.read_raw::<i64>()?.into_iter().take(index) {
+                if time_skew != MISSING_TIME_SKEW {
+                    skew.add(time_skew);
+                }
+            }
+            for i in 0..index {
+                sidecar.add_row(
+                    sample_rate,
+                    cpu_time[i].div_euclid(1000),
+                    (gps_time[i] != MISSING_GPS_TIME).then_some(gps_time[i]),
+                    gps_fix[i].then_some((latitude[i], longitude[i])));
+            }
+        }
 
+        Ok(HDF5Writer {
+            node_id: config.node_id,
+            file_path,
+            created,
+            ds_gps_time,
+            ds_cpu_time,
+            ds_time_skew,
+            ds_latitude,
+            ds_longitude,
+            ds_elevation,
+            ds_satellites,
+            ds_speed,
+            ds_angle,
+            ds_comments: file.dataset("comments")?,
+            data_set_samples,
+            ds_gps_fix,
+            ds_clipping,
+            ds_valid,
+            gps: GpsDatasets::open(&file)?,
+            summary,
+            file,
+            samples_per_frame: config.samples_per_frame,
+            pad_short_frames: config.pad_short_frames,
+            decimation: config.decimation,
+            sample_type: config.sample_type,
+            flush: FlushTimer::new(config.flush),
+            sample_rate,
+            skew,
+            sidecar,
+            index
+        })
+    }
 }
 
-impl Writer<HDF5WriterConfig> for HDF5Writer {
+impl Writer for HDF5Writer {
+    type Config = HDF5WriterConfig;
+
     async fn write_frame(&mut self, when: chrono::DateTime<Utc>, frame: &crate::serial::Frame) -> anyhow::Result<()> {
         log::debug!("Writing frame to HDF5 file at index: {}", self.index);
 
+        // Validate the sample count before touching any dataset so a bad frame
+        // can't leave the per-frame datasets misaligned
+        let mut samples = frame.samples();
+        // 1 if the checksum passed and no samples had to be padded
+        let valid = frame.metadata().checksum_ok() && samples.len() == self.samples_per_frame;
+        if samples.len() != self.samples_per_frame {
+            if samples.len() < self.samples_per_frame && self.pad_short_frames {
+                log::warn!("Padding short frame with {} samples to {}", samples.len(), self.samples_per_frame);
+                samples.resize(self.samples_per_frame, 0);
+            } else {
+                return Err(Rejected(format!("Frame has {} samples, expected {}", samples.len(), self.samples_per_frame)).into());
+            }
+        }
+
+        let samples = self.decimation.apply(&samples);
+
+        // HDF5 would silently truncate samples that don't fit the dataset
+        if let Some(value) = samples.iter().find(|value| !self.sample_type.contains(**value)) {
+            return Err(Rejected(format!("Sample {} doesn't fit in the {:?} samples dataset", value, self.sample_type)).into());
+        }
+
+        // The sample rate is stored once per file, taken from the first frame
+        match self.sample_rate {
+            None => {
+                let attr = self.file.new_attr::<f32>().create("SAMPLE_RATE")?;
+                attr.write_scalar(&frame.sample_rate())?;
+                let attr = self.file.new_attr::<f32>().create("EFFECTIVE_SAMPLE_RATE")?;
+                attr.write_scalar(&(frame.sample_rate() / self.decimation.factor as f32))?;
+                self.sample_rate = Some(frame.sample_rate());
+            },
+            Some(sample_rate) if sample_rate != frame.sample_rate() => {
+                log::warn!("Sample rate changed mid-file from {} to {}, SAMPLE_RATE attribute keeps {}", sample_rate, frame.sample_rate(), sample_rate);
+            },
+            Some(_) => {}
+        }
+
         // Resize the dataset to fit the new data
         self.ds_gps_time.resize([self.index + 1])?;
 
         // Write the new data
         self.ds_gps_time.write_slice(
-            &[frame.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?],
+            &[frame.timestamp().unwrap_or(MISSING_GPS_TIME)],
             &[self.index]
         )?;
 
         self.ds_cpu_time.resize([self.index + 1])?;
         self.ds_cpu_time.write_slice(
-            &[when.timestamp()],
+            &[when.timestamp_millis()],
             &[self.index]
         )?;
 
+        // CPU minus GPS time in milliseconds. The GPS time only has whole
+        // seconds, so the skew includes where in that second the frame arrived.
+        // Zero is sent before the GPS knows the time, so it counts as missing.
+        let skew = frame.timestamp()
+            .filter(|gps_time| *gps_time > 0)
+            .map(|gps_time| when.timestamp_millis() - gps_time * 1000);
+        self.ds_time_skew.resize([self.index + 1])?;
+        self.ds_time_skew.write_slice(
+            &[skew.unwrap_or(MISSING_TIME_SKEW)],
+            &[self.index]
+        )?;
+        if let Some(skew) = skew {
+            self.skew.add(skew);
+        }
+
         self.ds_latitude.resize([self.index + 1])?;
         self.ds_latitude.write_slice(
             &[frame.latitude()],
@@ -87,6 +444,18 @@ impl Writer<HDF5WriterConfig> for HDF5Writer {
             &[self.index]
         )?;
 
+        self.ds_speed.resize([self.index + 1])?;
+        self.ds_speed.write_slice(
+            &[frame.speed()],
+            &[self.index]
+        )?;
+
+        self.ds_angle.resize([self.index + 1])?;
+        self.ds_angle.write_slice(
+            &[frame.angle()],
+            &[self.index]
+        )?;
+
         self.ds_gps_fix.resize([self.index + 1])?;
         self.ds_gps_fix.write_slice(
             &[frame.metadata().has_gps_fix()],
@@ -99,45 +468,95 @@ impl Writer<HDF5WriterConfig> for HDF5Writer {
             &[self.index]
         )?;
 
-        self.data_set_samples.resize([self.index + 1, 7200])?;
-        self.data_set_samples.write_slice(&frame.samples(), (self.index, ..))?;
+        self.ds_valid.resize([self.index + 1])?;
+        self.ds_valid.write_slice(
+            &[valid as u8],
+            &[self.index]
+        )?;
 
-        self.file.flush()?;
+        self.data_set_samples.resize([self.index + 1, samples.len()])?;
+        match self.sample_type {
+            SampleType::I16 => {
+                let samples: Vec<i16> = samples.iter().map(|value| *value as i16).collect();
+                self.data_set_samples.write_slice(&samples, (self.index, ..))?;
+            },
+            SampleType::I32 => self.data_set_samples.write_slice(&samples, (self.index, ..))?,
+        }
 
+        self.summary.write(self.index, frame)?;
+
+        if self.flush.frame_written() {
+            self.file.flush()?;
+        }
+
+        self.sidecar.add_frame(when, frame);
         self.index += 1;
 
         Ok(())
     }
 
     fn new(config: HDF5WriterConfig)-> anyhow::Result<HDF5Writer> {
-        let file = hdf5::File::create(config.output_path.join(Path::new(format!("{}_{}.h5", config.node_id, chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S")).as_str())))?;
+        config.decimation.check()?;
+        config.adc_scale.check(config.sample_type)?;
+        config.flush.check()?;
+        if config.samples_chunk_rows == 0 {
+            return Err(anyhow::anyhow!("samples_chunk_rows must be at least 1"));
+        }
+        let width = config.decimation.width(config.samples_per_frame);
+
+        let created = chrono::Utc::now();
+        // Claims the name, HDF5 then writes over the empty file
+        let (file_path, _) = config.filename_timezone.create_file(&config.output_path, &config.node_id, created, "h5")?;
+        let file = hdf5::File::create(&file_path)?;
 
         let attr = file.new_attr::<VarLenUnicode>().create("NODE_ID")?;
         let varlen = hdf5::types::VarLenUnicode::from_str(&config.node_id).unwrap();
         attr.write_scalar(&varlen)?;
 
-
-        let data_set_sample = file.new_dataset::<i16>()
-            .chunk(7200)
-            .shape(7200)
+        let attr = file.new_attr::<u32>().create("DECIMATION_FACTOR")?;
+        attr.write_scalar(&(config.decimation.factor as u32))?;
+
+        // Samples stay raw counts, these record how to turn them into volts
+        let attr = file.new_attr::<u8>().create("ADC_BITS")?;
+        attr.write_scalar(&config.adc_scale.bits)?;
+        let attr = file.new_attr::<f32>().create("ADC_VREF")?;
+        attr.write_scalar(&config.adc_scale.vref)?;
+        let attr = file.new_attr::<f32>().create("ADC_OFFSET")?;
+        attr.write_scalar(&config.adc_scale.offset())?;
+        let attr = file.new_attr::<f32>().create("ADC_SCALE")?;
+        attr.write_scalar(&config.adc_scale.scale())?;
+        let attr = file.new_attr::<VarLenUnicode>().create("UNITS")?;
+        attr.write_scalar(&VarLenUnicode::from_str("V").unwrap())?;
+
+        let data_set_sample = file.new_dataset::<u32>()
+            .chunk(width)
+            .shape(width)
             .create("sample")?;
 
-        // write sample indicies
-        let sample = Array1::from_shape_fn(7200, |i| i as i16);
+        // write sample indicies, as positions in the original frame
+        let sample = Array1::from_shape_fn(width, |i| (i * config.decimation.factor) as u32);
         data_set_sample.write_slice(sample.as_slice().unwrap(), ..)?;
 
         let ds_gps_time = a_dataset!(file, "gps_time", i64, [0..], 1);
         let ds_cpu_time = a_dataset!(file, "cpu_time", i64, [0..], 1);
-        let ds_latitude = a_dataset!(file, "latitude", f32, [0..], 1);
-        let ds_longitude = a_dataset!(file, "longitude", f32, [0..], 1);
-        let ds_elevation = a_dataset!(file, "elevation", f32, [0..], 1);
-        let ds_satellites = a_dataset!(file, "satellites", i8, [0..], 1);
-        let ds_gps_fix = a_dataset!(file, "gps_fix", bool, [0..], 1);
-        let ds_clipping = a_dataset!(file, "clipping", bool, [0..], 1);
+        let ds_time_skew = a_dataset!(file, "time_skew", i64, [0..], 1);
+        // The device only sends whole GPS seconds
+        set_units(&ds_gps_time, "s")?;
+        set_units(&ds_cpu_time, CPU_TIME_UNITS)?;
+        set_units(&ds_time_skew, "ms")?;
+        a_dataset!(file, "latitude", f32, [0..], 1);
+        a_dataset!(file, "longitude", f32, [0..], 1);
+        a_dataset!(file, "elevation", f32, [0..], 1);
+        a_dataset!(file, "satellites", i8, [0..], 1);
+        a_dataset!(file, "speed", f32, [0..], 1);
+        a_dataset!(file, "angle", f32, [0..], 1);
+        a_dataset!(file, "gps_fix", bool, [0..], 1);
+        a_dataset!(file, "clipping", bool, [0..], 1);
+        a_dataset!(file, "valid", u8, [0..], 1);
 
         let ds_comments = file.new_dataset::<VarLenUnicode>()
             .chunk(1)
-            .deflate(8)
+            .deflate(config.comments_gzip_level)
             .shape(0..)
             .create("comments")?;
 
@@ -145,39 +564,363 @@ impl Writer<HDF5WriterConfig> for HDF5Writer {
         ds_comments.resize([ds_comments.size() + 1])?;
         ds_comments.write_slice(&[comment], &[ds_comments.size() - 1])?;
 
-        let data_set_samples = file.new_dataset::<i16>()
-            .chunk((1, 7200))
-            .shape((0.., 7200))
+        GpsDatasets::create(&file)?;
+        SummaryDatasets::create(&file)?;
+
+        let data_set_samples = match config.sample_type {
+            SampleType::I16 => file.new_dataset::<i16>(),
+            SampleType::I32 => file.new_dataset::<i32>(),
+        };
+        data_set_samples
+            .chunk((config.samples_chunk_rows, width))
+            .shape((0.., width))
             .deflate(config.gzip_level as u8)
             .create("samples")?;
 
-        Ok(HDF5Writer {
-            output_path: config.output_path,
-            file,
-            ds_gps_time,
-            ds_cpu_time,
-            ds_latitude,
-            ds_longitude,
-            ds_elevation,
-            ds_satellites,
-            ds_comments,
-            data_set_samples: data_set_samples,
-            ds_gps_fix,
-            ds_clipping,
-            index: 0
-        })
+        HDF5Writer::open(config, file, file_path, created)
+    }
+
+    fn resume(config: HDF5WriterConfig, file_path: &Path, created: chrono::DateTime<Utc>) -> anyhow::Result<HDF5Writer> {
+        config.decimation.check()?;
+        config.adc_scale.check(config.sample_type)?;
+        config.flush.check()?;
+
+        let file = hdf5::File::open_rw(file_path)?;
+        let writer = HDF5Writer::open(config, file, file_path.to_path_buf(), created)?;
+        let comment = VarLenUnicode::from_str(RESUMED_COMMENT).unwrap();
+        writer.ds_comments.resize([writer.ds_comments.size() + 1])?;
+        writer.ds_comments.write_slice(&[comment], &[writer.ds_comments.size() - 1])?;
+        Ok(writer)
     }
     
-    fn close(self) -> anyhow::Result<()> {
+    fn close(self) -> anyhow::Result<PathBuf> {
+        // Drift in these over a file points at the GPS losing lock and its
+        // clock running in holdover
+        let attr = self.file.new_attr::<f64>().create("TIME_SKEW_MEAN_MS")?;
+        attr.write_scalar(&self.skew.mean())?;
+        let attr = self.file.new_attr::<f64>().create("TIME_SKEW_STDDEV_MS")?;
+        attr.write_scalar(&self.skew.stddev())?;
+
         self.file.flush()?;
         self.file.close()?;
-        Ok(())
+
+        if let Err(e) = super::manifest::record(&self.file_path, &self.node_id, self.created, self.index) {
+            log::error!("Unable to record {} in the manifest: {:?}", self.file_path.display(), e);
+        }
+
+        if let Err(e) = self.sidecar.write(&self.file_path) {
+            log::error!("Unable to write the sidecar for {}: {:?}", self.file_path.display(), e);
+        }
+
+        Ok(self.file_path)
     }
     
     async fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
-        let comment = hdf5::types::VarLenUnicode::from_str(comment).unwrap();
+        let comment = VarLenUnicode::from_str(comment).map_err(|e| Rejected(format!("Invalid comment: {:?}", e)))?;
         self.ds_comments.resize([self.ds_comments.size() + 1])?;
         self.ds_comments.write_slice(&[comment], &[self.ds_comments.size() - 1])?;
         Ok(())
     }
+
+    async fn write_gps(&mut self, when: chrono::DateTime<Utc>, record: &crate::serial::GpsRecord) -> anyhow::Result<()> {
+        // Flushed along with the next frame
+        self.gps.write(when, record)?;
+        Ok(())
+    }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use hdf5::types::VarLenUnicode;
+
+    use super::{HDF5Writer, MISSING_GPS_TIME, MISSING_TIME_SKEW};
+    use crate::{serial::{nmea, Frame, ParseOptions, SampleType}, testutil, writer::{manifest, AdcScale, Decimation, DecimationMode, Rejected, Writer, RESUMED_COMMENT}};
+
+    fn comments(file: &hdf5::File) -> Vec<String> {
+        file.dataset("comments").unwrap().read_raw::<VarLenUnicode>().unwrap().iter().map(|comment| comment.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn writes_frames_and_attributes() {
+        let dir = testutil::temp_dir("hdf5-frames");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let untimed = Frame::parse(&testutil::frame_line(0, false, &[4, 5, 6]).replacen("$0,", "$,", 1)).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 3)).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294400, true, &[1, 2, 3])).await.unwrap();
+        writer.write_frame(when, &untimed).await.unwrap();
+        writer.write_comment("battery low").await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("gps_time").unwrap().read_raw::<i64>().unwrap(), vec![1709294400, MISSING_GPS_TIME]);
+        assert_eq!(file.dataset("cpu_time").unwrap().read_raw::<i64>().unwrap(), vec![when.timestamp_millis(); 2]);
+        assert_eq!(file.dataset("gps_fix").unwrap().read_raw::<bool>().unwrap(), vec![true, false]);
+        assert_eq!(file.dataset("satellites").unwrap().read_raw::<i8>().unwrap(), vec![7, 7]);
+        assert_eq!(file.dataset("valid").unwrap().read_raw::<u8>().unwrap(), vec![1, 1]);
+
+        let samples = file.dataset("samples").unwrap().read_2d::<i16>().unwrap();
+        assert_eq!(samples.shape(), &[2, 3]);
+        assert_eq!(samples.row(0).to_vec(), vec![1, 2, 3]);
+        assert_eq!(file.dataset("sample").unwrap().read_raw::<u32>().unwrap(), vec![0, 1, 2]);
+
+        assert_eq!(file.attr("NODE_ID").unwrap().read_scalar::<VarLenUnicode>().unwrap().as_str(), "node");
+        assert_eq!(file.attr("SAMPLE_RATE").unwrap().read_scalar::<f32>().unwrap(), 1000.0);
+        assert_eq!(file.attr("ADC_OFFSET").unwrap().read_scalar::<f32>().unwrap(), 2048.0);
+        assert_eq!(comments(&file).last().map(String::as_str), Some("battery low"));
+    }
+
+    #[test]
+    fn records_the_adc_scale_for_each_resolution() {
+        for (bits, offset, scale) in [(10, 512.0, 3.3 / 1024.0), (12, 2048.0, 3.3 / 4096.0)] {
+            let dir = testutil::temp_dir(&format!("hdf5-adc-{}", bits));
+            let mut config = testutil::hdf5_config("node", &dir, 3);
+            config.adc_scale = AdcScale { bits, vref: 3.3 };
+            let path = HDF5Writer::new(config).unwrap().close().unwrap();
+
+            let file = hdf5::File::open(&path).unwrap();
+            assert_eq!(file.attr("ADC_BITS").unwrap().read_scalar::<u8>().unwrap(), bits);
+            assert_eq!(file.attr("ADC_VREF").unwrap().read_scalar::<f32>().unwrap(), 3.3);
+            assert_eq!(file.attr("ADC_OFFSET").unwrap().read_scalar::<f32>().unwrap(), offset);
+            assert_eq!(file.attr("ADC_SCALE").unwrap().read_scalar::<f32>().unwrap(), scale);
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_speed_angle_and_clipping() {
+        let dir = testutil::temp_dir("hdf5-speed-angle");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        // Moving at 1.5 heading 90, with the ADC clipping (the O flag)
+        let moving = Frame::parse(&testutil::frame_line(1709294400, true, &[1, 2, 3]).replacen(",G,1000,52.1,4.3,10,7,0,0,", ",GO,1000,52.1,4.3,10,7,1.5,90,", 1)).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 3)).unwrap();
+        writer.write_frame(when, &moving).await.unwrap();
+        writer.write_frame(when, &testutil::frame(1709294401, true, &[4, 5, 6])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("speed").unwrap().read_raw::<f32>().unwrap(), vec![1.5, 0.0]);
+        assert_eq!(file.dataset("angle").unwrap().read_raw::<f32>().unwrap(), vec![90.0, 0.0]);
+        assert_eq!(file.dataset("clipping").unwrap().read_raw::<bool>().unwrap(), vec![true, false]);
+        assert_eq!(file.dataset("gps_fix").unwrap().read_raw::<bool>().unwrap(), vec![true, true]);
+    }
+
+    #[tokio::test]
+    async fn samples_are_chunked_by_samples_chunk_rows() {
+        let dir = testutil::temp_dir("hdf5-chunks");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut config = testutil::hdf5_config("node", &dir, 3);
+        config.samples_chunk_rows = 64;
+
+        let mut writer = HDF5Writer::new(config).unwrap();
+        for i in 0..200 {
+            writer.write_frame(when, &testutil::frame(1709294400 + i, true, &[1, 2, 3])).await.unwrap();
+        }
+        let path = writer.close().unwrap();
+
+        let samples = hdf5::File::open(&path).unwrap().dataset("samples").unwrap();
+        assert_eq!(samples.shape(), vec![200, 3]);
+        assert_eq!(samples.chunk(), Some(vec![64, 3]));
+    }
+
+    #[tokio::test]
+    async fn resume_continues_after_the_last_frame() {
+        let dir = testutil::temp_dir("hdf5-resume");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 3)).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294400, true, &[1, 2, 3])).await.unwrap();
+        writer.write_frame(when, &testutil::frame(1709294401, true, &[4, 5, 6])).await.unwrap();
+        let (path, created) = (writer.file_path.clone(), writer.created);
+        // Never closed, as after a crash
+        drop(writer);
+
+        let mut writer = HDF5Writer::resume(testutil::hdf5_config("node", &dir, 3), &path, created).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294402, true, &[7, 8, 9])).await.unwrap();
+        assert_eq!(writer.close().unwrap(), path);
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("gps_time").unwrap().read_raw::<i64>().unwrap(), vec![1709294400, 1709294401, 1709294402]);
+        assert_eq!(file.dataset("samples").unwrap().read_2d::<i16>().unwrap().row(2).to_vec(), vec![7, 8, 9]);
+        assert_eq!(file.dataset("summary/max").unwrap().read_raw::<i32>().unwrap(), vec![3, 6, 9]);
+        assert_eq!(comments(&file).last().map(String::as_str), Some(RESUMED_COMMENT));
+        assert_eq!(manifest::entries(&dir).unwrap()[0].frame_count, 3);
+
+        // Wider frames don't fit, and a damaged file can't be opened at all
+        assert!(HDF5Writer::resume(testutil::hdf5_config("node", &dir, 4), &path, created).is_err());
+        std::fs::write(&path, "not hdf5").unwrap();
+        assert!(HDF5Writer::resume(testutil::hdf5_config("node", &dir, 3), &path, created).is_err());
+    }
+
+    #[tokio::test]
+    async fn cpu_time_keeps_milliseconds() {
+        let dir = testutil::temp_dir("hdf5-cpu-time");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap() + chrono::Duration::milliseconds(1);
+        let later = when + chrono::Duration::milliseconds(250);
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 1)).unwrap();
+        writer.write_frame(when, &testutil::frame(1709294400, true, &[1])).await.unwrap();
+        writer.write_frame(later, &testutil::frame(1709294400, true, &[1])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        let cpu_time = file.dataset("cpu_time").unwrap();
+        assert_eq!(cpu_time.read_raw::<i64>().unwrap(), vec![1709294400001, 1709294400251]);
+        assert_eq!(cpu_time.attr("UNITS").unwrap().read_scalar::<VarLenUnicode>().unwrap().as_str(), "ms");
+        assert_eq!(file.dataset("gps_time").unwrap().attr("UNITS").unwrap().read_scalar::<VarLenUnicode>().unwrap().as_str(), "s");
+    }
+
+    #[tokio::test]
+    async fn writes_a_summary_row_per_frame() {
+        let dir = testutil::temp_dir("hdf5-summary");
+        let mut config = testutil::hdf5_config("node", &dir, 4);
+        config.decimation = Decimation { factor: 2, mode: DecimationMode::Pick };
+        let frames = [
+            testutil::frame(1, true, &[1, 2, 3, 4]),
+            testutil::frame(2, true, &[-300, 0, 300, 0]),
+            testutil::frame(3, true, &[7, 7, 7, 7]),
+        ];
+
+        let mut writer = HDF5Writer::new(config).unwrap();
+        for frame in &frames {
+            writer.write_frame(chrono::Utc::now(), frame).await.unwrap();
+        }
+        let path = writer.close().unwrap();
+
+        // From every sample, not only the ones decimation kept
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("summary/min").unwrap().read_raw::<i32>().unwrap(), vec![1, -300, 7]);
+        assert_eq!(file.dataset("summary/max").unwrap().read_raw::<i32>().unwrap(), vec![4, 300, 7]);
+        let rms = file.dataset("summary/rms").unwrap().read_raw::<f32>().unwrap();
+        assert_eq!(rms, frames.iter().map(|frame| frame.signal_stats().rms).collect::<Vec<_>>());
+        assert_eq!(rms[2], 0.0);
+    }
+
+    #[tokio::test]
+    async fn rejected_frames_leave_the_datasets_aligned() {
+        let dir = testutil::temp_dir("hdf5-rejected");
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 3)).unwrap();
+        assert!(writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[1, 2])).await.is_err());
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(3, true, &[1, 2, 3])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("gps_time").unwrap().read_raw::<i64>().unwrap(), vec![3]);
+        assert_eq!(file.dataset("samples").unwrap().shape(), vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_comment_it_cant_store() {
+        let dir = testutil::temp_dir("hdf5-rejected-comment");
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 3)).unwrap();
+        assert!(writer.write_comment("battery\0low").await.unwrap_err().is::<Rejected>());
+        writer.write_comment("battery low").await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(comments(&file).last().unwrap(), "battery low");
+    }
+
+    #[tokio::test]
+    async fn pads_short_frames_and_marks_them_invalid() {
+        let dir = testutil::temp_dir("hdf5-padded");
+        let mut config = testutil::hdf5_config("node", &dir, 4);
+        config.pad_short_frames = true;
+
+        let mut writer = HDF5Writer::new(config).unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[1, 2])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("samples").unwrap().read_raw::<i16>().unwrap(), vec![1, 2, 0, 0]);
+        assert_eq!(file.dataset("valid").unwrap().read_raw::<u8>().unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn decimates_and_records_sample_positions() {
+        let dir = testutil::temp_dir("hdf5-decimation");
+        let mut config = testutil::hdf5_config("node", &dir, 5);
+        config.decimation = Decimation { factor: 2, mode: DecimationMode::Pick };
+
+        let mut writer = HDF5Writer::new(config).unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[10, 11, 12, 13, 14])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("samples").unwrap().read_raw::<i16>().unwrap(), vec![10, 12, 14]);
+        assert_eq!(file.dataset("sample").unwrap().read_raw::<u32>().unwrap(), vec![0, 2, 4]);
+        assert_eq!(file.attr("EFFECTIVE_SAMPLE_RATE").unwrap().read_scalar::<f32>().unwrap(), 500.0);
+    }
+
+    #[tokio::test]
+    async fn i32_samples_are_not_truncated() {
+        let dir = testutil::temp_dir("hdf5-i32");
+        let options = ParseOptions { sample_type: SampleType::I32, ..ParseOptions::default() };
+        let frame = Frame::parse_with_options(&testutil::frame_line(1, true, &[100_000, -2_000_000, 3]), &options).unwrap();
+
+        let mut config = testutil::hdf5_config("node", &dir, 3);
+        config.sample_type = SampleType::I32;
+        config.adc_scale = AdcScale { bits: 24, vref: 2.5 };
+        let mut writer = HDF5Writer::new(config).unwrap();
+        writer.write_frame(chrono::Utc::now(), &frame).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("samples").unwrap().read_raw::<i32>().unwrap(), vec![100_000, -2_000_000, 3]);
+    }
+
+    #[tokio::test]
+    async fn i16_dataset_rejects_samples_out_of_range() {
+        let dir = testutil::temp_dir("hdf5-i16-range");
+        let options = ParseOptions { sample_type: SampleType::I32, ..ParseOptions::default() };
+        let frame = Frame::parse_with_options(&testutil::frame_line(1, true, &[40_000]), &options).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 1)).unwrap();
+        assert!(writer.write_frame(chrono::Utc::now(), &frame).await.is_err());
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("samples").unwrap().shape(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn records_the_time_skew() {
+        let dir = testutil::temp_dir("hdf5-skew");
+        let untimed = Frame::parse(&testutil::frame_line(0, false, &[1]).replacen("$0,", "$,", 1)).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 1)).unwrap();
+        writer.write_frame(chrono::Utc.timestamp_millis_opt(1_000_250).unwrap(), &testutil::frame(1000, true, &[1])).await.unwrap();
+        writer.write_frame(chrono::Utc.timestamp_millis_opt(1_001_250).unwrap(), &testutil::frame(1000, true, &[1])).await.unwrap();
+        writer.write_frame(chrono::Utc.timestamp_millis_opt(1_002_250).unwrap(), &untimed).await.unwrap();
+        // No fix yet, so a zero GPS time
+        writer.write_frame(chrono::Utc.timestamp_millis_opt(1_003_250).unwrap(), &testutil::frame(0, false, &[1])).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("time_skew").unwrap().read_raw::<i64>().unwrap(), vec![250, 1250, MISSING_TIME_SKEW, MISSING_TIME_SKEW]);
+        assert_eq!(file.attr("TIME_SKEW_MEAN_MS").unwrap().read_scalar::<f64>().unwrap(), 750.0);
+        assert_eq!(file.attr("TIME_SKEW_STDDEV_MS").unwrap().read_scalar::<f64>().unwrap(), 500.0);
+    }
+
+    #[tokio::test]
+    async fn writes_gps_records() {
+        let dir = testutil::temp_dir("hdf5-gps");
+        let when = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut writer = HDF5Writer::new(testutil::hdf5_config("node", &dir, 1)).unwrap();
+        writer.write_gps(when, &nmea::parse("#$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47").unwrap()).await.unwrap();
+        writer.write_gps(when, &nmea::parse("#$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A").unwrap()).await.unwrap();
+        let path = writer.close().unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        assert_eq!(file.dataset("gps/cpu_time").unwrap().read_raw::<i64>().unwrap(), vec![when.timestamp_millis(); 2]);
+        assert_eq!(file.dataset("gps/satellites").unwrap().read_raw::<i8>().unwrap(), vec![8, -1]);
+        let speed = file.dataset("gps/speed").unwrap().read_raw::<f32>().unwrap();
+        assert!(speed[0].is_nan());
+        assert_eq!(speed[1], 22.4);
+    }
+}
\ No newline at end of file
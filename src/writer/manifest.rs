@@ -0,0 +1,125 @@
+use std::{fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    filename: &'a str,
+    sha256: &'a str,
+    created: String,
+    node_id: &'a str,
+    frame_count: usize,
+}
+
+// An entry read back from manifest.jsonl
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordedFile {
+    pub filename: String,
+    pub sha256: String,
+    pub created: String,
+    pub node_id: String,
+    pub frame_count: usize,
+}
+
+// Hashes a finished capture file, writes a sha256sum-compatible
+// `{filename}.sha256` next to it and appends an entry to manifest.jsonl
+pub fn record(file_path: &Path, node_id: &str, created: DateTime<Utc>, frame_count: usize) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(file_path)?, &mut hasher)?;
+    let sha256 = format!("{:x}", hasher.finalize());
+
+    let filename = file_path.file_name()
+        .ok_or(anyhow::anyhow!("Capture file has no file name: {}", file_path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    std::fs::write(file_path.with_file_name(format!("{}.sha256", filename)), format!("{}  {}\n", sha256, filename))?;
+
+    let entry = ManifestEntry {
+        filename: &filename,
+        sha256: &sha256,
+        created: created.to_rfc3339(),
+        node_id,
+        frame_count,
+    };
+
+    let mut manifest = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path.with_file_name("manifest.jsonl"))?;
+    writeln!(manifest, "{}", serde_json::to_string(&entry)?)?;
+
+    Ok(sha256)
+}
+
+// Every file recorded in `dir`'s manifest.jsonl. Lines that don't parse are
+// skipped, and a missing manifest just means nothing has finished yet.
+pub fn entries(dir: &Path) -> anyhow::Result<Vec<RecordedFile>> {
+    let manifest = match File::open(dir.join("manifest.jsonl")) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(manifest).lines() {
+        match serde_json::from_str::<RecordedFile>(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!("Skipping unreadable manifest entry: {}", e),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::{entries, record};
+    use crate::testutil;
+
+    #[test]
+    fn records_a_checksum_file_and_a_manifest_entry() {
+        let dir = testutil::temp_dir("manifest-record");
+        let file_path = dir.join("node_1.csv");
+        std::fs::write(&file_path, "abc").unwrap();
+        let created = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let sha256 = record(&file_path, "node", created, 5).unwrap();
+        assert_eq!(sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(std::fs::read_to_string(dir.join("node_1.csv.sha256")).unwrap(), format!("{}  node_1.csv\n", sha256));
+
+        let recorded = entries(&dir).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].filename, "node_1.csv");
+        assert_eq!(recorded[0].sha256, sha256);
+        assert_eq!(recorded[0].created, "2024-03-01T12:00:00+00:00");
+        assert_eq!(recorded[0].node_id, "node");
+        assert_eq!(recorded[0].frame_count, 5);
+    }
+
+    #[test]
+    fn appends_and_skips_unreadable_lines() {
+        let dir = testutil::temp_dir("manifest-append");
+        for name in ["a.csv", "b.csv"] {
+            std::fs::write(dir.join(name), name).unwrap();
+            record(&dir.join(name), "node", chrono::Utc::now(), 1).unwrap();
+        }
+        let mut manifest = std::fs::read_to_string(dir.join("manifest.jsonl")).unwrap();
+        manifest.push_str("{\"filename\": \"half a line\n");
+        std::fs::write(dir.join("manifest.jsonl"), manifest).unwrap();
+
+        let filenames: Vec<String> = entries(&dir).unwrap().into_iter().map(|entry| entry.filename).collect();
+        assert_eq!(filenames, vec!["a.csv", "b.csv"]);
+    }
+
+    #[test]
+    fn no_manifest_means_no_entries() {
+        let dir = testutil::temp_dir("manifest-missing");
+        assert!(entries(&dir).unwrap().is_empty());
+        assert!(record(&dir.join("missing.csv"), "node", chrono::Utc::now(), 0).is_err());
+    }
+}
\ No newline at end of file
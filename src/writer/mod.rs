@@ -1,13 +1,704 @@
-use std::path::{Path, PathBuf};
+use std::{fmt, fs::File, io::ErrorKind, path::{Path, PathBuf}, str::FromStr, time::{Duration, Instant}};
 
-use chrono::Utc;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "hdf5")]
+use crate::serial::SampleType;
 
 pub mod csv;
+pub mod event;
+#[cfg(feature = "hdf5")]
 pub mod hdf5;
+pub mod manifest;
+#[cfg(feature = "hdf5")]
+pub mod sidecar;
+
+// Written as a comment where a file interrupted by a crash was picked up again
+pub const RESUMED_COMMENT: &str = "Continued after the previous run stopped without closing this file";
 
-pub trait Writer<C> where C: Clone {
-    fn new(config: C) -> anyhow::Result<Self> where Self: Sized;
-    fn close(self) -> anyhow::Result<()>;
+pub trait Writer {
+    type Config: Clone;
+
+    fn new(config: Self::Config) -> anyhow::Result<Self> where Self: Sized;
+    // Reopens a file a previous run never closed and continues after its
+    // last complete frame. Fails if the file is unreadable or was written
+    // with a different layout.
+    fn resume(config: Self::Config, file_path: &Path, created: DateTime<Utc>) -> anyhow::Result<Self> where Self: Sized;
+    // Flushes and closes the file, returning the path of the finished file
+    fn close(self) -> anyhow::Result<PathBuf>;
     async fn write_frame(&mut self, frame_when: chrono::DateTime<Utc>, frame: &crate::serial::Frame) -> anyhow::Result<()>;
     async fn write_comment(&mut self, comment: &str) -> anyhow::Result<()>;
+    async fn write_gps(&mut self, when: chrono::DateTime<Utc>, record: &crate::serial::GpsRecord) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Hdf5,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DecimationMode {
+    // Keep every Nth sample, starting with the first
+    #[default]
+    Pick,
+    // Average each run of N samples
+    Average,
+}
+
+// Reduces each frame to every Nth sample before it is written. A factor of 1
+// writes the frame untouched.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimation {
+    pub factor: usize,
+    pub mode: DecimationMode,
+}
+
+impl Default for Decimation {
+    fn default() -> Self {
+        Decimation { factor: 1, mode: DecimationMode::Pick }
+    }
+}
+
+impl Decimation {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.factor == 0 {
+            return Err(anyhow::anyhow!("Decimation factor must be at least 1"));
+        }
+        Ok(())
+    }
+
+    // Number of samples a frame of `samples` samples is reduced to
+    pub fn width(&self, samples: usize) -> usize {
+        samples.div_ceil(self.factor)
+    }
+
+    pub fn apply(&self, samples: &[i32]) -> Vec<i32> {
+        if self.factor == 1 {
+            return samples.to_vec();
+        }
+
+        match self.mode {
+            DecimationMode::Pick => samples.iter().step_by(self.factor).copied().collect(),
+            DecimationMode::Average => samples.chunks(self.factor)
+                .map(|chunk| (chunk.iter().map(|v| *v as i64).sum::<i64>() / chunk.len() as i64) as i32)
+                .collect(),
+        }
+    }
+}
+
+// Time zone of the timestamp in file names, so names match local logbooks.
+// Only the name changes, everything recorded in the file stays UTC. Written
+// as "UTC", a fixed offset like "+02:00", or an IANA name like
+// "Europe/Amsterdam".
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FilenameTimezone {
+    #[default]
+    Utc,
+    Fixed(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl FilenameTimezone {
+    const FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+    // Length of a timestamp written in FORMAT
+    const FORMAT_LEN: usize = 19;
+    // Files that may be started within one second before giving up
+    const MAX_FILES_PER_SECOND: u32 = 1000;
+
+    // e.g. node_2024-03-01_13-00-00.h5, or node_2024-03-01_13-00-00_1.h5
+    // for the second file started in that second
+    pub fn file_name(&self, node_id: &str, created: chrono::DateTime<Utc>, extension: &str, number: u32) -> String {
+        let timestamp = match self {
+            FilenameTimezone::Utc => created.format(Self::FORMAT).to_string(),
+            FilenameTimezone::Fixed(offset) => created.with_timezone(offset).format(Self::FORMAT).to_string(),
+            FilenameTimezone::Named(tz) => created.with_timezone(tz).format(Self::FORMAT).to_string(),
+        };
+        match number {
+            0 => format!("{}_{}.{}", node_id, timestamp, extension),
+            number => format!("{}_{}_{}.{}", node_id, timestamp, number, extension),
+        }
+    }
+
+    // Creates the empty file for one started at `created`. Names only go
+    // down to the second, so rather than overwrite a file started earlier in
+    // the same second this takes the next free number.
+    pub fn create_file(&self, output_path: &Path, node_id: &str, created: chrono::DateTime<Utc>, extension: &str) -> anyhow::Result<(PathBuf, File)> {
+        for number in 0..Self::MAX_FILES_PER_SECOND {
+            let file_path = output_path.join(self.file_name(node_id, created, extension, number));
+            match File::options().write(true).create_new(true).open(&file_path) {
+                Ok(file) => return Ok((file_path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(anyhow::anyhow!("Unable to create {}: {}", file_path.display(), e)),
+            }
+        }
+        Err(anyhow::anyhow!("Already {} files started at {} in {}", Self::MAX_FILES_PER_SECOND, created, output_path.display()))
+    }
+
+    // When a file named by file_name() was created, or None for other files
+    pub fn created(&self, node_id: &str, file_name: &str, extension: &str) -> Option<DateTime<Utc>> {
+        self.parse_file_name(node_id, file_name, extension).map(|(created, _)| created)
+    }
+
+    // When a file named by file_name() was created and its number within
+    // that second
+    fn parse_file_name(&self, node_id: &str, file_name: &str, extension: &str) -> Option<(DateTime<Utc>, u32)> {
+        let stem = file_name.strip_prefix(node_id)?.strip_prefix('_')?.strip_suffix(extension)?.strip_suffix('.')?;
+        let (timestamp, number) = stem.split_at_checked(Self::FORMAT_LEN)?;
+        let number = match number {
+            "" => 0,
+            number => number.strip_prefix('_')?.parse().ok()?,
+        };
+        let local = NaiveDateTime::parse_from_str(timestamp, Self::FORMAT).ok()?;
+        // Names from the hour repeated when summer time ends are ambiguous,
+        // the earlier one is as good a guess as any
+        let created = match self {
+            FilenameTimezone::Utc => Some(local.and_utc()),
+            FilenameTimezone::Fixed(offset) => offset.from_local_datetime(&local).earliest().map(|created| created.to_utc()),
+            FilenameTimezone::Named(tz) => tz.from_local_datetime(&local).earliest().map(|created| created.to_utc()),
+        };
+        Some((created?, number))
+    }
+}
+
+impl FromStr for FilenameTimezone {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<FilenameTimezone> {
+        if value.eq_ignore_ascii_case("utc") {
+            return Ok(FilenameTimezone::Utc);
+        }
+        if let Ok(offset) = value.parse::<FixedOffset>() {
+            return Ok(FilenameTimezone::Fixed(offset));
+        }
+        value.parse::<chrono_tz::Tz>()
+            .map(FilenameTimezone::Named)
+            .map_err(|_| anyhow::anyhow!("\"{}\" is not UTC, an offset like +02:00 or a time zone like Europe/Amsterdam", value))
+    }
+}
+
+impl TryFrom<String> for FilenameTimezone {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> anyhow::Result<FilenameTimezone> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for FilenameTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilenameTimezone::Utc => write!(f, "UTC"),
+            FilenameTimezone::Fixed(offset) => write!(f, "{}", offset),
+            FilenameTimezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+impl From<FilenameTimezone> for String {
+    fn from(timezone: FilenameTimezone) -> String {
+        timezone.to_string()
+    }
+}
+
+// Returned (inside the anyhow::Error) when a writer refuses what it was
+// given, e.g. a frame with the wrong number of samples or a comment it
+// can't store. The file is untouched and still usable.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// How raw ADC counts convert to volts: volts = (raw - offset()) * scale()
+#[cfg(feature = "hdf5")]
+#[derive(Debug, Clone, Copy)]
+pub struct AdcScale {
+    pub bits: u8,
+    pub vref: f32,
+}
+
+#[cfg(feature = "hdf5")]
+impl AdcScale {
+    // The samples have to be wide enough to hold every count
+    pub fn check(&self, sample_type: SampleType) -> anyhow::Result<()> {
+        if !(1..=sample_type.bits()).contains(&self.bits) {
+            return Err(anyhow::anyhow!("ADC resolution must be between 1 and {} bits, not {}", sample_type.bits(), self.bits));
+        }
+        Ok(())
+    }
+
+    // The count at 0V on a signal centered in the ADC range
+    pub fn offset(&self) -> f32 {
+        (1u64 << (self.bits - 1)) as f32
+    }
+
+    // Volts per count
+    pub fn scale(&self) -> f32 {
+        self.vref / (1u64 << self.bits) as f32
+    }
+}
+
+// How often writers flush to disk. Frames written since the last flush are
+// lost if the process crashes or the power goes, so flushing less often
+// trades up to that many frames for less wear on SD cards. close() always
+// flushes.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    pub every_n_frames: usize,
+    // Flush anyway once this long has passed since the last flush
+    pub interval: Option<Duration>,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy { every_n_frames: 1, interval: None }
+    }
+}
+
+impl FlushPolicy {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.every_n_frames == 0 {
+            return Err(anyhow::anyhow!("Flush interval must be at least 1 frame"));
+        }
+        Ok(())
+    }
+}
+
+// Counts frames written since the last flush against a FlushPolicy
+pub struct FlushTimer {
+    policy: FlushPolicy,
+    pending: usize,
+    last_flush: Instant,
+}
+
+impl FlushTimer {
+    pub fn new(policy: FlushPolicy) -> FlushTimer {
+        FlushTimer { policy, pending: 0, last_flush: Instant::now() }
+    }
+
+    // Records a written frame, returning true when it is time to flush
+    pub fn frame_written(&mut self) -> bool {
+        self.pending += 1;
+
+        let due = self.pending >= self.policy.every_n_frames
+            || self.policy.interval.is_some_and(|interval| self.last_flush.elapsed() >= interval);
+        if due {
+            self.pending = 0;
+            self.last_flush = Instant::now();
+        }
+
+        due
+    }
+}
+
+#[derive(Clone)]
+pub enum OutputWriterConfig {
+    #[cfg(feature = "hdf5")]
+    Hdf5(self::hdf5::HDF5WriterConfig),
+    Csv(self::csv::CsvWriterConfig),
+}
+
+impl OutputWriterConfig {
+    // Only HDF5 output is compressed
+    #[cfg_attr(not(feature = "hdf5"), allow(unused_variables))]
+    pub fn set_gzip_level(&mut self, gzip_level: i8) {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => config.gzip_level = gzip_level,
+            OutputWriterConfig::Csv(_) => {},
+        }
+    }
+
+    fn node_id(&self) -> &str {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => &config.node_id,
+            OutputWriterConfig::Csv(config) => &config.node_id,
+        }
+    }
+
+    fn filename_timezone(&self) -> FilenameTimezone {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => config.filename_timezone,
+            OutputWriterConfig::Csv(config) => config.filename_timezone,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(_) => "h5",
+            OutputWriterConfig::Csv(_) => "csv",
+        }
+    }
+
+    // The newest capture file for this node if it was never closed, e.g.
+    // because the process crashed, and was started less than `max_age` ago.
+    // Closed files are in the manifest, so if the newest one is there no
+    // file was interrupted.
+    pub fn interrupted_file(&self, max_age: Duration) -> anyhow::Result<Option<(PathBuf, DateTime<Utc>)>> {
+        let mut newest: Option<(PathBuf, (DateTime<Utc>, u32))> = None;
+        for entry in std::fs::read_dir(self.output_path())? {
+            let path = entry?.path();
+            let position = path.file_name()
+                .and_then(|name| self.filename_timezone().parse_file_name(self.node_id(), &name.to_string_lossy(), self.extension()));
+            if let Some(position) = position {
+                if newest.as_ref().is_none_or(|(_, newest)| position > *newest) {
+                    newest = Some((path, position));
+                }
+            }
+        }
+
+        let Some((path, (created, _))) = newest else {
+            return Ok(None);
+        };
+        if (Utc::now() - created).to_std().is_ok_and(|age| age >= max_age) {
+            return Ok(None);
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if manifest::entries(self.output_path())?.iter().any(|entry| entry.filename == file_name) {
+            return Ok(None);
+        }
+
+        Ok(Some((path, created)))
+    }
+
+    pub fn output_path(&self) -> &Path {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => &config.output_path,
+            OutputWriterConfig::Csv(config) => &config.output_path,
+        }
+    }
+
+    pub fn set_output_path(&mut self, output_path: PathBuf) {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => config.output_path = output_path,
+            OutputWriterConfig::Csv(config) => config.output_path = output_path,
+        }
+    }
+}
+
+pub enum OutputWriter {
+    #[cfg(feature = "hdf5")]
+    Hdf5(Box<self::hdf5::HDF5Writer>),
+    Csv(self::csv::CsvWriter),
+}
+
+impl OutputWriter {
+    pub fn file_path(&self) -> &Path {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriter::Hdf5(writer) => writer.file_path(),
+            OutputWriter::Csv(writer) => writer.file_path(),
+        }
+    }
+
+    // Continues the file a previous run was writing if it is younger than
+    // `max_age`, otherwise, or if it can't be continued, starts a new one.
+    // Also returns how long ago the file was started.
+    pub fn resume_or_new(config: OutputWriterConfig, max_age: Duration) -> anyhow::Result<(OutputWriter, Duration)> {
+        match config.interrupted_file(max_age) {
+            Ok(Some((path, created))) => match OutputWriter::resume(config.clone(), &path, created) {
+                Ok(writer) => {
+                    log::info!("Continuing interrupted file {}", path.display());
+                    return Ok((writer, (Utc::now() - created).to_std().unwrap_or_default()));
+                },
+                Err(e) => log::warn!("Unable to continue {}, starting a new file: {:#}", path.display(), e),
+            },
+            Ok(None) => {},
+            Err(e) => log::warn!("Unable to look for an interrupted file, starting a new one: {:#}", e),
+        }
+
+        Ok((OutputWriter::new(config)?, Duration::ZERO))
+    }
+}
+
+impl Writer for OutputWriter {
+    type Config = OutputWriterConfig;
+
+    fn new(config: OutputWriterConfig) -> anyhow::Result<OutputWriter> {
+        match config {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => Ok(OutputWriter::Hdf5(Box::new(self::hdf5::HDF5Writer::new(config)?))),
+            OutputWriterConfig::Csv(config) => Ok(OutputWriter::Csv(self::csv::CsvWriter::new(config)?)),
+        }
+    }
+
+    fn resume(config: OutputWriterConfig, file_path: &Path, created: DateTime<Utc>) -> anyhow::Result<OutputWriter> {
+        match config {
+            #[cfg(feature = "hdf5")]
+            OutputWriterConfig::Hdf5(config) => Ok(OutputWriter::Hdf5(Box::new(self::hdf5::HDF5Writer::resume(config, file_path, created)?))),
+            OutputWriterConfig::Csv(config) => Ok(OutputWriter::Csv(self::csv::CsvWriter::resume(config, file_path, created)?)),
+        }
+    }
+
+    fn close(self) -> anyhow::Result<PathBuf> {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriter::Hdf5(writer) => writer.close(),
+            OutputWriter::Csv(writer) => writer.close(),
+        }
+    }
+
+    async fn write_frame(&mut self, frame_when: chrono::DateTime<Utc>, frame: &crate::serial::Frame) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriter::Hdf5(writer) => writer.write_frame(frame_when, frame).await,
+            OutputWriter::Csv(writer) => writer.write_frame(frame_when, frame).await,
+        }
+    }
+
+    async fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriter::Hdf5(writer) => writer.write_comment(comment).await,
+            OutputWriter::Csv(writer) => writer.write_comment(comment).await,
+        }
+    }
+
+    async fn write_gps(&mut self, when: chrono::DateTime<Utc>, record: &crate::serial::GpsRecord) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "hdf5")]
+            OutputWriter::Hdf5(writer) => writer.write_gps(when, record).await,
+            OutputWriter::Csv(writer) => writer.write_gps(when, record).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+
+    use super::{csv::{CsvWriter, CsvWriterConfig}, manifest, Decimation, DecimationMode, FilenameTimezone, FlushPolicy, FlushTimer, OutputWriter, OutputWriterConfig, Writer, RESUMED_COMMENT};
+    use crate::testutil;
+
+    fn csv_config(dir: &std::path::Path) -> OutputWriterConfig {
+        OutputWriterConfig::Csv(csv_writer_config(dir))
+    }
+
+    fn csv_writer_config(dir: &std::path::Path) -> CsvWriterConfig {
+        CsvWriterConfig {
+            node_id: "node".to_string(),
+            output_path: dir.to_path_buf(),
+            samples_per_frame: 3,
+            decimation: Decimation::default(),
+            flush: FlushPolicy::default(),
+            filename_timezone: FilenameTimezone::default(),
+        }
+    }
+
+    // Knows nothing about the writer but the trait, so this only compiles
+    // while every writer can be built from its config alone
+    fn open<W: Writer>(config: W::Config) -> anyhow::Result<W> {
+        W::new(config)
+    }
+
+    async fn write_one_frame<W: Writer>(config: W::Config) -> std::path::PathBuf {
+        let mut writer = open::<W>(config).unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[1, 2, 3])).await.unwrap();
+        writer.close().unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_writer_opens_through_the_trait() {
+        let path = write_one_frame::<CsvWriter>(csv_writer_config(&testutil::temp_dir("writer-generic-csv"))).await;
+        assert_eq!(testutil::csv_rows(&path).len(), 1);
+
+        let path = write_one_frame::<OutputWriter>(csv_config(&testutil::temp_dir("writer-generic-output"))).await;
+        assert_eq!(testutil::csv_rows(&path).len(), 1);
+
+        #[cfg(feature = "hdf5")]
+        {
+            let path = write_one_frame::<super::hdf5::HDF5Writer>(testutil::hdf5_config("node", &testutil::temp_dir("writer-generic-hdf5"), 3)).await;
+            assert_eq!(::hdf5::File::open(path).unwrap().dataset("samples").unwrap().shape(), vec![1, 3]);
+        }
+    }
+
+    #[test]
+    fn file_names_use_the_configured_time_zone() {
+        let created = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 23, 30, 0).unwrap();
+        let name = |timezone: &str| timezone.parse::<FilenameTimezone>().unwrap().file_name("node", created, "h5", 0);
+
+        assert_eq!(name("UTC"), "node_2024-03-01_23-30-00.h5");
+        assert_eq!(name("+02:00"), "node_2024-03-02_01-30-00.h5");
+        assert_eq!(name("-05:30"), "node_2024-03-01_18-00-00.h5");
+        // Central European Time, summer time only starts at the end of March
+        assert_eq!(name("Europe/Amsterdam"), "node_2024-03-02_00-30-00.h5");
+        assert!("Mars/Olympus_Mons".parse::<FilenameTimezone>().is_err());
+    }
+
+    #[test]
+    fn file_names_give_back_when_they_were_created() {
+        let created = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 23, 30, 0).unwrap();
+        for timezone in ["UTC", "+02:00", "Europe/Amsterdam"] {
+            let timezone = timezone.parse::<FilenameTimezone>().unwrap();
+            assert_eq!(timezone.created("node", &timezone.file_name("node", created, "h5", 0), "h5"), Some(created));
+            assert_eq!(timezone.created("node", &timezone.file_name("node", created, "h5", 2), "h5"), Some(created));
+        }
+
+        assert_eq!(FilenameTimezone::Utc.file_name("node", created, "h5", 2), "node_2024-03-01_23-30-00_2.h5");
+        assert_eq!(FilenameTimezone::Utc.created("node", "node_2024-03-01_23-30-00_x.h5", "h5"), None);
+
+        assert_eq!(FilenameTimezone::Utc.created("node", "node_2024-03-01_23-30-00.csv", "h5"), None);
+        assert_eq!(FilenameTimezone::Utc.created("node", "node_2_2024-03-01_23-30-00.h5", "h5"), None);
+        assert_eq!(FilenameTimezone::Utc.created("node", "manifest.jsonl", "h5"), None);
+    }
+
+    #[tokio::test]
+    async fn continues_an_interrupted_file() {
+        let dir = testutil::temp_dir("writer-resume");
+        let config = csv_config(&dir);
+
+        let mut writer = OutputWriter::new(config.clone()).unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(1, true, &[1, 1, 1])).await.unwrap();
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(2, true, &[2, 2, 2])).await.unwrap();
+        let path = writer.file_path().to_path_buf();
+        // Crash halfway through the next row
+        drop(writer);
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str("3,17");
+        std::fs::write(&path, contents).unwrap();
+
+        let (mut writer, age) = OutputWriter::resume_or_new(config, Duration::from_secs(3600)).unwrap();
+        assert_eq!(writer.file_path(), path);
+        assert!(age < Duration::from_secs(60));
+        writer.write_frame(chrono::Utc::now(), &testutil::frame(3, true, &[3, 3, 3])).await.unwrap();
+        writer.close().unwrap();
+
+        let gps_times: Vec<String> = testutil::csv_rows(&path).iter().map(|row| row.split(',').next().unwrap().to_string()).collect();
+        assert_eq!(gps_times, vec!["1", "2", "3"]);
+        assert!(std::fs::read_to_string(&path).unwrap().contains(&format!("\n# {}\n", RESUMED_COMMENT)));
+        assert_eq!(testutil::files_with_extension(&dir, "csv"), vec![path]);
+        assert_eq!(manifest::entries(&dir).unwrap()[0].frame_count, 3);
+    }
+
+    #[tokio::test]
+    async fn starts_a_new_file_unless_the_last_one_can_be_continued() {
+        let dir = testutil::temp_dir("writer-resume-new");
+        let config = csv_config(&dir);
+        let max_age = Duration::from_secs(3600);
+
+        // Nothing to continue
+        assert!(config.interrupted_file(max_age).unwrap().is_none());
+
+        // Too old
+        let old = dir.join(FilenameTimezone::Utc.file_name("node", chrono::Utc::now() - chrono::Duration::hours(2), "csv", 0));
+        std::fs::write(&old, "gps_time,cpu_time,latitude,longitude,elevation,satellites,sample_0,sample_1,sample_2\n").unwrap();
+        assert!(config.interrupted_file(max_age).unwrap().is_none());
+
+        // Different columns, so it isn't touched
+        let other = dir.join(FilenameTimezone::Utc.file_name("node", chrono::Utc::now() - chrono::Duration::minutes(10), "csv", 0));
+        std::fs::write(&other, "gps_time,cpu_time,sample_0\n1,2,3\n").unwrap();
+        assert_eq!(config.interrupted_file(max_age).unwrap().map(|(path, _)| path), Some(other.clone()));
+        let (writer, age) = OutputWriter::resume_or_new(config.clone(), max_age).unwrap();
+        assert_ne!(writer.file_path(), other);
+        assert_eq!(age, Duration::ZERO);
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "gps_time,cpu_time,sample_0\n1,2,3\n");
+
+        // Closed properly
+        writer.close().unwrap();
+        assert!(config.interrupted_file(max_age).unwrap().is_none());
+    }
+
+    #[test]
+    fn files_started_in_the_same_second_get_numbered() {
+        let dir = testutil::temp_dir("writer-same-second");
+        let created = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 23, 30, 0).unwrap();
+        let create = || FilenameTimezone::Utc.create_file(&dir, "node", created, "csv").unwrap().0;
+
+        let first = create();
+        std::fs::write(&first, "first").unwrap();
+        let second = create();
+        let third = create();
+
+        assert_eq!(first, dir.join("node_2024-03-01_23-30-00.csv"));
+        assert_eq!(second, dir.join("node_2024-03-01_23-30-00_1.csv"));
+        assert_eq!(third, dir.join("node_2024-03-01_23-30-00_2.csv"));
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "first");
+    }
+
+    #[test]
+    fn the_last_numbered_file_is_the_one_to_continue() {
+        let dir = testutil::temp_dir("writer-resume-numbered");
+        let config = csv_config(&dir);
+        let created = chrono::Utc::now();
+        for _ in 0..3 {
+            FilenameTimezone::Utc.create_file(&dir, "node", created, "csv").unwrap();
+        }
+
+        let (path, _) = config.interrupted_file(Duration::from_secs(3600)).unwrap().unwrap();
+        assert_eq!(path, dir.join(FilenameTimezone::Utc.file_name("node", created, "csv", 2)));
+    }
+
+    #[test]
+    fn decimation_picks_or_averages() {
+        let samples = [1, 2, 3, 4, 5, 6, 7];
+
+        let pick = Decimation { factor: 3, mode: DecimationMode::Pick };
+        assert_eq!(pick.apply(&samples), vec![1, 4, 7]);
+        assert_eq!(pick.width(samples.len()), 3);
+
+        let average = Decimation { factor: 3, mode: DecimationMode::Average };
+        assert_eq!(average.apply(&samples), vec![2, 5, 7]);
+        assert_eq!(average.apply(&[-1, -2]), vec![-1]);
+
+        let none = Decimation::default();
+        assert_eq!(none.apply(&samples), samples.to_vec());
+        assert_eq!(none.width(samples.len()), samples.len());
+
+        assert!(Decimation { factor: 0, mode: DecimationMode::Pick }.check().is_err());
+    }
+
+    #[test]
+    fn flushes_every_n_frames() {
+        let mut timer = FlushTimer::new(FlushPolicy { every_n_frames: 3, interval: None });
+        let flushes: Vec<bool> = (0..7).map(|_| timer.frame_written()).collect();
+        assert_eq!(flushes, vec![false, false, true, false, false, true, false]);
+
+        assert!(FlushPolicy { every_n_frames: 0, interval: None }.check().is_err());
+    }
+
+    #[test]
+    fn flushes_once_the_interval_has_passed() {
+        let mut timer = FlushTimer::new(FlushPolicy { every_n_frames: 1000, interval: Some(Duration::from_millis(20)) });
+        assert!(!timer.frame_written());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(timer.frame_written());
+        assert!(!timer.frame_written());
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn adc_scale_centers_the_range() {
+        let scale = super::AdcScale { bits: 12, vref: 3.3 };
+        assert_eq!(scale.offset(), 2048.0);
+        assert_eq!(scale.scale(), 3.3 / 4096.0);
+        assert!(scale.check(super::SampleType::I16).is_ok());
+
+        let scale = super::AdcScale { bits: 10, vref: 3.3 };
+        assert_eq!(scale.offset(), 512.0);
+        assert_eq!(scale.scale(), 3.3 / 1024.0);
+        assert!(scale.check(super::SampleType::I16).is_ok());
+
+        assert!(super::AdcScale { bits: 0, vref: 3.3 }.check(super::SampleType::I16).is_err());
+        assert!(super::AdcScale { bits: 24, vref: 3.3 }.check(super::SampleType::I16).is_err());
+        assert!(super::AdcScale { bits: 24, vref: 3.3 }.check(super::SampleType::I32).is_ok());
+        assert!(super::AdcScale { bits: 33, vref: 3.3 }.check(super::SampleType::I32).is_err());
+    }
 }
\ No newline at end of file
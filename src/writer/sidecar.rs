@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+// Summary of a capture file, built up as frames are written and saved as
+// `{filename}.json` on close, so files can be indexed without opening them
+#[derive(Debug, Clone, Serialize)]
+pub struct Sidecar {
+    node_id: String,
+    software_version: &'static str,
+    gzip_level: i8,
+    frame_count: usize,
+    // Taken from the first frame, like the SAMPLE_RATE attribute
+    sample_rate: Option<f32>,
+    start_cpu_time: Option<i64>,
+    end_cpu_time: Option<i64>,
+    start_gps_time: Option<i64>,
+    end_gps_time: Option<i64>,
+    // Only frames with a GPS fix count, the rest carry no real position
+    bounding_box: Option<BoundingBox>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BoundingBox {
+    min_latitude: f32,
+    max_latitude: f32,
+    min_longitude: f32,
+    max_longitude: f32,
+}
+
+impl Sidecar {
+    pub fn new(node_id: &str, gzip_level: i8) -> Sidecar {
+        Sidecar {
+            node_id: node_id.to_string(),
+            software_version: env!("CARGO_PKG_VERSION"),
+            gzip_level,
+            frame_count: 0,
+            sample_rate: None,
+            start_cpu_time: None,
+            end_cpu_time: None,
+            start_gps_time: None,
+            end_gps_time: None,
+            bounding_box: None,
+        }
+    }
+
+    pub fn add_frame(&mut self, when: DateTime<Utc>, frame: &crate::serial::Frame) {
+        let position = frame.metadata().has_gps_fix().then(|| (frame.latitude(), frame.longitude()));
+        self.add_row(Some(frame.sample_rate()), when.timestamp(), frame.timestamp(), position);
+    }
+
+    // What add_frame takes from a frame, for frames read back from a file
+    // that is being continued. The position is None without a GPS fix.
+    pub fn add_row(&mut self, sample_rate: Option<f32>, cpu_time: i64, gps_time: Option<i64>, position: Option<(f32, f32)>) {
+        self.frame_count += 1;
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate.get_or_insert(sample_rate);
+        }
+
+        self.start_cpu_time.get_or_insert(cpu_time);
+        self.end_cpu_time = Some(cpu_time);
+
+        if let Some(timestamp) = gps_time {
+            self.start_gps_time.get_or_insert(timestamp);
+            self.end_gps_time = Some(timestamp);
+        }
+
+        let Some((latitude, longitude)) = position else {
+            return;
+        };
+        if !latitude.is_finite() || !longitude.is_finite() {
+            return;
+        }
+
+        self.bounding_box = Some(match self.bounding_box {
+            Some(bounds) => BoundingBox {
+                min_latitude: bounds.min_latitude.min(latitude),
+                max_latitude: bounds.max_latitude.max(latitude),
+                min_longitude: bounds.min_longitude.min(longitude),
+                max_longitude: bounds.max_longitude.max(longitude),
+            },
+            None => BoundingBox {
+                min_latitude: latitude,
+                max_latitude: latitude,
+                min_longitude: longitude,
+                max_longitude: longitude,
+            },
+        });
+    }
+
+    // Writes the sidecar next to `file_path`
+    pub fn write(&self, file_path: &Path) -> anyhow::Result<()> {
+        let filename = file_path.file_name()
+            .ok_or(anyhow::anyhow!("Capture file has no file name: {}", file_path.display()))?
+            .to_string_lossy()
+            .to_string();
+
+        std::fs::write(file_path.with_file_name(format!("{}.json", filename)), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::Sidecar;
+    use crate::{serial::Frame, testutil};
+
+    fn frame_at(timestamp: i64, has_fix: bool, latitude: f32, longitude: f32) -> Frame {
+        let line = testutil::frame_line(timestamp, has_fix, &[1]).replacen("52.1,4.3", &format!("{},{}", latitude, longitude), 1);
+        Frame::parse(&line).unwrap()
+    }
+
+    #[test]
+    fn bounding_box_skips_frames_without_a_fix() {
+        let mut sidecar = Sidecar::new("node", 4);
+        let when = chrono::Utc::now();
+        sidecar.add_frame(when, &frame_at(0, false, 10.0, 10.0));
+        sidecar.add_frame(when, &frame_at(1, true, 52.0, 4.0));
+        sidecar.add_frame(when, &frame_at(2, true, 53.0, 3.0));
+
+        let json = serde_json::to_value(&sidecar).unwrap();
+        assert_eq!(json["bounding_box"]["min_latitude"], 52.0);
+        assert_eq!(json["bounding_box"]["max_latitude"], 53.0);
+        assert_eq!(json["bounding_box"]["min_longitude"], 3.0);
+        assert_eq!(json["bounding_box"]["max_longitude"], 4.0);
+        assert_eq!(json["frame_count"], 3);
+    }
+
+    #[test]
+    fn writes_next_to_the_capture_file() {
+        let dir = testutil::temp_dir("sidecar");
+        let mut sidecar = Sidecar::new("node", 4);
+        sidecar.add_frame(chrono::Utc.timestamp_opt(1_000, 0).unwrap(), &testutil::frame(1, true, &[1, 2, 3]));
+        sidecar.write(&dir.join("node_2024.h5")).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.join("node_2024.h5.json")).unwrap()).unwrap();
+        assert_eq!(json["node_id"], "node");
+        assert_eq!(json["start_cpu_time"], 1_000);
+        assert_eq!(json["start_gps_time"], 1);
+    }
+}
\ No newline at end of file